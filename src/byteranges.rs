@@ -0,0 +1,507 @@
+use std::io::prelude::*;
+
+use crate::fetch::{self, DownloadError};
+
+/// Part headers are only a few lines long, anything longer means the response is not what it claims to be
+const MAX_PART_HEADERS_SIZE: usize = 16 * 1024;
+
+/// A range of bytes of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub size: u64,
+}
+
+impl ByteRange {
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+}
+
+/// Formats ranges as the value of a `Range` header, in which the end of each range is inclusive
+pub fn get_range_header_value(ranges: &[ByteRange]) -> String {
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|range| format!("{}-{}", range.start, range.end() - 1))
+        .collect();
+
+    format!("bytes={}", ranges.join(","))
+}
+
+/// Returns what is left of the ranges once the given number of bytes has been downloaded, counting the ranges one
+/// after the other
+pub fn skip_bytes(ranges: &[ByteRange], mut number_of_bytes: u64) -> Vec<ByteRange> {
+    let mut remaining_ranges: Vec<ByteRange> = Vec::new();
+    for range in ranges {
+        if number_of_bytes >= range.size {
+            number_of_bytes -= range.size;
+            continue;
+        }
+
+        remaining_ranges.push(ByteRange {
+            start: range.start + number_of_bytes,
+            size: range.size - number_of_bytes,
+        });
+        number_of_bytes = 0;
+    }
+
+    remaining_ranges
+}
+
+/// Returns the boundary of a `Content-Type: multipart/byteranges; boundary=<boundary>` header
+pub fn get_multipart_boundary(content_type: &str) -> Option<&str> {
+    let (media_type, parameters) = content_type.split_once(';')?;
+    if !media_type
+        .trim()
+        .eq_ignore_ascii_case("multipart/byteranges")
+    {
+        return None;
+    }
+
+    parameters.split(';').find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+/// What a response with byte ranges holds, as it arrives
+#[derive(Debug, PartialEq)]
+pub enum ByteRangesEvent<'a> {
+    /// The start of a range, with the first and last byte of the file it covers
+    Part { start: u64, end: u64 },
+    /// Bytes of the range that started last
+    Data(&'a [u8]),
+}
+
+enum ParserState {
+    /// The single range of a response that is not multipart, which has not been reported yet
+    Single {
+        start: u64,
+        end: u64,
+    },
+    /// Reading the delimiter and headers of the next part, which are kept until they are complete
+    Headers(Vec<u8>),
+    Body {
+        remaining_bytes: u64,
+    },
+    Done,
+}
+
+/// Splits the body of a `206 Partial Content` response into the ranges it holds as it arrives, whether the server sent
+/// `multipart/byteranges` or a single range
+pub struct ByteRangesParser {
+    /// The line that starts each part, which is `--` followed by the boundary
+    delimiter: Option<Vec<u8>>,
+    state: ParserState,
+}
+
+impl ByteRangesParser {
+    pub fn multipart(boundary: &str) -> ByteRangesParser {
+        ByteRangesParser {
+            delimiter: Some(format!("--{}", boundary).into_bytes()),
+            state: ParserState::Headers(Vec::new()),
+        }
+    }
+
+    /// A response that is a single range, from the `Content-Range` header
+    pub fn single(start: u64, end: u64) -> ByteRangesParser {
+        ByteRangesParser {
+            delimiter: None,
+            state: ParserState::Single { start, end },
+        }
+    }
+
+    /// Parses the next chunk of the body, reporting each range and its bytes as they are found
+    pub fn feed(
+        &mut self,
+        mut chunk: &[u8],
+        on_event: &mut impl FnMut(ByteRangesEvent) -> Result<(), DownloadError>,
+    ) -> Result<(), DownloadError> {
+        while !chunk.is_empty() {
+            match &mut self.state {
+                ParserState::Single { start, end } => {
+                    let (start, end) = (*start, *end);
+                    let remaining_bytes = get_range_size(start, end).ok_or_else(|| {
+                        DownloadError::InvalidContentRange {
+                            expected: "a range that ends after it starts".to_string(),
+                            actual: Some(format!("bytes {}-{}", start, end)),
+                        }
+                    })?;
+                    self.state = ParserState::Body { remaining_bytes };
+                    on_event(ByteRangesEvent::Part { start, end })?;
+                }
+                ParserState::Headers(buffer) => {
+                    // Headers are short, so they are read a byte at a time to leave the body where it is
+                    let delimiter = self.delimiter.as_deref().unwrap_or_default();
+                    let mut header_size = 0;
+                    while header_size < chunk.len()
+                        && !is_end_of_part_headers(buffer, delimiter)
+                        && !is_closing_delimiter(buffer, delimiter)
+                    {
+                        buffer.push(chunk[header_size]);
+                        header_size += 1;
+                    }
+                    chunk = &chunk[header_size..];
+
+                    if is_closing_delimiter(buffer, delimiter) {
+                        self.state = ParserState::Done;
+                    } else if is_end_of_part_headers(buffer, delimiter) {
+                        let (start, end) = parse_part_headers(buffer, delimiter)?;
+                        let remaining_bytes = get_range_size(start, end).ok_or_else(|| {
+                            DownloadError::InvalidMultipart(format!(
+                                "a part covers bytes {}-{}, which do not make a range",
+                                start, end
+                            ))
+                        })?;
+                        self.state = ParserState::Body { remaining_bytes };
+                        on_event(ByteRangesEvent::Part { start, end })?;
+                    } else if buffer.len() > MAX_PART_HEADERS_SIZE {
+                        return Err(DownloadError::InvalidMultipart(
+                            "the headers of a part are too long".to_string(),
+                        ));
+                    }
+                }
+                ParserState::Body { remaining_bytes } => {
+                    let size = chunk.len().min(*remaining_bytes as usize);
+                    *remaining_bytes -= size as u64;
+                    if *remaining_bytes == 0 {
+                        self.state = match self.delimiter {
+                            Some(_) => ParserState::Headers(Vec::new()),
+                            None => ParserState::Done,
+                        };
+                    }
+                    on_event(ByteRangesEvent::Data(&chunk[..size]))?;
+                    chunk = &chunk[size..];
+                }
+                // Anything after the last part is of no use
+                ParserState::Done => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the body so far ends with a whole part, rather than part of the way through one
+    pub fn is_between_parts(&self) -> bool {
+        match &self.state {
+            ParserState::Single { .. } | ParserState::Body { .. } => false,
+            ParserState::Headers(_) | ParserState::Done => true,
+        }
+    }
+}
+
+/// How many bytes are in a range from its first and last byte, unless the range ends before it starts or is too large
+/// to count
+fn get_range_size(start: u64, end: u64) -> Option<u64> {
+    end.checked_sub(start)?.checked_add(1)
+}
+
+/// The headers of a part end with a blank line, which a preamble before the first delimiter can also have
+fn is_end_of_part_headers(buffer: &[u8], delimiter: &[u8]) -> bool {
+    buffer.ends_with(b"\r\n\r\n")
+        && buffer
+            .windows(delimiter.len())
+            .any(|window| window == delimiter)
+}
+
+/// The last part is followed by the delimiter with `--` after it
+fn is_closing_delimiter(buffer: &[u8], delimiter: &[u8]) -> bool {
+    let line = buffer.trim_ascii_start();
+    line.len() >= delimiter.len() + 2
+        && line.starts_with(delimiter)
+        && &line[delimiter.len()..delimiter.len() + 2] == b"--"
+}
+
+/// Returns the first and last byte of the range a part holds, from its `Content-Range` header
+fn parse_part_headers(buffer: &[u8], delimiter: &[u8]) -> Result<(u64, u64), DownloadError> {
+    let headers = String::from_utf8_lossy(buffer);
+    let delimiter = String::from_utf8_lossy(delimiter);
+
+    // Anything before the delimiter is either the line break that ends the previous part or a preamble
+    let mut lines = headers
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != delimiter);
+    if lines.next().is_none() {
+        return Err(DownloadError::InvalidMultipart(format!(
+            "expected a part to start with {}",
+            delimiter
+        )));
+    }
+
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.trim().eq_ignore_ascii_case("content-range") {
+            return fetch::parse_content_range(Some(value.trim())).ok_or_else(|| {
+                DownloadError::InvalidMultipart(format!("invalid Content-Range {}", value.trim()))
+            });
+        }
+    }
+
+    Err(DownloadError::InvalidMultipart(
+        "a part has no Content-Range".to_string(),
+    ))
+}
+
+/// Writes the bytes of the requested ranges in the order they were requested, out of the ranges a server sent.
+/// Servers may merge ranges that are close together into one, in which case the bytes between them are skipped.
+pub struct RangesWriter<'a, W: Write> {
+    ranges: &'a [ByteRange],
+    writer: W,
+    /// Which range the next byte that is needed belongs to, and how much of that range has been written
+    range_index: usize,
+    range_offset: u64,
+    /// Where in the file the next byte of the response is from
+    position: u64,
+}
+
+impl<'a, W: Write> RangesWriter<'a, W> {
+    pub fn new(ranges: &'a [ByteRange], writer: W) -> RangesWriter<'a, W> {
+        RangesWriter {
+            ranges,
+            writer,
+            range_index: 0,
+            range_offset: 0,
+            position: ranges.first().map_or(0, |range| range.start),
+        }
+    }
+
+    /// Where in the file the next byte that is needed is, if any are
+    fn get_next_needed_position(&self) -> Option<u64> {
+        self.ranges
+            .get(self.range_index)
+            .map(|range| range.start + self.range_offset)
+    }
+
+    /// Starts a range of the response, which has to start before the next byte that is needed or the server left it
+    /// out
+    pub fn start_part(&mut self, start: u64) -> Result<(), DownloadError> {
+        if let Some(needed_position) = self.get_next_needed_position() {
+            if start > needed_position {
+                return Err(DownloadError::InvalidContentRange {
+                    expected: format!("bytes {}-", needed_position),
+                    actual: Some(format!("bytes {}-", start)),
+                });
+            }
+        }
+        self.position = start;
+
+        Ok(())
+    }
+
+    /// Writes whichever of the bytes of the current range are needed, and returns how many that was
+    pub fn write(&mut self, mut bytes: &[u8]) -> Result<u64, DownloadError> {
+        let mut written_bytes: u64 = 0;
+
+        while let Some(needed_position) = self.get_next_needed_position() {
+            if bytes.is_empty() {
+                break;
+            }
+
+            // Bytes between the ranges that were asked for, which the server sent to merge them
+            if self.position < needed_position {
+                let skipped_size = bytes.len().min((needed_position - self.position) as usize);
+                bytes = &bytes[skipped_size..];
+                self.position += skipped_size as u64;
+                continue;
+            }
+            if self.position > needed_position {
+                return Err(DownloadError::InvalidContentRange {
+                    expected: format!("bytes {}-", needed_position),
+                    actual: Some(format!("bytes {}-", self.position)),
+                });
+            }
+
+            let range = &self.ranges[self.range_index];
+            let size = bytes.len().min((range.size - self.range_offset) as usize);
+            self.writer.write_all(&bytes[..size])?;
+            bytes = &bytes[size..];
+            self.position += size as u64;
+            self.range_offset += size as u64;
+            written_bytes += size as u64;
+            if self.range_offset == range.size {
+                self.range_index += 1;
+                self.range_offset = 0;
+            }
+        }
+
+        Ok(written_bytes)
+    }
+
+    /// Whether every byte of every range has been written
+    pub fn is_done(&self) -> bool {
+        self.range_index == self.ranges.len()
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_get_range_header_value() {
+        let ranges = [
+            ByteRange { start: 0, size: 10 },
+            ByteRange {
+                start: 100,
+                size: 1,
+            },
+        ];
+
+        assert_eq!(get_range_header_value(&ranges), "bytes=0-9,100-100");
+        assert_eq!(
+            skip_bytes(&ranges, 10),
+            vec![ByteRange {
+                start: 100,
+                size: 1
+            }]
+        );
+        assert_eq!(
+            skip_bytes(&ranges, 4),
+            vec![ByteRange { start: 4, size: 6 }, ranges[1]]
+        );
+    }
+
+    #[test]
+    fn test_get_multipart_boundary() {
+        assert_eq!(
+            get_multipart_boundary("multipart/byteranges; boundary=3d6b6a416f9b5"),
+            Some("3d6b6a416f9b5")
+        );
+        assert_eq!(
+            get_multipart_boundary(r#"Multipart/Byteranges; charset=utf-8; boundary="abc""#),
+            Some("abc")
+        );
+        assert_eq!(get_multipart_boundary("application/octet-stream"), None);
+    }
+
+    #[test]
+    fn test_ranges_writer_reads_multipart_response() {
+        let file: Vec<u8> = (0..=255).collect();
+        // Two ranges the server merged into one, because only a byte separates them, and one on its own
+        let ranges = [
+            ByteRange { start: 10, size: 5 },
+            ByteRange { start: 16, size: 4 },
+            ByteRange {
+                start: 200,
+                size: 6,
+            },
+        ];
+        let mut body: Vec<u8> = b"preamble\r\n".to_vec();
+        for (start, end) in [(10, 19), (200, 205)] {
+            body.extend_from_slice(b"\r\n--xyz\r\nContent-Type: application/octet-stream\r\n");
+            body.extend_from_slice(
+                format!("Content-Range: bytes {}-{}/256\r\n\r\n", start, end).as_bytes(),
+            );
+            body.extend_from_slice(&file[start..=end]);
+        }
+        body.extend_from_slice(b"\r\n--xyz--\r\n");
+
+        let mut written: Vec<u8> = Vec::new();
+        let mut ranges_writer = RangesWriter::new(&ranges, &mut written);
+        let mut parser = ByteRangesParser::multipart("xyz");
+        // Feed the body in small chunks, so parts and headers are split between them
+        for chunk in body.chunks(7) {
+            parser
+                .feed(chunk, &mut |event| match event {
+                    ByteRangesEvent::Part { start, .. } => ranges_writer.start_part(start),
+                    ByteRangesEvent::Data(bytes) => ranges_writer.write(bytes).map(|_| ()),
+                })
+                .unwrap();
+        }
+
+        assert!(ranges_writer.is_done());
+        assert!(parser.is_between_parts());
+        let expected: Vec<u8> = [&file[10..15], &file[16..20], &file[200..206]].concat();
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn test_ranges_writer_rejects_missing_bytes() {
+        let ranges = [
+            ByteRange { start: 0, size: 4 },
+            ByteRange { start: 10, size: 4 },
+        ];
+        let mut ranges_writer = RangesWriter::new(&ranges, Vec::new());
+
+        // A server that only sends the first range is fine, the rest can be asked for again
+        ranges_writer.start_part(0).unwrap();
+        assert_eq!(ranges_writer.write(&[0; 4]).unwrap(), 4);
+        assert!(!ranges_writer.is_done());
+
+        // One that skips some of the bytes is not
+        assert!(ranges_writer.start_part(12).is_err());
+    }
+
+    #[test]
+    fn test_parser_rejects_malformed_parts() {
+        let parse = |content_range: &str| {
+            let body = format!(
+                "--xyz\r\nContent-Range: {}\r\n\r\nabcd\r\n--xyz--\r\n",
+                content_range
+            );
+            let mut parser = ByteRangesParser::multipart("xyz");
+            parser.feed(body.as_bytes(), &mut |_| Ok(()))
+        };
+
+        assert!(parse("bytes 0-3/256").is_ok());
+        for content_range in [
+            "bytes 20-10/256",
+            "bytes 0-18446744073709551615/*",
+            "bytes */256",
+            "bytes 0-x/256",
+        ] {
+            assert!(
+                matches!(
+                    parse(content_range),
+                    Err(DownloadError::InvalidMultipart(_))
+                ),
+                "{}",
+                content_range
+            );
+        }
+
+        // A part with no Content-Range at all
+        let mut parser = ByteRangesParser::multipart("xyz");
+        let result = parser.feed(
+            b"--xyz\r\nContent-Type: text/plain\r\n\r\nabcd",
+            &mut |_| Ok(()),
+        );
+        assert!(matches!(result, Err(DownloadError::InvalidMultipart(_))));
+
+        // Single ranges are checked too, even though the Content-Range of the response was
+        let mut parser = ByteRangesParser::single(0, u64::MAX);
+        assert!(parser.feed(b"abcd", &mut |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_parser_accepts_closing_delimiter_without_line_break() {
+        let mut events: Vec<String> = Vec::new();
+        let mut parser = ByteRangesParser::multipart("xyz");
+        for chunk in [
+            &b"--xyz\r\nContent-Range: bytes 4-7/256\r\n\r\nabcd\r\n--xy"[..],
+            b"z--",
+        ] {
+            parser
+                .feed(chunk, &mut |event| {
+                    events.push(format!("{:?}", event));
+                    Ok(())
+                })
+                .unwrap();
+        }
+
+        assert!(parser.is_between_parts());
+        assert!(matches!(parser.state, ParserState::Done));
+        assert_eq!(
+            events,
+            vec![
+                format!("{:?}", ByteRangesEvent::Part { start: 4, end: 7 }),
+                format!("{:?}", ByteRangesEvent::Data(b"abcd")),
+            ]
+        );
+    }
+}
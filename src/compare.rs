@@ -4,11 +4,12 @@ use std::io::Read;
 
 use safetensors::{SafeTensors, View};
 
+use crate::config::Config;
 use crate::{hasher, hf};
 
-pub fn compare_hashes_via_registry(model_id_a: &str, model_id_b: &str) {
-    let model_a_layers_to_hashes = get_model_hashes_by_model_id(model_id_a);
-    let model_b_layers_to_hashes = get_model_hashes_by_model_id(model_id_b);
+pub fn compare_hashes_via_registry(config: &Config, model_id_a: &str, model_id_b: &str) {
+    let model_a_layers_to_hashes = get_model_hashes_by_model_id(config, model_id_a);
+    let model_b_layers_to_hashes = get_model_hashes_by_model_id(config, model_id_b);
 
     // Extract the hashes
     let model_a_hashes: Vec<String> = model_a_layers_to_hashes
@@ -36,17 +37,20 @@ pub fn compare_hashes_via_registry(model_id_a: &str, model_id_b: &str) {
     println!("{} layers found in common.", same_hash_counter);
 }
 
-fn get_model_hashes_by_model_id(model_id: &str) -> HashMap<String, String> {
+fn get_model_hashes_by_model_id(config: &Config, model_ref: &str) -> HashMap<String, String> {
+    let (model_id, revision) = hf::parse_model_id_and_revision(model_ref);
+
     // Get all safetensor file names
 
     // Query the HF API to see the file names
-    let model_info_result = hf::get_model_info(model_id);
+    let model_info_result = hf::get_model_info(config.hf_endpoint(), model_id, revision);
     if model_info_result.is_err() {
         // TODO: Handle better, print the error message too
         panic!("Unable to retrieve model info when attempting download!")
     }
 
     let model_info = model_info_result.unwrap();
+    let commit_sha = model_info.get_commit_sha(revision);
 
     let model_filenames: Vec<&String> = model_info
         .siblings
@@ -65,7 +69,10 @@ fn get_model_hashes_by_model_id(model_id: &str) -> HashMap<String, String> {
 
     // Get the hashes of each file
     for file_name in safetensors_filenames {
-        let (_, layers_to_hashes_map) = hasher::get_model_file_hashes(model_id, file_name);
+        // Only the hashes are needed to compare, so skip downloading the header
+        let layers_to_hashes_map =
+            hasher::get_registry_hashes(config.registry_url(), model_id, &commit_sha, file_name)
+                .unwrap_or_else(|e| panic!("Unable to retrieve hashes from the registry: {}", e));
 
         for result in layers_to_hashes_map.iter() {
             all_layers_to_hashes.insert(result.0.to_string(), result.1.to_string());
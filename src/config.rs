@@ -0,0 +1,352 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use clap::builder::RangedU64ValueParser;
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::store::Codec;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("unable to read config file {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("invalid config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("invalid config file {path}: {reason}")]
+    Invalid { path: PathBuf, reason: String },
+    #[error("invalid {name}: {reason}")]
+    Env { name: String, reason: String },
+}
+
+/// Settings shared by every command. Each setting is taken from the first of these that sets it:
+/// CLI flags, environment variables, the config file, then the defaults below.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where layers, manifests and refs are stored
+    pub store_dir: String,
+    /// Where hashes are written to, and served from by the registry
+    pub results_dir: String,
+    /// The registry that layer hashes are looked up in
+    pub registry_url: String,
+    /// Hugging Face, or a mirror of it
+    pub hf_endpoint: String,
+    /// The address the registry listens on
+    pub registry_listen_addr: String,
+    /// The database the registry indexes hashes in
+    pub registry_index: String,
+    /// The store the registry serves layers from, if it serves them at all
+    pub registry_blobs_dir: Option<String>,
+    /// How many range requests are downloaded at once
+    pub download_jobs: usize,
+    /// The most bytes per second that every download may use together, if there is a limit at all
+    pub download_rate_limit: Option<u64>,
+    /// How layers are compressed when they are added to the store
+    pub compression: Codec,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            store_dir: "./download".to_string(),
+            results_dir: "./results".to_string(),
+            registry_url: "http://localhost:3000".to_string(),
+            hf_endpoint: "https://huggingface.co".to_string(),
+            registry_listen_addr: "0.0.0.0:3000".to_string(),
+            registry_index: "./registry.sqlite".to_string(),
+            registry_blobs_dir: None,
+            download_jobs: 8,
+            download_rate_limit: None,
+            compression: Codec::Lz4,
+        }
+    }
+}
+
+/// Overrides for the config, which apply to every command
+#[derive(Args, Debug, Default)]
+pub struct ConfigArgs {
+    /// Config file to use instead of `cake/config.toml` in the user's config directory
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Folder the layers are stored in
+    #[arg(long, global = true)]
+    pub store: Option<String>,
+    /// Folder hashes are published to and served from
+    #[arg(long, global = true)]
+    pub results: Option<String>,
+    /// Base URL of the hash registry
+    #[arg(long, global = true)]
+    pub registry_url: Option<String>,
+    /// Base URL of Hugging Face, or a mirror of it
+    #[arg(long, global = true)]
+    pub hf_endpoint: Option<String>,
+    /// How many range requests to download at once
+    #[arg(long, global = true, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub jobs: Option<usize>,
+    /// Most bytes per second to download at, across every layer, such as `500K` or `10M`
+    #[arg(long, global = true, value_parser = parse_byte_size)]
+    pub limit_rate: Option<u64>,
+    /// How layers are compressed when they are added to the store
+    #[arg(long, global = true, value_enum)]
+    pub compression: Option<Codec>,
+}
+
+impl Config {
+    /// Loads the config file, then applies the environment variables and CLI flags on top of it
+    pub fn load(config_args: &ConfigArgs) -> Result<Config, ConfigError> {
+        let env_var = |name: &str| std::env::var(name).ok();
+
+        // An explicitly chosen config file has to exist, the default one is optional
+        let mut config = match config_args
+            .config
+            .clone()
+            .or_else(|| env_var("CAKE_CONFIG").map(PathBuf::from))
+        {
+            Some(config_path) => Config::from_file(config_path)?,
+            None => match get_default_config_path() {
+                Some(config_path) if config_path.is_file() => Config::from_file(config_path)?,
+                _ => Config::default(),
+            },
+        };
+
+        config.apply_env(env_var)?;
+        config.apply_args(config_args);
+
+        Ok(config)
+    }
+
+    fn from_file(config_path: PathBuf) -> Result<Config, ConfigError> {
+        let config_toml = match fs::read_to_string(&config_path) {
+            Ok(config_toml) => config_toml,
+            Err(source) => {
+                return Err(ConfigError::Read {
+                    path: config_path,
+                    source,
+                })
+            }
+        };
+
+        Config::parse(config_path, &config_toml)
+    }
+
+    /// Parses the contents of a config file, with the same checks as the environment variables and CLI flags
+    fn parse(config_path: PathBuf, config_toml: &str) -> Result<Config, ConfigError> {
+        let config: Config = match toml::from_str(config_toml) {
+            Ok(config) => config,
+            Err(source) => {
+                return Err(ConfigError::Parse {
+                    path: config_path,
+                    source,
+                })
+            }
+        };
+
+        // Downloads would never start without any jobs, and a limit of nothing cannot be waited out
+        let reason = if config.download_jobs == 0 {
+            "download_jobs has to be a positive number"
+        } else if config.download_rate_limit == Some(0) {
+            "download_rate_limit has to be a positive number of bytes"
+        } else {
+            return Ok(config);
+        };
+
+        Err(ConfigError::Invalid {
+            path: config_path,
+            reason: reason.to_string(),
+        })
+    }
+
+    fn apply_env(&mut self, env_var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let settings = [
+            ("CAKE_STORE", &mut self.store_dir),
+            ("CAKE_RESULTS", &mut self.results_dir),
+            ("CAKE_REGISTRY_URL", &mut self.registry_url),
+            // Same variable as the huggingface_hub library, so mirrors only have to be configured once
+            ("HF_ENDPOINT", &mut self.hf_endpoint),
+            ("CAKE_REGISTRY_LISTEN", &mut self.registry_listen_addr),
+            ("CAKE_REGISTRY_INDEX", &mut self.registry_index),
+        ];
+        for (name, setting) in settings {
+            if let Some(value) = env_var(name).filter(|value| !value.is_empty()) {
+                *setting = value;
+            }
+        }
+
+        if let Some(value) = env_var("CAKE_REGISTRY_BLOBS").filter(|value| !value.is_empty()) {
+            self.registry_blobs_dir = Some(value);
+        }
+
+        let invalid_env = |name: &str, reason: String| ConfigError::Env {
+            name: name.to_string(),
+            reason,
+        };
+        if let Some(value) = env_var("CAKE_JOBS").filter(|value| !value.is_empty()) {
+            self.download_jobs = match value.parse::<usize>() {
+                Ok(jobs) if jobs > 0 => jobs,
+                _ => {
+                    return Err(invalid_env(
+                        "CAKE_JOBS",
+                        format!("{} is not a positive number", value),
+                    ))
+                }
+            };
+        }
+        if let Some(value) = env_var("CAKE_RATE_LIMIT").filter(|value| !value.is_empty()) {
+            self.download_rate_limit =
+                Some(parse_byte_size(&value).map_err(|e| invalid_env("CAKE_RATE_LIMIT", e))?);
+        }
+        if let Some(value) = env_var("CAKE_COMPRESSION").filter(|value| !value.is_empty()) {
+            self.compression = Codec::from_str(&value, true).map_err(|_| {
+                invalid_env("CAKE_COMPRESSION", format!("{} is not a codec", value))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn apply_args(&mut self, config_args: &ConfigArgs) {
+        let settings = [
+            (&config_args.store, &mut self.store_dir),
+            (&config_args.results, &mut self.results_dir),
+            (&config_args.registry_url, &mut self.registry_url),
+            (&config_args.hf_endpoint, &mut self.hf_endpoint),
+        ];
+        for (arg, setting) in settings {
+            if let Some(value) = arg {
+                setting.clone_from(value);
+            }
+        }
+
+        if let Some(jobs) = config_args.jobs {
+            self.download_jobs = jobs;
+        }
+        if let Some(limit_rate) = config_args.limit_rate {
+            self.download_rate_limit = Some(limit_rate);
+        }
+        if let Some(compression) = config_args.compression {
+            self.compression = compression;
+        }
+    }
+
+    /// Base URLs are joined with paths that start with a slash
+    pub fn registry_url(&self) -> &str {
+        self.registry_url.trim_end_matches('/')
+    }
+
+    pub fn hf_endpoint(&self) -> &str {
+        self.hf_endpoint.trim_end_matches('/')
+    }
+}
+
+fn get_default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|config_dir| config_dir.join("cake").join("config.toml"))
+}
+
+/// Parses a number of bytes with an optional `K`, `M` or `G` suffix, which are powers of 1024 like curl's
+pub fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&value[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => number
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("{} is too large", value)),
+        _ => Err(format!("{} is not a positive number of bytes", value)),
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_config_layers() {
+        let mut config: Config = toml::from_str(
+            r#"
+            store_dir = "/file/store"
+            registry_url = "http://file-registry:3000/"
+            compression = "none"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.hf_endpoint, Config::default().hf_endpoint);
+
+        config
+            .apply_env(|name| match name {
+                "CAKE_REGISTRY_URL" => Some("http://env-registry:3000".to_string()),
+                "HF_ENDPOINT" => Some("https://hf-mirror.example".to_string()),
+                "CAKE_JOBS" => Some("4".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        config.apply_args(&ConfigArgs {
+            hf_endpoint: Some("https://cli-mirror.example/".to_string()),
+            limit_rate: Some(1024),
+            ..ConfigArgs::default()
+        });
+
+        assert_eq!(config.store_dir, "/file/store");
+        assert_eq!(config.registry_url(), "http://env-registry:3000");
+        assert_eq!(config.hf_endpoint(), "https://cli-mirror.example");
+        assert_eq!(config.results_dir, Config::default().results_dir);
+        assert_eq!(config.download_jobs, 4);
+        assert_eq!(config.download_rate_limit, Some(1024));
+        assert_eq!(config.compression, Codec::None);
+
+        assert!(config
+            .apply_env(|name| (name == "CAKE_JOBS").then(|| "0".to_string()))
+            .is_err());
+        assert!(config
+            .apply_env(|name| (name == "CAKE_COMPRESSION").then(|| "zstd".to_string()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_config_file_rejects_zero_values() {
+        let config_path = PathBuf::from("config.toml");
+
+        let config = Config::parse(
+            config_path.clone(),
+            "download_jobs = 2\ndownload_rate_limit = 1024\n",
+        )
+        .unwrap();
+        assert_eq!(config.download_jobs, 2);
+        assert_eq!(config.download_rate_limit, Some(1024));
+
+        assert!(matches!(
+            Config::parse(config_path.clone(), "download_jobs = 0\n"),
+            Err(ConfigError::Invalid { .. })
+        ));
+        assert!(matches!(
+            Config::parse(config_path, "download_rate_limit = 0\n"),
+            Err(ConfigError::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!(parse_byte_size("1500"), Ok(1500));
+        assert_eq!(parse_byte_size("500K"), Ok(500 * 1024));
+        assert_eq!(parse_byte_size("10m"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_byte_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_byte_size("0").is_err());
+        assert!(parse_byte_size("fast").is_err());
+        assert!(parse_byte_size("").is_err());
+    }
+
+    #[test]
+    fn test_config_rejects_unknown_settings() {
+        assert!(toml::from_str::<Config>(r#"storage = "/store""#).is_err());
+    }
+}
@@ -1,31 +1,111 @@
+use futures::stream::{self, StreamExt};
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use rayon::iter::ParallelIterator;
-use rayon::prelude::*;
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
-use reqwest::{blocking::Client, Error};
 use serde_json::{json, Value};
-use std::env;
-use std::fs::{self, File};
-use std::io::prelude::*;
-use std::io::Read;
-use std::path::PathBuf;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 
+use crate::byteranges::{self, ByteRange};
+use crate::config::Config;
+use crate::fetch::{self, DownloadError, Fetcher, RateLimiter, RetryPolicy};
 use crate::hf;
-use crate::{hasher, Layer};
+use crate::plan::{RangePlanner, RangeRequest, RemainingLayer};
+use crate::shards::{self, ShardIndex};
+use crate::{hasher, manifest, store, Layer};
 
-pub fn get_download_url_from_model_id(model_id: &str, file_name: &str) -> String {
+/// Weights in other formats are skipped by default, since the safetensors files already contain them
+pub const DEFAULT_EXCLUDE_PATTERNS: [&str; 15] = [
+    "*.bin",
+    "*.pt",
+    "*.pth",
+    "*.ckpt",
+    "*.h5",
+    "*.msgpack",
+    "*.onnx",
+    "*.onnx_data",
+    "*.gguf",
+    "*.tflite",
+    "*.npz",
+    "*.ot",
+    "*.mlmodel",
+    "*.mlpackage/*",
+    ".gitattributes",
+];
+
+/// Decides which of the files in a repo, other than the safetensors files, are downloaded
+pub struct FileFilter {
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl FileFilter {
+    /// Every file is included when no include patterns are given
+    pub fn new(include: &[String], exclude: &[String]) -> Result<FileFilter, globset::Error> {
+        let include = if include.is_empty() {
+            vec!["*".to_string()]
+        } else {
+            include.to_vec()
+        };
+
+        Ok(FileFilter {
+            include: build_glob_set(&include)?,
+            exclude: build_glob_set(exclude)?,
+        })
+    }
+
+    pub fn is_match(&self, file_name: &str) -> bool {
+        self.include.is_match(file_name) && !self.exclude.is_match(file_name)
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+
+    builder.build()
+}
+
+pub fn get_download_url_from_model_id(
+    hf_endpoint: &str,
+    model_id: &str,
+    revision: &str,
+    file_name: &str,
+) -> String {
     format!(
-        "https://huggingface.co/{}/resolve/main/{}?download=true",
-        model_id, file_name
+        "{}/{}/resolve/{}/{}?download=true",
+        hf_endpoint,
+        model_id,
+        hf::encode_revision(revision),
+        file_name
     )
     .to_string()
 }
 
-pub fn download_safetensors_file_by_model_id(model_id: &str) {
+/// How a model is downloaded, as chosen on the command line
+pub struct DownloadOptions {
+    pub codec: store::Codec,
+    pub retry_policy: RetryPolicy,
+    pub file_filter: FileFilter,
+    /// Publish the hashes of layers that had to be hashed locally to the registry
+    pub publish: bool,
+}
+
+pub fn download_safetensors_file_by_model_id(
+    config: &Config,
+    model_id: &str,
+    revision: &str,
+    options: &DownloadOptions,
+) {
     // Query the HF API to see the file names
 
-    let model_info_result = hf::get_model_info(model_id);
+    let model_info_result = hf::get_model_info(config.hf_endpoint(), model_id, revision);
     if model_info_result.is_err() {
         // TODO: Handle better, print the error message too
         panic!("Unable to retrieve model info when attempting download!")
@@ -33,6 +113,13 @@ pub fn download_safetensors_file_by_model_id(model_id: &str) {
 
     let model_info = model_info_result.unwrap();
 
+    // Download everything from the same commit, even if the branch moves while we are downloading
+    let commit_sha = model_info.get_commit_sha(revision);
+    println!(
+        "{}@{} resolved to commit {}",
+        model_id, revision, commit_sha
+    );
+
     let model_filenames: Vec<&String> = model_info
         .siblings
         .as_slice()
@@ -62,8 +149,27 @@ pub fn download_safetensors_file_by_model_id(model_id: &str) {
         .iter()
         .for_each(|f| println!("> {}", f));
 
-    // TODO: Configurable download folder, or pick a better sensible default
-    let download_dir: &str = "./download";
+    let download_dir: &str = &config.store_dir;
+    // Keeps garbage collection from deleting layers before the manifest that references them is saved
+    let _store_lock = store::lock_store(download_dir, false)
+        .unwrap_or_else(|e| panic!("Unable to lock {}: {}", download_dir, e));
+    let (fetcher, registry_fetcher) = get_fetchers(config, options.retry_policy);
+
+    // The small files needed to load the model, such as its config and tokenizer
+    let mut all_files_downloaded = download_model_files(
+        config,
+        &model_info,
+        model_id,
+        &commit_sha,
+        options,
+        &fetcher,
+    );
+
+    let mut any_layers_hashed_locally = false;
+    let mut any_headers_downloaded = false;
+    let mut any_stale_hashes = false;
+    // The tensor names in the header of each file, to check the indexes of sharded models against
+    let mut shard_tensors: BTreeMap<String, Vec<String>> = BTreeMap::new();
     let mut file_index = 0;
     for file_name in safetensors_filenames {
         println!(
@@ -73,23 +179,22 @@ pub fn download_safetensors_file_by_model_id(model_id: &str) {
             file_name
         );
 
-        let file_url = &get_download_url_from_model_id(model_id, file_name);
+        let file_url =
+            &get_download_url_from_model_id(config.hf_endpoint(), model_id, &commit_sha, file_name);
 
         // TODO: Propose that this part that determines the hashes could be added to the safetensors spec itself
-        // TODO: Handle the situation where the registry is unavailable by downloading all of the layers
-        let (model_header, layers_to_hashes_map) =
-            hasher::get_model_file_hashes(model_id, file_name);
+        let (model_header, mut layers_to_hashes_map) =
+            match hasher::get_model_file_hashes(config, model_id, &commit_sha, file_name) {
+                Ok(model_file_hashes) => model_file_hashes,
+                Err(e) => {
+                    println!("Unable to retrieve the header of {}: {}", file_name, e);
+                    all_files_downloaded = false;
+                    file_index += 1;
+                    continue;
+                }
+            };
 
-        let locally_available_hashes = hasher::get_locally_available_hashes(download_dir);
-
-        let mut model_layers_to_download: Vec<String> = layers_to_hashes_map
-            .iter()
-            .filter(|(_, layer_hash)| !locally_available_hashes.contains(layer_hash))
-            .map(|(ln, _)| ln)
-            .cloned()
-            .collect();
-
-        let mut all_layer_names: Vec<_> = model_header
+        let all_layer_names: Vec<String> = model_header
             .raw_header
             .as_object()
             .unwrap()
@@ -97,12 +202,29 @@ pub fn download_safetensors_file_by_model_id(model_id: &str) {
             .filter(|k| k != &"__metadata__")
             .map(|n| n.to_string())
             .collect();
+        shard_tensors.insert(file_name.to_string(), all_layer_names.clone());
 
-        // If we have no hashes available, then download all the layers instead
-        if layers_to_hashes_map.is_empty() {
-            println!("Layer to Hashes Map is missing, downloading all layers instead...");
-            model_layers_to_download.clear();
-            model_layers_to_download.append(&mut all_layer_names);
+        // Layers without a hash from the registry are downloaded anyway and hashed locally
+        let model_layers_to_download: Vec<String> = all_layer_names
+            .iter()
+            .filter(|ln| match layers_to_hashes_map.get(*ln) {
+                // A single stat per layer, rather than listing the whole store
+                Some(layer_hash) => store::find_blob(download_dir, layer_hash).is_none(),
+                None => true,
+            })
+            .cloned()
+            .collect();
+        let unhashed_layers_count = all_layer_names
+            .iter()
+            .filter(|ln| !layers_to_hashes_map.contains_key(*ln))
+            .count();
+
+        if unhashed_layers_count > 0 {
+            println!(
+                "{} of {} layers have no known hash, they will be downloaded and hashed locally",
+                unhashed_layers_count,
+                all_layer_names.len()
+            );
         }
 
         if model_layers_to_download.is_empty() {
@@ -110,86 +232,502 @@ pub fn download_safetensors_file_by_model_id(model_id: &str) {
                 "All layers have already been downloaded for {} of {}",
                 file_name, model_id
             );
+        } else {
+            println!(
+                "{} Layers Total. {} Layers left to be downloaded",
+                all_layer_names.len(),
+                model_layers_to_download.len()
+            );
+
+            // Setup the progress bars
+            let main_bar = ProgressBar::new(model_layers_to_download.len() as u64).with_style(
+                ProgressStyle::with_template(
+                    "[{elapsed_precise}] {bar:20.green/yellow} {pos:>4}/{len:4} {spinner:.blue} {msg}",
+                )
+                .unwrap(),
+            );
+            main_bar.enable_steady_tick(Duration::from_millis(500));
+            let main_bar_clone = main_bar.clone();
+            let mp: MultiProgress = MultiProgress::new();
+            mp.add(main_bar);
+            let layers_downloaded_count = model_layers_to_download.len();
+
+            let layers = match get_layers_from_header(
+                &model_header.raw_header,
+                model_header.raw_header_bytes.len() as u64,
+                Some(model_layers_to_download),
+            ) {
+                Ok(layers) => layers,
+                Err(e) => {
+                    main_bar_clone
+                        .abandon_with_message(format!("Unable to download {}: {}", file_name, e));
+                    all_files_downloaded = false;
+                    file_index += 1;
+                    continue;
+                }
+            };
+            let downloaded_layers: Vec<(Layer, Result<String, DownloadError>)> =
+                download_layers_to_store(
+                    layers,
+                    &layers_to_hashes_map,
+                    &LayerDownload {
+                        file_url,
+                        registry_url: Some(config.registry_url()),
+                        storage_dir: download_dir,
+                        codec: options.codec,
+                        fetcher: &fetcher,
+                        registry_fetcher: &registry_fetcher,
+                        jobs: config.download_jobs,
+                    },
+                    mp,
+                    |layer, result| {
+                        // Increment the progress bar
+                        main_bar_clone.inc(1);
+                        match result {
+                            Ok(_) => main_bar_clone
+                                .set_message(format!("Last completed: {}", layer.name)),
+                            Err(e) => main_bar_clone
+                                .println(format!("Failed to download {}: {}", layer.name, e)),
+                        }
+                    },
+                );
+
+            let mut failed_layers_count = 0;
+            for (layer, result) in downloaded_layers {
+                match result {
+                    Ok(layer_hash) => {
+                        // A registry hash that did not match the bytes on Hugging Face is replaced and published again
+                        if let Some(stale_hash) = layers_to_hashes_map.get(&layer.name) {
+                            any_stale_hashes |= *stale_hash != layer_hash;
+                        }
+                        layers_to_hashes_map.insert(layer.name, layer_hash);
+                    }
+                    Err(_) => failed_layers_count += 1,
+                }
+            }
+
+            if failed_layers_count > 0 {
+                main_bar_clone.abandon_with_message(format!(
+                    "{} layers of {} failed to download, run the download again to resume",
+                    failed_layers_count, file_name
+                ));
+                all_files_downloaded = false;
+                file_index += 1;
+                continue;
+            }
+
+            main_bar_clone.finish_with_message(format!("{} All done!", file_name));
+
+            println!(
+                "{} layers already present, {} layers downloaded",
+                all_layer_names.len() - layers_downloaded_count,
+                layers_downloaded_count
+            );
+        }
+
+        // Record which blobs make up this file now that they are all available
+        let model_manifest = manifest::Manifest::from_header(
+            model_id,
+            &commit_sha,
+            file_name,
+            &model_header.raw_header_bytes,
+            &model_header.raw_header,
+            &layers_to_hashes_map,
+        )
+        .map(|m| manifest::Manifest {
+            lfs_sha256: model_info.get_lfs_sha256(file_name),
+            ..m
+        });
+
+        match &model_manifest {
+            Some(model_manifest) => {
+                manifest::save_manifest(download_dir, model_manifest);
+                any_layers_hashed_locally |= unhashed_layers_count > 0 || any_stale_hashes;
+                any_headers_downloaded |= !model_header.from_registry;
+            }
+            None => all_files_downloaded = false,
+        }
+
+        file_index += 1;
+    }
+
+    // The shards of a model are one set of tensors, so a tensor missing from its index would not be loaded
+    for index_file_name in model_info
+        .siblings
+        .iter()
+        .map(|s| &s.rfilename)
+        .filter(|f| shards::is_index_file(f))
+    {
+        let validation_result =
+            get_shard_index(config, model_id, &commit_sha, index_file_name, &fetcher)
+                .and_then(|index| Ok(index.validate(index_file_name, &shard_tensors)?));
+        match validation_result {
+            Ok(()) => println!("{} agrees with the headers of its shards", index_file_name),
+            Err(e) => println!(
+                "WARNING: {} does not agree with the headers of its shards: {}",
+                index_file_name, e
+            ),
+        }
+    }
+
+    // Share the hashes that were computed locally, so the next download can skip layers it already has,
+    // and the headers, so it does not need to ask Hugging Face for them.
+    // The registry replaces the hashes of the whole revision, so every file is published together.
+    if options.publish && (any_layers_hashed_locally || any_headers_downloaded) {
+        let manifests = manifest::load_manifests(download_dir, model_id, &commit_sha);
+
+        if any_layers_hashed_locally {
+            // Hashes are keyed by tensor name, so files that share a tensor name cannot be published together
+            match hasher::get_model_hashes_from_manifests(&manifests) {
+                Ok(model_hashes) => match hasher::publish_model_hashes(
+                    config.registry_url(),
+                    model_id,
+                    &commit_sha,
+                    &model_hashes,
+                ) {
+                    Ok(()) => println!(
+                        "Published the hashes of {} layers to {}",
+                        model_hashes.len(),
+                        config.registry_url()
+                    ),
+                    Err(e) => println!("Unable to publish the hashes to the registry: {}", e),
+                },
+                Err(e) => println!("Unable to publish the hashes to the registry: {}", e),
+            }
+        }
+
+        match hasher::publish_file_headers(config.registry_url(), &manifests) {
+            Ok(()) => println!(
+                "Published the headers of {} files to {}",
+                manifests.len(),
+                config.registry_url()
+            ),
+            Err(e) => println!("Unable to publish the headers to the registry: {}", e),
+        }
+    }
+
+    // Point the requested revision at the commit that was downloaded, so it can be exported by name
+    if all_files_downloaded {
+        manifest::save_revision_ref(download_dir, model_id, revision, &commit_sha);
+    }
+}
+
+/// Downloads every file of the repo that is not a safetensors file and passes the filter, as a single blob each.
+/// Files are content-addressed like layers, so identical files across models are only stored once.
+/// Returns whether every file was downloaded.
+fn download_model_files(
+    config: &Config,
+    model_info: &hf::ModelInfo,
+    model_id: &str,
+    commit_sha: &str,
+    options: &DownloadOptions,
+    fetcher: &Fetcher,
+) -> bool {
+    let storage_dir: &str = &config.store_dir;
+    let file_names: Vec<&String> = model_info
+        .siblings
+        .iter()
+        .map(|s| &s.rfilename)
+        .filter(|f| !f.ends_with(".safetensors") && options.file_filter.is_match(f))
+        .collect();
+
+    if file_names.is_empty() {
+        return true;
+    }
+
+    println!("{} other files to be downloaded:", file_names.len());
+    file_names.iter().for_each(|f| println!("> {}", f));
+
+    let mut files_manifest = manifest::load_files_manifest(storage_dir, model_id, commit_sha);
+    let mut all_files_downloaded = true;
+    for file_name in file_names {
+        let already_downloaded = files_manifest
+            .files
+            .get(file_name)
+            .is_some_and(|entry| store::find_blob(storage_dir, &entry.hash).is_some());
+        if already_downloaded {
             continue;
         }
 
-        println!(
-            "{} Layers Total. {} Layers left to be downloaded",
-            all_layer_names.len(),
-            model_layers_to_download.len()
-        );
+        let file_url =
+            get_download_url_from_model_id(config.hf_endpoint(), model_id, commit_sha, file_name);
+        let lfs = model_info.get_lfs(file_name);
+        match download_file_to_store(
+            &file_url,
+            lfs.map(|lfs| lfs.sha256.to_string()),
+            lfs.map(|lfs| lfs.size),
+            storage_dir,
+            options.codec,
+            fetcher,
+        ) {
+            Ok(file_entry) => {
+                files_manifest
+                    .files
+                    .insert(file_name.to_string(), file_entry);
+            }
+            Err(e) => {
+                println!("Failed to download {}: {}", file_name, e);
+                all_files_downloaded = false;
+            }
+        }
+    }
 
-        // Setup the progress bars
-        let main_bar = ProgressBar::new(model_layers_to_download.len() as u64).with_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] {bar:20.green/yellow} {pos:>4}/{len:4} {spinner:.blue} {msg}",
-            )
-            .unwrap(),
-        );
-        main_bar.enable_steady_tick(Duration::from_millis(500));
-        let main_bar_clone = main_bar.clone();
-        let mp: MultiProgress = MultiProgress::new();
-        mp.add(main_bar);
-        let layers_downloaded_count = model_layers_to_download.len();
-
-        par_download_layers(
-            model_header.raw_header,
-            file_url.to_string(),
-            Some(model_layers_to_download),
-            mp,
-        )
-        .for_each(|(layer, layer_bytes)| {
-            let layer_hash = layers_to_hashes_map.get(&layer.name).unwrap();
+    // Save whatever was downloaded, so the next run only fetches the files that failed
+    manifest::save_files_manifest(storage_dir, &files_manifest);
+
+    all_files_downloaded
+}
+
+/// Downloads the given blobs of a safetensors file again, such as blobs that went missing from the store.
+/// The manifest has everything needed to find the layers, so Hugging Face is not asked for the header.
+/// Fails if the header in the manifest is invalid; layers that fail to download are only reported.
+pub fn refetch_layers(
+    config: &Config,
+    model_manifest: &manifest::Manifest,
+    hashes: &HashSet<String>,
+    codec: store::Codec,
+    retry_policy: RetryPolicy,
+) -> Result<(), DownloadError> {
+    // Tensors with the same contents only need to be downloaded once
+    let mut layers_to_hashes_map: HashMap<String, String> = HashMap::new();
+    let mut seen_hashes: HashSet<&str> = HashSet::new();
+    for (tensor_name, tensor) in &model_manifest.tensors {
+        if hashes.contains(&tensor.hash) && seen_hashes.insert(&tensor.hash) {
+            layers_to_hashes_map.insert(tensor_name.to_string(), tensor.hash.to_string());
+        }
+    }
+    if layers_to_hashes_map.is_empty() {
+        return Ok(());
+    }
 
-            // Decide where to store the this layer by its hash
-            // TODO: Handle this failure case ahead of time somehow
-            let mut hashed_layer_path = PathBuf::new();
-            hashed_layer_path.push(download_dir);
-            hashed_layer_path.push(layer_hash);
+    println!(
+        "Downloading {} layers of {}@{}: {} again",
+        layers_to_hashes_map.len(),
+        model_manifest.model_id,
+        model_manifest.revision,
+        model_manifest.file_name
+    );
+    let header: Value = serde_json::from_str(&model_manifest.header)?;
+    let layers = get_layers_from_header(
+        &header,
+        model_manifest.header.len() as u64,
+        Some(layers_to_hashes_map.keys().cloned().collect()),
+    )?;
 
-            // Create the directory if its does not exist
-            fs::create_dir_all(hashed_layer_path.parent().unwrap()).unwrap();
-            // Write the file
-            let mut file = File::create(hashed_layer_path).unwrap();
+    let file_url = &get_download_url_from_model_id(
+        config.hf_endpoint(),
+        &model_manifest.model_id,
+        &model_manifest.revision,
+        &model_manifest.file_name,
+    );
+    let (fetcher, registry_fetcher) = get_fetchers(config, retry_policy);
+    let layer_download = LayerDownload {
+        file_url,
+        registry_url: Some(config.registry_url()),
+        storage_dir: &config.store_dir,
+        codec,
+        fetcher: &fetcher,
+        registry_fetcher: &registry_fetcher,
+        jobs: config.download_jobs,
+    };
 
-            main_bar_clone.set_message(format!("Writing: {}", layer.name));
+    download_layers_to_store(
+        layers,
+        &layers_to_hashes_map,
+        &layer_download,
+        MultiProgress::new(),
+        |layer, result| {
+            if let Err(e) = result {
+                println!("Failed to download {}: {}", layer.name, e);
+            }
+        },
+    );
 
-            file.write_all(&layer_bytes).unwrap();
-            file.flush().unwrap();
-            // Increment the progress bar
-            main_bar_clone.inc(1);
+    Ok(())
+}
 
-            main_bar_clone.set_message(format!("Last completed: {}", layer.name));
-        });
+/// Downloads the given blobs of a model's other files again, such as blobs that went missing from the store
+pub fn refetch_files(
+    config: &Config,
+    files_manifest: &manifest::FilesManifest,
+    hashes: &HashSet<String>,
+    codec: store::Codec,
+    retry_policy: RetryPolicy,
+) {
+    let (fetcher, _) = get_fetchers(config, retry_policy);
 
-        main_bar_clone.finish_with_message(format!("{} All done!", file_name));
+    for (file_name, file_entry) in &files_manifest.files {
+        if !hashes.contains(&file_entry.hash) {
+            continue;
+        }
 
         println!(
-            "{} layers already present, {} layers downloaded",
-            layers_to_hashes_map.len() - layers_downloaded_count,
-            layers_downloaded_count
+            "Downloading {} of {}@{} again",
+            file_name, files_manifest.model_id, files_manifest.revision
         );
+        let file_url = get_download_url_from_model_id(
+            config.hf_endpoint(),
+            &files_manifest.model_id,
+            &files_manifest.revision,
+            file_name,
+        );
+        let result = download_file_to_store(
+            &file_url,
+            Some(file_entry.hash.to_string()),
+            Some(file_entry.size),
+            &config.store_dir,
+            codec,
+            &fetcher,
+        );
+        if let Err(e) = result {
+            println!("Failed to download {}: {}", file_name, e);
+        }
+    }
+}
 
-        file_index += 1;
+/// Returns a fetcher for Hugging Face and one for the registry, which share the speed cap of the config.
+/// Falling back to Hugging Face is better than retrying a registry that is struggling, so the registry's is not
+/// retried.
+pub fn get_fetchers(config: &Config, retry_policy: RetryPolicy) -> (Fetcher, Fetcher) {
+    let rate_limiter = config
+        .download_rate_limit
+        .map(|bytes_per_second| Arc::new(RateLimiter::new(bytes_per_second)));
+    let registry_retry_policy = RetryPolicy {
+        max_retries: 0,
+        ..retry_policy
+    };
+
+    (
+        Fetcher::new(retry_policy).with_rate_limiter(rate_limiter.clone()),
+        Fetcher::for_registry(registry_retry_policy).with_rate_limiter(rate_limiter),
+    )
+}
+
+/// Downloads a file that is not split into layers into its partial file, hashing it on the way in, then moves it into
+/// the store. Files can be as large as layers, so a partial file left by a previous run is picked up where it stopped
+/// if the size of the file is known.
+fn download_file_to_store(
+    file_url: &str,
+    expected_hash: Option<String>,
+    expected_size: Option<u64>,
+    storage_dir: &str,
+    codec: store::Codec,
+    fetcher: &Fetcher,
+) -> Result<manifest::FileEntry, DownloadError> {
+    // Like layers, files without a known hash are named after their URL, which includes the commit sha
+    let partial_key = expected_hash
+        .clone()
+        .unwrap_or_else(|| hasher::sha256_hash(file_url.as_bytes()));
+    let partial_blob_path = store::get_partial_blob_path(storage_dir, &partial_key)?;
+    fs::create_dir_all(partial_blob_path.parent().unwrap())?;
+    let partial_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&partial_blob_path)?;
+    let mut downloaded_bytes = partial_file.metadata()?.len();
+    if expected_size.is_none_or(|expected_size| downloaded_bytes > expected_size) {
+        partial_file.set_len(0)?;
+        downloaded_bytes = 0;
+    }
+
+    let mut partial_writer = hasher::HashingWriter::new(partial_file);
+    if downloaded_bytes > 0 {
+        partial_writer.hash_existing(&mut File::open(&partial_blob_path)?)?;
+    }
+
+    // The bytes are written and hashed on a blocking thread while the next ones arrive
+    let partial_writer = fetch::block_on(async {
+        let mut blocking_writer = fetch::BlockingWriter::new(partial_writer);
+        let result = match expected_size {
+            Some(expected_size) if downloaded_bytes > 0 => {
+                fetcher
+                    .download_part_of_file_to_writer(
+                        file_url,
+                        downloaded_bytes,
+                        expected_size - downloaded_bytes,
+                        None,
+                        &mut blocking_writer,
+                    )
+                    .await
+            }
+            _ => {
+                fetcher
+                    .download_file_to_writer(file_url, None, &mut blocking_writer)
+                    .await
+            }
+        };
+        let partial_writer = blocking_writer.finish().await?;
+        result.map(|_| partial_writer)
+    })?;
+    let hash = partial_writer.finalize();
+    let size = fs::metadata(&partial_blob_path)?.len();
+
+    // Files stored with git LFS have a known hash, the rest are small enough to trust the response
+    if let Some(expected_hash) = expected_hash {
+        if expected_hash != hash {
+            // The bad bytes could be anywhere in the file, so start over
+            fs::remove_file(&partial_blob_path)?;
+            return Err(DownloadError::HashMismatch {
+                expected: expected_hash,
+                actual: hash,
+            });
+        }
     }
 
-    // TODO: Add support to export the safetensors file/s conditionally at the end
+    if store::find_blob(storage_dir, &hash).is_some() {
+        fs::remove_file(&partial_blob_path)?;
+    } else {
+        fs::create_dir_all(store::get_blob_dir(storage_dir, &hash)?)?;
+        store::promote_blob_file(storage_dir, &hash, &partial_blob_path, codec)?;
+    }
+
+    Ok(manifest::FileEntry { hash, size })
 }
 
-pub fn par_download_layers(
-    header: Value,
-    file_url: String,
-    tensor_names_allow_list: Option<Vec<String>>,
-    mp: MultiProgress,
-) -> impl ParallelIterator<Item = (Layer, Vec<u8>)> {
-    // print!("{}", header);
+/// Reads the index of a sharded model from the store if it was downloaded with the other files of the model, or
+/// from Hugging Face otherwise, such as when the file filter excluded it
+pub fn get_shard_index(
+    config: &Config,
+    model_id: &str,
+    commit_sha: &str,
+    index_file_name: &str,
+    fetcher: &Fetcher,
+) -> anyhow::Result<ShardIndex> {
+    let storage_dir: &str = &config.store_dir;
+    let files_manifest = manifest::load_files_manifest(storage_dir, model_id, commit_sha);
 
-    // Setup the reqwest client to enable connection pooling
-    let client = Client::new();
+    let mut index_bytes: Vec<u8> = Vec::new();
+    let is_in_store = files_manifest
+        .files
+        .get(index_file_name)
+        .is_some_and(|entry| {
+            store::open_blob(storage_dir, &entry.hash)
+                .and_then(|mut reader| reader.read_to_end(&mut index_bytes))
+                .is_ok()
+        });
+    if !is_in_store {
+        let index_url = get_download_url_from_model_id(
+            config.hf_endpoint(),
+            model_id,
+            commit_sha,
+            index_file_name,
+        );
+        index_bytes = fetcher.download_file(&index_url)?;
+    }
+
+    Ok(ShardIndex::from_slice(&index_bytes)?)
+}
+
+fn get_layers_from_header(
+    header: &Value,
+    header_length: u64,
+    tensor_names_allow_list: Option<Vec<String>>,
+) -> Result<Vec<Layer>, DownloadError> {
+    // Offsets in the header are relative to the tensor data, which comes after the header length and the header
+    let data_start = 8 + header_length;
 
-    // Iterate over each tensor and download it
     // Only download the layers in the allow list, if it is available
-    let layers: Vec<Layer> = header
+    let mut layers: Vec<Layer> = header
         .as_object()
         .unwrap()
         .iter()
@@ -204,160 +742,1070 @@ pub fn par_download_layers(
             }
         })
         .map(|(name, metadata)| {
-            let offsets = metadata
-                .get("data_offsets")
-                .and_then(Value::as_array)
-                .unwrap();
-            let offset_start = offsets[0].as_u64().unwrap();
-            let offset_end = offsets[1].as_u64().unwrap();
-            let offset_diff = offset_end - offset_start;
-            Layer {
+            // Headers from Hugging Face are not checked by anyone else, and a tensor that ends before it starts would
+            // otherwise underflow its size
+            let offsets = metadata.get("data_offsets").and_then(Value::as_array);
+            let (offset_start, offset_end) = offsets
+                .and_then(|offsets| Some((offsets.first()?.as_u64()?, offsets.get(1)?.as_u64()?)))
+                .filter(|(offset_start, offset_end)| offset_end >= offset_start)
+                .ok_or_else(|| DownloadError::InvalidOffsets(name.to_string()))?;
+            Ok(Layer {
                 name: name.to_string(),
                 offset_start,
                 offset_end,
-                size: offset_diff,
-            }
+                file_offset: data_start + offset_start,
+                size: offset_end - offset_start,
+            })
+        })
+        .collect::<Result<Vec<Layer>, DownloadError>>()?;
+
+    // Start with the largest layers so they are not left running on their own at the end
+    layers.sort_by_key(|layer| std::cmp::Reverse(layer.size));
+
+    Ok(layers)
+}
+
+fn add_progress_bar(mp: &MultiProgress, size: u64, message: String) -> ProgressBar {
+    let pb = mp.add(ProgressBar::new(size));
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:20.cyan/blue} {pos:>8}/{len:8}B {msg}",
+        )
+        .unwrap(),
+    );
+    pb.enable_steady_tick(Duration::from_millis(200));
+    pb.set_message(message);
+
+    pb
+}
+
+/// Shows the progress of a range request, named after the layers it downloads
+fn add_request_progress_bar(
+    mp: &MultiProgress,
+    request: &RangeRequest,
+    layers: &[&Layer],
+) -> ProgressBar {
+    let first_part = &request.parts[0];
+    let layer_name = &layers[first_part.layer_index].name;
+    let message = match (first_part.piece, request.parts.len()) {
+        (Some(piece), _) => format!("{} (piece {})", layer_name, piece + 1),
+        (None, 1) => layer_name.to_string(),
+        (None, part_count) => format!("{} and {} more", layer_name, part_count - 1),
+    };
+
+    add_progress_bar(mp, request.size(), message)
+}
+
+/// Splits the body of a range request between the parts it covers, in order
+struct PartsWriter<W: Write> {
+    writers: Vec<W>,
+    /// How many more bytes each part expects
+    remaining_sizes: Vec<u64>,
+    current_part: usize,
+}
+
+impl<W: Write> PartsWriter<W> {
+    fn new(writers: Vec<W>, remaining_sizes: Vec<u64>) -> PartsWriter<W> {
+        PartsWriter {
+            writers,
+            remaining_sizes,
+            current_part: 0,
+        }
+    }
+
+    fn into_writers(self) -> Vec<W> {
+        self.writers
+    }
+}
+
+impl<W: Write> Write for PartsWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Parts that already have all of their bytes are skipped
+        while self.remaining_sizes.get(self.current_part) == Some(&0) {
+            self.current_part += 1;
+        }
+        let Some(remaining_size) = self.remaining_sizes.get_mut(self.current_part) else {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "received more bytes than the layers of the request add up to",
+            ));
+        };
+
+        let size = buf.len().min(*remaining_size as usize);
+        let written_size = self.writers[self.current_part].write(&buf[..size])?;
+        *remaining_size -= written_size as u64;
+
+        Ok(written_size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writers
+            .iter_mut()
+            .try_for_each(|writer| writer.flush())
+    }
+}
+
+/// Each layer along with its hash, or why it could not be downloaded
+pub type LayerResults = Vec<(Layer, Result<String, DownloadError>)>;
+
+/// Downloads and hashes each layer without storing it, for computing the hashes of a model.
+/// Bytes are hashed as they arrive, so memory use does not depend on the size of the layers. Small layers next to
+/// each other are downloaded with a single request.
+/// Calls `on_layer_done` as each layer finishes, and returns the hash of every layer, or an error if the header is
+/// invalid.
+pub fn hash_layers(
+    header: &Value,
+    header_length: u64,
+    file_url: &str,
+    fetcher: &Fetcher,
+    jobs: usize,
+    mp: MultiProgress,
+    on_layer_done: impl Fn(&Layer, &Result<String, DownloadError>),
+) -> Result<LayerResults, DownloadError> {
+    let sorted_layers = get_layers_from_header(header, header_length, None)?;
+
+    // Each layer is hashed in order, so layers are never split into pieces
+    let remaining_layers: Vec<RemainingLayer> = sorted_layers
+        .iter()
+        .map(|layer| RemainingLayer {
+            file_offset: layer.file_offset,
+            size: layer.size,
+            downloaded_bytes: 0,
         })
         .collect();
-    // println!("{:?}", layers);
+    let range_requests = RangePlanner::without_splitting().plan(&remaining_layers);
 
-    let mut sorted_layers = layers.clone();
-    sorted_layers.sort_by(|a: &Layer, b: &Layer| b.size.cmp(&a.size));
+    let layers: Vec<&Layer> = sorted_layers.iter().collect();
+    let (layers, mp, on_layer_done) = (&layers, &mp, &on_layer_done);
+    let request_hashes = stream::iter(range_requests)
+        .map(|request| async move {
+            let pb = add_request_progress_bar(mp, &request, layers);
 
-    // println!("{:?}", sorted_layers);
+            // Download the tensors, hashing them on a blocking thread
+            let parts_writer = PartsWriter::new(
+                request
+                    .parts
+                    .iter()
+                    .map(|_| hasher::HashingWriter::new(io::sink()))
+                    .collect(),
+                request.parts.iter().map(|part| part.size).collect(),
+            );
+            let result = fetcher
+                .spawn_download_ranges_of_file_to_writer(
+                    file_url,
+                    &request.ranges,
+                    Some(&pb),
+                    parts_writer,
+                )
+                .await
+                .map(|parts_writer| {
+                    parts_writer
+                        .into_writers()
+                        .into_iter()
+                        .map(hasher::HashingWriter::finalize)
+                        .collect::<Vec<_>>()
+                })
+                .map_err(Arc::new);
+            pb.finish_and_clear();
 
-    sorted_layers.into_par_iter().map(move |layer| {
-        let client = &client;
-        let pb = mp.add(ProgressBar::new(layer.size));
-        pb.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] {bar:20.cyan/blue} {pos:>8}/{len:8}B {msg}",
-            )
-            .unwrap(),
+            let layer_hashes: Vec<(usize, Result<String, DownloadError>)> = request
+                .parts
+                .iter()
+                .enumerate()
+                .map(|(part_index, part)| {
+                    let result = match &result {
+                        Ok(part_hashes) => Ok(part_hashes[part_index].clone()),
+                        Err(e) => Err(DownloadError::Shared(e.clone())),
+                    };
+                    on_layer_done(layers[part.layer_index], &result);
+                    (part.layer_index, result)
+                })
+                .collect();
+            layer_hashes
+        })
+        .buffer_unordered(jobs)
+        .collect::<Vec<_>>();
+    let mut layer_hashes: Vec<Option<Result<String, DownloadError>>> =
+        sorted_layers.iter().map(|_| None).collect();
+    for (layer_index, result) in fetch::block_on(request_hashes).into_iter().flatten() {
+        layer_hashes[layer_index] = Some(result);
+    }
+
+    Ok(sorted_layers
+        .into_iter()
+        .zip(layer_hashes)
+        .map(|(layer, result)| {
+            // Empty layers are not part of any request
+            let result = result.unwrap_or_else(|| {
+                let result = Ok(hasher::sha256_hash(&[]));
+                on_layer_done(&layer, &result);
+                result
+            });
+            (layer, result)
+        })
+        .collect())
+}
+
+/// Where the layers of a safetensors file are downloaded from, and how they are stored
+pub struct LayerDownload<'a> {
+    /// The safetensors file on Hugging Face, which every layer can be downloaded from
+    pub file_url: &'a str,
+    /// A registry that may have some of the layers, which is preferred over Hugging Face
+    pub registry_url: Option<&'a str>,
+    pub storage_dir: &'a str,
+    pub codec: store::Codec,
+    pub fetcher: &'a Fetcher,
+    pub registry_fetcher: &'a Fetcher,
+    /// How many requests are made at once
+    pub jobs: usize,
+}
+
+impl LayerDownload<'_> {
+    fn get_registry_blob_url(&self, layer_hash: &str) -> Option<String> {
+        self.registry_url
+            .map(|registry_url| format!("{}/v1/blobs/{}", registry_url, layer_hash))
+    }
+}
+
+/// A layer that is being downloaded into the store
+struct LayerTarget<'a> {
+    layer: &'a Layer,
+    /// The hash the layer should have, if the registry knows it
+    expected_hash: Option<&'a str>,
+    /// What the partial files of the layer are named after: its hash if it is known, otherwise its position in the
+    /// file, so that the download can still be resumed
+    partial_key: String,
+}
+
+impl<'a> LayerTarget<'a> {
+    fn new(file_url: &str, layer: &'a Layer, expected_hash: Option<&'a String>) -> LayerTarget<'a> {
+        let partial_key = match expected_hash {
+            Some(layer_hash) => layer_hash.to_string(),
+            // The URL includes the commit sha, so the same range always refers to the same bytes
+            None => hasher::sha256_hash(
+                format!("{}:{}:{}", file_url, layer.offset_start, layer.offset_end).as_bytes(),
+            ),
+        };
+
+        LayerTarget {
+            layer,
+            expected_hash: expected_hash.map(String::as_str),
+            partial_key,
+        }
+    }
+}
+
+/// How far along a layer is during an attempt at downloading it
+#[derive(Default)]
+struct LayerProgress {
+    /// How many of the requests that cover the layer have not finished yet
+    pending_requests: Cell<usize>,
+    /// Where each piece of the layer starts, if the layer was split
+    piece_offsets: RefCell<Vec<u64>>,
+    /// The hash of the layer, if a single request downloaded the rest of it and hashed it along the way
+    hash: RefCell<Option<String>>,
+    /// The first error of any of the requests that cover the layer
+    error: RefCell<Option<Arc<DownloadError>>>,
+}
+
+/// Downloads each layer straight into the store, resuming any layers that were partially downloaded before.
+/// Small layers next to each other are downloaded with a single request, and large layers are split into pieces that
+/// are downloaded at the same time. Layers without a known hash are hashed once downloaded.
+/// Calls `on_layer_done` as each layer finishes, and returns the hash of every layer.
+/// Each request runs as a task of its own on the download runtime, with its writes and hashing on a blocking thread,
+/// so the number of requests made at once does not depend on how many cores there are.
+pub fn download_layers_to_store(
+    layers: Vec<Layer>,
+    layers_to_hashes_map: &HashMap<String, String>,
+    layer_download: &LayerDownload,
+    mp: MultiProgress,
+    on_layer_done: impl Fn(&Layer, &Result<String, DownloadError>),
+) -> Vec<(Layer, Result<String, DownloadError>)> {
+    // Layers with the same contents are only downloaded once
+    let mut targets: Vec<LayerTarget> = Vec::new();
+    let mut target_indexes: Vec<usize> = Vec::new();
+    let mut target_indexes_by_key: HashMap<String, usize> = HashMap::new();
+    for layer in &layers {
+        let target = LayerTarget::new(
+            layer_download.file_url,
+            layer,
+            layers_to_hashes_map.get(&layer.name),
         );
-        pb.enable_steady_tick(Duration::from_millis(200));
-        pb.set_message(layer.name.to_string());
+        let target_index = *target_indexes_by_key
+            .entry(target.partial_key.to_string())
+            .or_insert_with(|| {
+                targets.push(target);
+                targets.len() - 1
+            });
+        target_indexes.push(target_index);
+    }
 
-        // Download the tensor
-        // println!("{}: Downloading {}...", model_id, tensor_name);
-        let tensor: Vec<u8> = download_tensor(
-            &file_url,
-            layer.offset_start,
-            layer.offset_end,
-            client,
-            Some(pb.clone()),
-        )
-        .unwrap(); // Handle unwrap better
+    let results: RefCell<Vec<Option<SharedLayerResult>>> =
+        RefCell::new(targets.iter().map(|_| None).collect());
+    let retry_target_indexes: RefCell<Vec<usize>> = RefCell::new(Vec::new());
+
+    fetch::block_on(async {
+        // If the registry served the wrong bytes it would most likely serve them again, so they are downloaded from
+        // Hugging Face instead. Hugging Face has the bytes the model is made of, so there is nothing to retry after.
+        let mut attempt_target_indexes: Vec<usize> = (0..targets.len()).collect();
+        for use_registry in [true, false] {
+            let on_target_done = |target_index: usize, result: Result<String, DownloadError>| {
+                let target = &targets[target_index];
+                match &result {
+                    Err(DownloadError::HashMismatch {
+                        expected, actual, ..
+                    }) if use_registry => {
+                        let _ = mp.println(format!(
+                            "The registry served bytes of {} that hash to {} instead of {}, downloading it from Hugging Face instead",
+                            target.layer.name, actual, expected
+                        ));
+                        retry_target_indexes.borrow_mut().push(target_index);
+                        return;
+                    }
+                    Ok(actual_hash) if target.expected_hash.is_some_and(|h| h != actual_hash) => {
+                        let _ = mp.println(format!(
+                            "The registry has the wrong hash for {}, it is stored under the hash of the bytes from Hugging Face instead: {}",
+                            target.layer.name, actual_hash
+                        ));
+                    }
+                    _ => {}
+                }
+
+                // Every layer with the same contents is done at the same time
+                let result = result.map_err(Arc::new);
+                for (layer, _) in layers
+                    .iter()
+                    .zip(&target_indexes)
+                    .filter(|(_, i)| **i == target_index)
+                {
+                    on_layer_done(layer, &to_layer_result(&result));
+                }
+                results.borrow_mut()[target_index] = Some(result);
+            };
+
+            download_layers_once(
+                layer_download,
+                &targets,
+                &attempt_target_indexes,
+                use_registry,
+                &mp,
+                &on_target_done,
+            )
+            .await;
+
+            attempt_target_indexes = retry_target_indexes.take();
+            if attempt_target_indexes.is_empty() {
+                break;
+            }
+        }
+    });
+
+    let results = results.into_inner();
+    target_indexes
+        .iter()
+        .map(|target_index| match &results[*target_index] {
+            Some(result) => to_layer_result(result),
+            None => Err(DownloadError::Io(io::Error::other(
+                "the layer was never downloaded",
+            ))),
+        })
+        .zip(layers)
+        .map(|(result, layer)| (layer, result))
+        .collect()
+}
+
+/// Layers with the same contents share the result of a single download, so they share its error too
+type SharedLayerResult = Result<String, Arc<DownloadError>>;
+
+/// Gives a layer its own copy of a shared result
+fn to_layer_result(result: &SharedLayerResult) -> Result<String, DownloadError> {
+    match result {
+        Ok(layer_hash) => Ok(layer_hash.to_string()),
+        Err(e) => Err(DownloadError::Shared(e.clone())),
+    }
+}
+
+/// Downloads the given layers once, from the registry if it has them and from Hugging Face otherwise.
+/// Calls `on_target_done` as each layer is verified and moved into the store, or fails.
+async fn download_layers_once(
+    layer_download: &LayerDownload<'_>,
+    targets: &[LayerTarget<'_>],
+    target_indexes: &[usize],
+    use_registry: bool,
+    mp: &MultiProgress,
+    on_target_done: &dyn Fn(usize, Result<String, DownloadError>),
+) {
+    let storage_dir = layer_download.storage_dir;
+
+    // Each layer is a blob of its own in the registry, so they are downloaded from it one by one
+    let registry_results = stream::iter(target_indexes)
+        .map(|target_index| async move {
+            let target = &targets[*target_index];
+            let result = match target.expected_hash {
+                Some(layer_hash) if use_registry => {
+                    download_layer_from_registry(layer_download, target, layer_hash, mp).await
+                }
+                _ => Ok(None),
+            };
+            (*target_index, result)
+        })
+        .buffer_unordered(layer_download.jobs)
+        .collect::<Vec<_>>()
+        .await;
+    let mut hf_targets: Vec<(usize, &LayerTarget)> = Vec::new();
+    let mut remaining_layers: Vec<RemainingLayer> = Vec::new();
+    for (target_index, result) in registry_results {
+        let target = &targets[target_index];
+        let result = match result {
+            Ok(Some(layer_hash)) => {
+                finish_layer(layer_download, target, Some(layer_hash), &[], true).await
+            }
+            // Plan whatever is left around what was downloaded before
+            Ok(None) => match get_downloaded_bytes(storage_dir, target) {
+                Ok(downloaded_bytes) => {
+                    hf_targets.push((target_index, target));
+                    remaining_layers.push(RemainingLayer {
+                        file_offset: target.layer.file_offset,
+                        size: target.layer.size,
+                        downloaded_bytes,
+                    });
+                    continue;
+                }
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e),
+        };
+        on_target_done(target_index, result);
+    }
+    let range_requests = RangePlanner::default().plan(&remaining_layers);
+
+    let layer_progress: Vec<LayerProgress> = hf_targets
+        .iter()
+        .map(|_| LayerProgress::default())
+        .collect();
+    for part in range_requests.iter().flat_map(|request| &request.parts) {
+        let progress = &layer_progress[part.layer_index];
+        progress
+            .pending_requests
+            .set(progress.pending_requests.get() + 1);
+        if part.piece.is_some() {
+            progress.piece_offsets.borrow_mut().push(part.layer_offset);
+        }
+    }
+
+    // Layers that were downloaded in full before only have to be verified
+    for ((target_index, target), progress) in hf_targets.iter().zip(&layer_progress) {
+        if progress.pending_requests.get() == 0 {
+            let result = finish_layer(layer_download, target, None, &[], false).await;
+            on_target_done(*target_index, result);
+        }
+    }
+
+    let layers: Vec<&Layer> = hf_targets.iter().map(|(_, target)| target.layer).collect();
+    let (hf_targets, layers, layer_progress) = (&hf_targets, &layers, &layer_progress);
+    stream::iter(range_requests)
+        .map(|request| async move {
+            let pb = add_request_progress_bar(mp, &request, layers);
+            let result = download_range_request(layer_download, &request, hf_targets, &pb)
+                .await
+                .map_err(Arc::new);
+            pb.finish_and_clear();
+
+            for (part_index, part) in request.parts.iter().enumerate() {
+                let progress = &layer_progress[part.layer_index];
+                match &result {
+                    Ok(layer_hashes) => {
+                        if let Some(layer_hash) = &layer_hashes[part_index] {
+                            progress.hash.replace(Some(layer_hash.to_string()));
+                        }
+                    }
+                    Err(e) => {
+                        progress.error.borrow_mut().get_or_insert_with(|| e.clone());
+                    }
+                }
+
+                // A layer is done once every request that covers it is
+                progress
+                    .pending_requests
+                    .set(progress.pending_requests.get() - 1);
+                if progress.pending_requests.get() > 0 {
+                    continue;
+                }
+
+                let (target_index, target) = hf_targets[part.layer_index];
+                let error = progress.error.take();
+                let result = match error {
+                    Some(e) => Err(DownloadError::Shared(e)),
+                    None => {
+                        let mut piece_offsets = progress.piece_offsets.take();
+                        piece_offsets.sort();
+                        finish_layer(
+                            layer_download,
+                            target,
+                            progress.hash.take(),
+                            &piece_offsets,
+                            false,
+                        )
+                        .await
+                    }
+                };
+                on_target_done(target_index, result);
+            }
+        })
+        .buffer_unordered(layer_download.jobs)
+        .collect::<Vec<()>>()
+        .await;
+}
+
+/// Returns how much of a layer is in its partial file. A partial file that is too long is not what it seems, so it is
+/// deleted and the layer is started over.
+fn get_downloaded_bytes(storage_dir: &str, target: &LayerTarget) -> io::Result<u64> {
+    let partial_blob_path = store::get_partial_blob_path(storage_dir, &target.partial_key)?;
+    let downloaded_bytes = match fs::metadata(&partial_blob_path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    if downloaded_bytes > target.layer.size {
+        fs::remove_file(partial_blob_path)?;
+        return Ok(0);
+    }
+
+    Ok(downloaded_bytes)
+}
+
+/// Downloads whatever the registry has of a layer into its partial file, picking up from wherever a previous run
+/// stopped. Returns the hash of the layer if the registry had all of it, in which case nothing is left to download.
+async fn download_layer_from_registry(
+    layer_download: &LayerDownload<'_>,
+    target: &LayerTarget<'_>,
+    layer_hash: &str,
+    mp: &MultiProgress,
+) -> Result<Option<String>, DownloadError> {
+    let layer = target.layer;
+    let Some(blob_url) = layer_download.get_registry_blob_url(layer_hash) else {
+        return Ok(None);
+    };
+    if !layer_download.registry_fetcher.exists(&blob_url).await {
+        return Ok(None);
+    }
+
+    let partial_blob_path = store::get_partial_blob_path(layer_download.storage_dir, layer_hash)?;
+    fs::create_dir_all(partial_blob_path.parent().unwrap())?;
+    let partial_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&partial_blob_path)?;
+    let mut downloaded_bytes = partial_file.metadata()?.len();
+    if downloaded_bytes > layer.size {
+        partial_file.set_len(0)?;
+        downloaded_bytes = 0;
+    }
+
+    // Only the bytes of a previous run are read back from disk, the rest are hashed on their way in
+    let mut partial_writer = hasher::HashingWriter::new(partial_file);
+    if downloaded_bytes > 0 {
+        partial_writer.hash_existing(&mut File::open(&partial_blob_path)?)?;
+    }
+
+    if downloaded_bytes < layer.size {
+        let pb = add_progress_bar(mp, layer.size, layer.name.to_string());
+        pb.set_position(downloaded_bytes);
+
+        // Blobs are the layer on its own, so they start at the beginning rather than at the layer's offset
+        let range = ByteRange {
+            start: downloaded_bytes,
+            size: layer.size - downloaded_bytes,
+        };
+        let result = layer_download
+            .registry_fetcher
+            .spawn_download_ranges_of_file_to_writer(&blob_url, &[range], Some(&pb), partial_writer)
+            .await;
         pb.finish_and_clear();
-        (layer.clone(), tensor)
+
+        // Whatever did arrive from the registry does not have to be downloaded again
+        partial_writer = match result {
+            Ok(partial_writer) => partial_writer,
+            Err(e) => {
+                let _ = mp.println(format!(
+                    "Unable to download {} from the registry, falling back to Hugging Face: {}",
+                    layer.name, e
+                ));
+                return Ok(None);
+            }
+        };
+    }
+
+    Ok(Some(partial_writer.finalize()))
+}
+
+/// Where the body of a range request goes for one of the layers it covers
+enum PartWriter {
+    /// The rest of a layer, which is hashed on the way into its partial file
+    Layer(hasher::HashingWriter<File>),
+    /// A piece of a layer, which is only hashed once the pieces are joined
+    Piece(File),
+}
+
+impl Write for PartWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            PartWriter::Layer(writer) => writer.write(buf),
+            PartWriter::Piece(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            PartWriter::Layer(writer) => writer.flush(),
+            PartWriter::Piece(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Downloads a range request into the partial files of the layers it covers, picking up from wherever a previous run
+/// stopped. Returns the hash of each layer that the request downloaded the rest of.
+async fn download_range_request(
+    layer_download: &LayerDownload<'_>,
+    request: &RangeRequest,
+    targets: &[(usize, &LayerTarget<'_>)],
+    pb: &ProgressBar,
+) -> Result<Vec<Option<String>>, DownloadError> {
+    let storage_dir = layer_download.storage_dir;
+
+    let mut part_writers: Vec<PartWriter> = Vec::new();
+    let mut remaining_sizes: Vec<u64> = Vec::new();
+    let mut part_paths: Vec<(PathBuf, u64)> = Vec::new();
+    // The body arrives in order, so only the parts before the first incomplete one can be skipped
+    let mut skipped_bytes: u64 = 0;
+    let mut is_resumable = true;
+    for part in &request.parts {
+        let target = targets[part.layer_index].1;
+        let (part_path, part_start) = match part.piece {
+            Some(_) => (
+                store::get_partial_piece_path(storage_dir, &target.partial_key, part.layer_offset)?,
+                0,
+            ),
+            None => (
+                store::get_partial_blob_path(storage_dir, &target.partial_key)?,
+                part.layer_offset,
+            ),
+        };
+        fs::create_dir_all(part_path.parent().unwrap())?;
+        let part_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part_path)?;
+
+        let mut downloaded_bytes = part_file.metadata()?.len().saturating_sub(part_start);
+        if !is_resumable || downloaded_bytes > part.size {
+            part_file.set_len(part_start)?;
+            downloaded_bytes = 0;
+        }
+        if is_resumable {
+            skipped_bytes += downloaded_bytes;
+        }
+        is_resumable &= downloaded_bytes == part.size;
+
+        part_writers.push(match part.piece {
+            Some(_) => PartWriter::Piece(part_file),
+            None => {
+                // Only the bytes of a previous run are read back from disk, the rest are hashed on their way in
+                let mut partial_writer = hasher::HashingWriter::new(part_file);
+                partial_writer.hash_existing(&mut File::open(&part_path)?)?;
+                PartWriter::Layer(partial_writer)
+            }
+        });
+        remaining_sizes.push(part.size - downloaded_bytes);
+        part_paths.push((part_path, part_start + part.size));
+    }
+
+    pb.set_position(skipped_bytes);
+    let mut parts_writer = PartsWriter::new(part_writers, remaining_sizes);
+    if skipped_bytes < request.size() {
+        parts_writer = layer_download
+            .fetcher
+            .spawn_download_ranges_of_file_to_writer(
+                layer_download.file_url,
+                &byteranges::skip_bytes(&request.ranges, skipped_bytes),
+                Some(pb),
+                parts_writer,
+            )
+            .await?;
+    }
+
+    // Keep the partial files around so that a truncated response can be resumed
+    for (part_path, expected_size) in &part_paths {
+        let part_size = fs::metadata(part_path)?.len();
+        if part_size != *expected_size {
+            return Err(DownloadError::UnexpectedLength {
+                expected: *expected_size,
+                actual: part_size,
+            });
+        }
+    }
+
+    Ok(parts_writer
+        .into_writers()
+        .into_iter()
+        .map(|part_writer| match part_writer {
+            PartWriter::Layer(partial_writer) => Some(partial_writer.finalize()),
+            PartWriter::Piece(_) => None,
+        })
+        .collect())
+}
+
+/// Joins the pieces of a layer if it was split, then verifies the layer and moves it into the store.
+/// `layer_hash` is the hash of the whole partial file if it was computed as the layer downloaded.
+/// Returns the hash of the layer.
+async fn finish_layer(
+    layer_download: &LayerDownload<'_>,
+    target: &LayerTarget<'_>,
+    layer_hash: Option<String>,
+    piece_offsets: &[u64],
+    from_registry: bool,
+) -> Result<String, DownloadError> {
+    let storage_dir = layer_download.storage_dir.to_string();
+    let partial_key = target.partial_key.to_string();
+    let piece_offsets = piece_offsets.to_vec();
+
+    // Joining the pieces and hashing the layer reads all of it back from disk, so it is done off the runtime
+    let actual_hash = match layer_hash {
+        Some(layer_hash) => layer_hash,
+        None => tokio::task::spawn_blocking(move || {
+            join_partial_pieces(&storage_dir, &partial_key, &piece_offsets)
+        })
+        .await
+        .map_err(io::Error::other)??,
+    };
+    let storage_dir = layer_download.storage_dir;
+    let partial_blob_path = store::get_partial_blob_path(storage_dir, &target.partial_key)?;
+    // Pieces from a previous run that were not needed after all
+    store::remove_partial_pieces(storage_dir, &target.partial_key)?;
+
+    let partial_blob_size = fs::metadata(&partial_blob_path)?.len();
+    if partial_blob_size != target.layer.size {
+        fs::remove_file(&partial_blob_path)?;
+        return Err(DownloadError::UnexpectedLength {
+            expected: target.layer.size,
+            actual: partial_blob_size,
+        });
+    }
+
+    match target.expected_hash {
+        // Only promote bytes from the registry into the store once their contents match the expected hash
+        Some(layer_hash) if layer_hash != actual_hash && from_registry => {
+            // The bad bytes could be anywhere in the file, so start over
+            fs::remove_file(&partial_blob_path)?;
+            return Err(DownloadError::HashMismatch {
+                expected: layer_hash.to_string(),
+                actual: actual_hash,
+            });
+        }
+        Some(layer_hash) if layer_hash == actual_hash => {}
+        // Hugging Face has the bytes the model is made of, so a hash that does not match them is stale and the layer
+        // is stored like a layer without a known hash. Another model may already have stored the same layer.
+        _ if store::find_blob(storage_dir, &actual_hash).is_some() => {
+            fs::remove_file(&partial_blob_path)?;
+            return Ok(actual_hash);
+        }
+        _ => {
+            // Blobs are stored in the folder of their hash, which is not the folder of the position's hash. Layers
+            // with the same contents promote their own partial files, so they never share one.
+            fs::create_dir_all(store::get_blob_dir(storage_dir, &actual_hash)?)?;
+        }
+    }
+
+    promote_partial_blob(layer_download, &actual_hash, &partial_blob_path).await?;
+
+    Ok(actual_hash)
+}
+
+/// Appends the pieces of a layer to its partial file in order, and returns the hash of the whole partial file.
+/// Each piece goes where it starts in the layer, so whatever the partial file already holds of the pieces is skipped
+/// and a join that was interrupted carries on from where it stopped. The pieces are only deleted once all of them are
+/// joined.
+fn join_partial_pieces(
+    storage_dir: &str,
+    partial_key: &str,
+    piece_offsets: &[u64],
+) -> io::Result<String> {
+    let partial_blob_path = store::get_partial_blob_path(storage_dir, partial_key)?;
+    let partial_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&partial_blob_path)?;
+    let mut partial_writer = hasher::HashingWriter::new(partial_file);
+    let mut joined_size = partial_writer.hash_existing(&mut File::open(&partial_blob_path)?)?;
+
+    for piece_offset in piece_offsets {
+        let piece_path = store::get_partial_piece_path(storage_dir, partial_key, *piece_offset)?;
+        let mut piece_file = File::open(piece_path)?;
+        let piece_size = piece_file.metadata()?.len();
+        if piece_offset + piece_size <= joined_size {
+            continue;
+        }
+        if *piece_offset > joined_size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "the partial file ends at {} but the next piece starts at {}",
+                    joined_size, piece_offset
+                ),
+            ));
+        }
+
+        piece_file.seek(SeekFrom::Start(joined_size - piece_offset))?;
+        joined_size += io::copy(&mut piece_file, &mut partial_writer)?;
+    }
+    partial_writer.flush()?;
+
+    Ok(partial_writer.finalize())
+}
+
+/// Moves a verified partial file into the store under the hash of its contents. Compressing it takes a while, so it
+/// is done off the runtime.
+async fn promote_partial_blob(
+    layer_download: &LayerDownload<'_>,
+    layer_hash: &str,
+    partial_blob_path: &Path,
+) -> Result<(), DownloadError> {
+    let (storage_dir, layer_hash, partial_blob_path, codec) = (
+        layer_download.storage_dir.to_string(),
+        layer_hash.to_string(),
+        partial_blob_path.to_path_buf(),
+        layer_download.codec,
+    );
+    tokio::task::spawn_blocking(move || {
+        store::promote_blob_file(&storage_dir, &layer_hash, &partial_blob_path, codec)
     })
+    .await
+    .map_err(io::Error::other)??;
+
+    Ok(())
 }
 
-pub fn download_safetensors_header(file_url: &str) -> (serde_json::Value, u64) {
-    let client = Client::new();
+/// Returns the parsed JSON header along with its raw bytes, which are empty if no header could be read.
+pub fn download_safetensors_header(
+    file_url: &str,
+) -> Result<(serde_json::Value, Vec<u8>), DownloadError> {
+    let fetcher = Fetcher::new(RetryPolicy::default());
 
     // Step 1: download the first 8 bytes of the file, that contains the header length as u64
 
-    let header_length_bytes: Vec<u8> =
-        download_part_of_file(file_url, 0, 8, &client, None).unwrap();
-    let ret = String::from_utf8_lossy(&header_length_bytes).to_string();
-    println!("{}", ret);
+    let header_length_bytes: Vec<u8> = fetcher.download_part_of_file(file_url, 0, 8, None)?;
 
-    // println!("{:?}", header_length_bytes);
     let json_header_length = get_u64_from_u8_vec(header_length_bytes);
 
     match json_header_length {
         Some(jhl) => {
             println!("JSON header is {jhl} bytes long");
 
-            let header_bytes: Vec<u8> =
-                download_part_of_file(file_url, 8, jhl, &client, None).unwrap();
-            let json_string = String::from_utf8_lossy(&header_bytes);
-            // println!("{:}", json_string);
-            // println!("{}", json_header_length);
-            // println!("{}", json_string.len());
-            // println!("{}", header_bytes.len());
-            let metadata_json: serde_json::Value = serde_json::from_str(&json_string).unwrap();
+            let header_bytes: Vec<u8> = fetcher.download_part_of_file(file_url, 8, jhl, None)?;
+            let metadata_json: serde_json::Value = serde_json::from_slice(&header_bytes)?;
 
-            (metadata_json, jhl)
+            Ok((metadata_json, header_bytes))
         }
-        None => (json!({}), 0),
+        None => Ok((json!({}), Vec::new())),
     }
 }
 
 fn get_u64_from_u8_vec(bytes: Vec<u8>) -> Option<u64> {
     let b = bytes.try_into();
     match b {
-        Ok(bytes) => {
-            println!("{:?}", bytes);
-            Some(u64::from_le_bytes(bytes))
-        }
-        Err(_e) => {
-            // println!("{:?}", e);
-            None
-        }
+        Ok(bytes) => Some(u64::from_le_bytes(bytes)),
+        Err(_e) => None,
     }
 }
 
-fn download_tensor(
-    file_url: &str,
-    offset_start: u64,
-    offset_end: u64,
-    client: &Client,
-    pb: Option<ProgressBar>,
-) -> Result<Vec<u8>, Error> {
-    let offset_diff = offset_end - offset_start;
-    let byte_count = offset_diff;
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
 
-    download_part_of_file(file_url, offset_start, byte_count, client, pb)
-}
+    #[test]
+    fn test_get_layers_from_header_offsets_start_after_header() {
+        let header = json!({
+            "__metadata__": {"format": "pt"},
+            "a": {"dtype": "F16", "shape": [2], "data_offsets": [0, 4]},
+            "b": {"dtype": "F16", "shape": [4], "data_offsets": [4, 12]},
+        });
 
-fn download_part_of_file(
-    file_url: &str,
-    byte_index: u64,
-    number_of_bytes: u64,
-    client: &Client,
-    pb: Option<ProgressBar>,
-) -> Result<Vec<u8>, Error> {
-    let chunk_size = 1024; // 1KB
+        let layers = get_layers_from_header(&header, 64, None).unwrap();
 
-    // Set up headers
-    let mut headers = HeaderMap::new();
+        assert_eq!(layers.len(), 2);
+        assert_eq!((layers[0].name.as_str(), layers[0].file_offset), ("b", 76));
+        assert_eq!((layers[1].name.as_str(), layers[1].file_offset), ("a", 72));
 
-    if let Ok(bearer_token) = env::var("HF_API_KEY") {
-        let bearer_value = format!("Bearer {}", bearer_token);
-        headers.insert(AUTHORIZATION, HeaderValue::from_str(&bearer_value).unwrap());
+        // A tensor that ends before it starts is an error rather than an underflow
+        let header = json!({
+            "a": {"dtype": "F16", "shape": [2], "data_offsets": [0, 4]},
+            "b": {"dtype": "F16", "shape": [4], "data_offsets": [12, 4]},
+        });
+        assert!(matches!(
+            get_layers_from_header(&header, 64, None),
+            Err(DownloadError::InvalidOffsets(tensor_name)) if tensor_name == "b"
+        ));
     }
 
-    // Range is exclusive. Example: 0-499 is byte 0 to byte 499, so 500 bytes in total
-    let range_header_value = format!("bytes={}-{}", byte_index, byte_index + number_of_bytes - 1);
-    headers.insert("Range", HeaderValue::from_str(&range_header_value).unwrap());
-    let mut response = client.get(file_url).headers(headers).send()?;
+    #[test]
+    fn test_parts_writer_splits_bytes_between_parts_in_order() {
+        let bytes: Vec<u8> = (0..100).collect();
 
-    // let status_code = response.status();
-    // println!("Status Code: {}", status_code);
+        // The first part was already downloaded, so only the rest of the request arrives
+        let mut parts_writer =
+            PartsWriter::new(vec![Vec::new(), Vec::new(), Vec::new()], vec![0, 30, 50]);
+        for chunk in bytes[20..].chunks(7) {
+            parts_writer.write_all(chunk).unwrap();
+        }
+        assert!(parts_writer.write_all(&[0]).is_err());
 
-    // Setup the progress bar if one is available
-    let total_size = response.content_length().unwrap_or(0);
-    if let Some(pb) = &pb {
-        pb.set_length(total_size);
+        let written_parts = parts_writer.into_writers();
+        assert!(written_parts[0].is_empty());
+        assert_eq!(written_parts[1], bytes[20..50]);
+        assert_eq!(written_parts[2], bytes[50..]);
     }
 
-    let mut buffer: Vec<u8> = Vec::new();
-    let mut chunk = vec![0; chunk_size];
-    let mut position: usize = 0;
+    #[test]
+    fn test_join_partial_pieces_resumes_an_interrupted_join() {
+        let storage_dir =
+            std::env::temp_dir().join(format!("cake-test-join-{:016x}", rand::random::<u64>()));
+        let storage_dir = storage_dir.to_str().unwrap();
+        let partial_key = hasher::sha256_hash(b"layer");
+        let layer: Vec<u8> = (0..250).map(|i| i as u8).collect();
 
-    while let Ok(chunk_size) = response.read(&mut chunk) {
-        if chunk_size == 0 {
-            break; // End of stream
+        // The first 10 bytes were downloaded before the layer was split, the rest arrived in pieces of 100
+        let piece_offsets = [10, 110, 210];
+        for piece_offset in piece_offsets {
+            let piece_path =
+                store::get_partial_piece_path(storage_dir, &partial_key, piece_offset).unwrap();
+            fs::create_dir_all(piece_path.parent().unwrap()).unwrap();
+            let piece_end = (piece_offset as usize + 100).min(layer.len());
+            fs::write(piece_path, &layer[piece_offset as usize..piece_end]).unwrap();
         }
 
-        position += chunk_size;
-        buffer.extend_from_slice(&chunk[..chunk_size]);
-        if let Some(pb) = &pb {
-            pb.set_position(position as u64);
-        }
+        // The join stopped part of the way through the second piece
+        let partial_blob_path = store::get_partial_blob_path(storage_dir, &partial_key).unwrap();
+        fs::write(&partial_blob_path, &layer[..150]).unwrap();
+        let layer_hash = join_partial_pieces(storage_dir, &partial_key, &piece_offsets).unwrap();
+        assert_eq!(fs::read(&partial_blob_path).unwrap(), layer);
+        assert_eq!(layer_hash, hasher::sha256_hash(&layer));
+
+        // Joining again has nothing left to append
+        let layer_hash = join_partial_pieces(storage_dir, &partial_key, &piece_offsets).unwrap();
+        assert_eq!(fs::read(&partial_blob_path).unwrap(), layer);
+        assert_eq!(layer_hash, hasher::sha256_hash(&layer));
+
+        // A partial file that ends before the first piece cannot be joined
+        fs::write(&partial_blob_path, &layer[..5]).unwrap();
+        assert!(join_partial_pieces(storage_dir, &partial_key, &piece_offsets).is_err());
+
+        fs::remove_dir_all(storage_dir).unwrap();
     }
 
-    Ok(buffer)
+    #[test]
+    fn test_download_layers_resumes_from_partial_file() {
+        use axum::http::{header, HeaderMap as AxumHeaderMap, StatusCode as AxumStatusCode};
+        use std::sync::Mutex;
+
+        let storage_dir =
+            std::env::temp_dir().join(format!("cake-test-resume-{:016x}", rand::random::<u64>()));
+        let storage_dir = storage_dir.to_str().unwrap();
+        let file: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+
+        // Answers a single range and records it, like Hugging Face does
+        let requests: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+        let handler = {
+            let (file, requests) = (file.clone(), requests.clone());
+            move |headers: AxumHeaderMap| async move {
+                let range = headers[header::RANGE].to_str().unwrap().to_string();
+                requests.lock().unwrap().push(range.to_string());
+                let (start, end) = range.trim_start_matches("bytes=").split_once('-').unwrap();
+                let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                (
+                    AxumStatusCode::PARTIAL_CONTENT,
+                    [(
+                        header::CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", start, end, file.len()),
+                    )],
+                    file[start..=end].to_vec(),
+                )
+            }
+        };
+        let app = axum::Router::new().route("/model.safetensors", axum::routing::get(handler));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let file_url = format!(
+            "http://{}/model.safetensors",
+            listener.local_addr().unwrap()
+        );
+        runtime.spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        // The tensor data starts after the 8 bytes of the header length and a header of 50 bytes
+        let header = json!({"a": {"dtype": "U8", "shape": [200], "data_offsets": [0, 200]}});
+        let layers = get_layers_from_header(&header, 50, None).unwrap();
+        let layer_bytes = &file[58..258];
+        let layer_hash = hasher::sha256_hash(layer_bytes);
+
+        // A previous run stopped after the first 120 bytes of the layer
+        let partial_blob_path = store::get_partial_blob_path(storage_dir, &layer_hash).unwrap();
+        fs::create_dir_all(partial_blob_path.parent().unwrap()).unwrap();
+        fs::write(&partial_blob_path, &layer_bytes[..120]).unwrap();
+
+        let fetcher = Fetcher::new(RetryPolicy::default());
+        let results = download_layers_to_store(
+            layers,
+            &HashMap::from([("a".to_string(), layer_hash.to_string())]),
+            &LayerDownload {
+                file_url: &file_url,
+                registry_url: None,
+                storage_dir,
+                codec: store::Codec::Lz4,
+                fetcher: &fetcher,
+                registry_fetcher: &fetcher,
+                jobs: 4,
+            },
+            MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden()),
+            |_, _| {},
+        );
+
+        // Only the bytes after the partial file are asked for
+        assert_eq!(*requests.lock().unwrap(), vec!["bytes=178-257".to_string()]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1.as_ref().unwrap(), &layer_hash);
+        let mut blob_bytes = Vec::new();
+        store::open_blob(storage_dir, &layer_hash)
+            .unwrap()
+            .read_to_end(&mut blob_bytes)
+            .unwrap();
+        assert_eq!(blob_bytes, layer_bytes);
+        assert!(!partial_blob_path.exists());
+
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_filter_defaults_skip_other_weight_formats() {
+        let exclude: Vec<String> = DEFAULT_EXCLUDE_PATTERNS.map(String::from).to_vec();
+        let file_filter = FileFilter::new(&[], &exclude).unwrap();
+
+        assert!(file_filter.is_match("config.json"));
+        assert!(file_filter.is_match("tokenizer/tokenizer.json"));
+        assert!(!file_filter.is_match("pytorch_model.bin"));
+        assert!(!file_filter.is_match("unet/diffusion_pytorch_model.bin"));
+        assert!(!file_filter.is_match(".gitattributes"));
+    }
+
+    #[test]
+    fn test_file_filter_with_include_patterns() {
+        let file_filter = FileFilter::new(&["*.json".to_string()], &[]).unwrap();
+
+        assert!(file_filter.is_match("generation_config.json"));
+        assert!(!file_filter.is_match("tokenizer.model"));
+    }
 }
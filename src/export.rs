@@ -1,75 +1,325 @@
 use indicatif::{ProgressBar, ProgressStyle};
-use serde_json::Value;
+use std::collections::HashSet;
 use std::fs::{self};
-use std::io::prelude::*;
-use std::path::PathBuf;
+use std::io::{self, prelude::*, BufWriter};
+use std::path::{Path, PathBuf};
 
-pub fn _combine_cached_files_to_safetensors_file(
+use thiserror::Error;
+
+use crate::manifest::{self, FileEntry, Manifest, TensorEntry};
+use crate::shards::{self, ShardIndex};
+use crate::{hasher, store};
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("{file_name} does not match the original file on huggingface (expected {expected}, got {actual})")]
+    HashMismatch {
+        file_name: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("{model_id}@{revision} has not been downloaded yet")]
+    NotDownloaded { model_id: String, revision: String },
+    #[error("unable to lock {storage_dir}: {source}")]
+    Lock {
+        storage_dir: String,
+        source: io::Error,
+    },
+    /// The blob was deleted by `cake gc` or quarantined by `cake fsck` after the manifest was written
+    #[error("{hash} is missing from the store, run `cake fsck --refetch` to download it again: {source}")]
+    MissingBlob { hash: String, source: io::Error },
+    #[error("unable to write the export: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Opens a blob of the model that is being exported, which may have gone missing since it was downloaded
+fn open_model_blob(storage_dir: &str, hash: &str) -> Result<Box<dyn Read + Send>, ExportError> {
+    store::open_blob(storage_dir, hash).map_err(|source| ExportError::MissingBlob {
+        hash: hash.to_string(),
+        source,
+    })
+}
+
+/// Exports every file of a model into a folder. Stops at the first safetensors file that does not match the original
+/// on Hugging Face, which is deleted rather than left for something to load.
+pub fn export_model_by_model_id(
     model_id: &str,
-    storage_directory: &str,
-    target_file_path: &str,
-) {
-    println!("Exporting {} to {}...", model_id, target_file_path);
+    revision: &str,
+    storage_dir: &str,
+    out_dir: &str,
+) -> Result<(), ExportError> {
+    let _store_lock =
+        store::lock_store(storage_dir, false).map_err(|source| ExportError::Lock {
+            storage_dir: storage_dir.to_string(),
+            source,
+        })?;
+    let commit_sha = manifest::resolve_revision(storage_dir, model_id, revision);
+    let manifests = manifest::load_manifests(storage_dir, model_id, &commit_sha);
+
+    if manifests.is_empty() {
+        return Err(ExportError::NotDownloaded {
+            model_id: model_id.to_string(),
+            revision: revision.to_string(),
+        });
+    }
+
+    let file_count = manifests.len();
+    for (file_index, model_manifest) in manifests.iter().enumerate() {
+        println!(
+            "File {} of {}: {}",
+            file_index + 1,
+            file_count,
+            model_manifest.file_name
+        );
+
+        let mut target_file_path = PathBuf::new();
+        target_file_path.push(out_dir);
+        target_file_path.push(&model_manifest.file_name);
+        fs::create_dir_all(target_file_path.parent().unwrap())?;
+
+        combine_cached_files_to_safetensors_file(model_manifest, storage_dir, &target_file_path)?;
+    }
+
+    // Place the config, tokenizer and index next to the shards so the model can be loaded from the folder
+    let files_manifest = manifest::load_files_manifest(storage_dir, model_id, &commit_sha);
+    for (file_name, file_entry) in &files_manifest.files {
+        println!("Exporting {}...", file_name);
+
+        let mut target_file_path = PathBuf::new();
+        target_file_path.push(out_dir);
+        target_file_path.push(file_name);
+        fs::create_dir_all(target_file_path.parent().unwrap())?;
 
-    let mut header_file_path = PathBuf::new();
-    header_file_path.push(storage_directory);
-    header_file_path.push(model_id);
-    header_file_path.push("header.json");
+        if shards::is_index_file(file_name) {
+            export_shard_index(
+                file_name,
+                file_entry,
+                &manifests,
+                storage_dir,
+                &target_file_path,
+            )?;
+            continue;
+        }
 
-    let mut header_file = fs::File::open(header_file_path).unwrap();
-    let mut header_bytes = Vec::new();
-    header_file.read_to_end(&mut header_bytes).unwrap();
+        let mut file_reader = open_model_blob(storage_dir, &file_entry.hash)?;
+        let mut output_file = fs::File::create(&target_file_path)?;
+        io::copy(&mut file_reader, &mut output_file)?;
+    }
+
+    Ok(())
+}
 
-    // println!("{}", header_bytes.len());
+/// Writes the index of a sharded model as it follows from the manifests of its shards, rather than as it was
+/// downloaded, so that loading the model finds every tensor where it is
+fn export_shard_index(
+    index_file_name: &str,
+    file_entry: &FileEntry,
+    manifests: &[Manifest],
+    storage_dir: &str,
+    target_file_path: &Path,
+) -> Result<(), ExportError> {
+    let mut original_bytes: Vec<u8> = Vec::new();
+    let mut file_reader = open_model_blob(storage_dir, &file_entry.hash)?;
+    file_reader.read_to_end(&mut original_bytes)?;
 
-    let mut output_file = fs::OpenOptions::new()
+    let original_index = match ShardIndex::from_slice(&original_bytes) {
+        Ok(original_index) => original_index,
+        Err(e) => {
+            println!(
+                "WARNING: {} cannot be regenerated so it is exported as is: {}",
+                index_file_name, e
+            );
+            fs::write(target_file_path, &original_bytes)?;
+            return Ok(());
+        }
+    };
+
+    let exported_shards: HashSet<&str> = manifests.iter().map(|m| m.file_name.as_str()).collect();
+    for shard in original_index.get_shard_file_names(index_file_name) {
+        if !exported_shards.contains(shard.as_str()) {
+            println!(
+                "WARNING: {} lists {} but it has not been downloaded, its tensors are kept as listed",
+                index_file_name, shard
+            );
+        }
+    }
+
+    let index_json = original_index
+        .regenerate(index_file_name, manifests)
+        .to_json();
+    fs::write(target_file_path, &index_json)?;
+
+    if index_json.as_bytes() == original_bytes {
+        println!("{} matches the original index", index_file_name);
+    } else {
+        println!(
+            "{} was regenerated from its shards as the original did not match them",
+            index_file_name
+        );
+    }
+
+    Ok(())
+}
+
+fn combine_cached_files_to_safetensors_file(
+    model_manifest: &Manifest,
+    storage_dir: &str,
+    target_file_path: &Path,
+) -> Result<(), ExportError> {
+    println!(
+        "Exporting {} to {}...",
+        model_manifest.model_id,
+        target_file_path.display()
+    );
+
+    // Write the header exactly as it was in the original file, so that the exported file is byte-identical
+    let header_bytes = model_manifest.header.as_bytes();
+
+    let output_file = fs::OpenOptions::new()
         .write(true)
         .truncate(true)
         .create(true)
-        .open(target_file_path)
-        .unwrap();
+        .open(target_file_path)?;
+    // Hashed as it is written, so the export can be verified without reading the file back
+    let mut output_writer = hasher::HashingWriter::new(BufWriter::new(output_file));
     // Write the header length
-    let header_length_bytes = &header_bytes.len().to_le_bytes();
-    println!("{:?}", header_length_bytes);
-    output_file.write_all(header_length_bytes).unwrap();
-    println!("{:?}", header_bytes.len());
+    let header_length_bytes = &(header_bytes.len() as u64).to_le_bytes();
+    output_writer.write_all(header_length_bytes)?;
     // Write the header bytes
-    output_file.write_all(&header_bytes).unwrap();
-    // Iterate through the tensors and write them to files
-
-    let header: Value = serde_json::from_slice(&header_bytes).unwrap();
-    let tensor_names: Vec<String> = header
-        .as_object()
-        .unwrap()
-        .keys()
-        .filter(|key| *key != "__metadata__")
-        .cloned()
-        .collect();
+    output_writer.write_all(header_bytes)?;
 
-    let tensor_count = tensor_names.len();
+    // Tensors have to be written in the same order as their offsets in the header
+    let mut tensors: Vec<(&String, &TensorEntry)> = model_manifest.tensors.iter().collect();
+    tensors.sort_by_key(|(_, tensor)| tensor.data_offsets[0]);
 
     let sty_main = ProgressStyle::with_template(
         "[{elapsed_precise}] {bar:40.green/yellow} {pos:>4}/{len:4} {msg}",
     )
     .unwrap();
 
-    let main_bar: ProgressBar = ProgressBar::new(tensor_count as u64);
+    let main_bar: ProgressBar = ProgressBar::new(tensors.len() as u64);
     main_bar.set_style(sty_main);
 
-    for tensor_name in tensor_names {
+    let data_start = 8 + header_bytes.len() as u64;
+    let mut position = data_start;
+    for (tensor_name, tensor) in tensors {
         main_bar.set_message(format!("Writing {}", tensor_name));
 
-        let mut tensor_file_path = PathBuf::new();
-        tensor_file_path.push(storage_directory);
-        tensor_file_path.push(model_id);
-        tensor_file_path.push(tensor_name);
-        let mut tensor_file = fs::File::open(tensor_file_path).unwrap();
-        let mut tensor_bytes: Vec<_> = Vec::new();
-        tensor_file.read_to_end(&mut tensor_bytes).unwrap();
-        output_file.write_all(&tensor_bytes).unwrap();
+        // The spec does not allow holes between tensors, but zero-fill any so the offsets stay valid
+        let tensor_start = data_start + tensor.data_offsets[0];
+        if position < tensor_start {
+            let padding = vec![0; (tensor_start - position) as usize];
+            output_writer.write_all(&padding)?;
+            position = tensor_start;
+        }
+
+        let mut tensor_reader = open_model_blob(storage_dir, &tensor.hash)?;
+        position += io::copy(&mut tensor_reader, &mut output_writer)?;
 
         main_bar.inc(1);
     }
 
+    output_writer.flush()?;
     main_bar.finish_with_message("Export complete");
+
+    let exported_hash = output_writer.finalize();
+    match &model_manifest.lfs_sha256 {
+        Some(lfs_sha256) if *lfs_sha256 == exported_hash => println!(
+            "{} matches the original file on huggingface ({})",
+            model_manifest.file_name, exported_hash
+        ),
+        Some(lfs_sha256) => {
+            fs::remove_file(target_file_path)?;
+            return Err(ExportError::HashMismatch {
+                file_name: model_manifest.file_name.to_string(),
+                expected: lfs_sha256.to_string(),
+                actual: exported_hash,
+            });
+        }
+        None => println!(
+            "{} exported with hash {}, no original hash is known to verify it against",
+            model_manifest.file_name, exported_hash
+        ),
+    }
+
+    Ok(())
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use crate::hasher;
+    #[allow(unused_imports)]
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_export_removes_file_that_does_not_match_original() {
+        let storage_dir = std::env::temp_dir().join(format!(
+            "cake-test-export-mismatch-{:016x}",
+            rand::random::<u64>()
+        ));
+        let storage_dir = storage_dir.to_str().unwrap();
+
+        let tensor_bytes = b"abcd";
+        let tensor_hash = hasher::sha256_hash(tensor_bytes);
+        store::write_blob(storage_dir, &tensor_hash, tensor_bytes, store::Codec::Lz4).unwrap();
+        let header = r#"{"a":{"dtype":"U8","shape":[4],"data_offsets":[0,4]}}"#;
+        let mut file_bytes = (header.len() as u64).to_le_bytes().to_vec();
+        file_bytes.extend_from_slice(header.as_bytes());
+        file_bytes.extend_from_slice(tensor_bytes);
+
+        let mut model_manifest = Manifest {
+            model_id: "org/model".to_string(),
+            revision: "abc123".to_string(),
+            file_name: "model.safetensors".to_string(),
+            header: header.to_string(),
+            tensors: BTreeMap::from([(
+                "a".to_string(),
+                TensorEntry {
+                    hash: tensor_hash,
+                    data_offsets: [0, 4],
+                    size: 4,
+                },
+            )]),
+            lfs_sha256: Some(hasher::sha256_hash(&file_bytes)),
+        };
+        let target_file_path = Path::new(storage_dir).join("model.safetensors");
+
+        combine_cached_files_to_safetensors_file(&model_manifest, storage_dir, &target_file_path)
+            .unwrap();
+        assert_eq!(fs::read(&target_file_path).unwrap(), file_bytes);
+
+        model_manifest.lfs_sha256 = Some("0".repeat(64));
+        assert!(matches!(
+            combine_cached_files_to_safetensors_file(
+                &model_manifest,
+                storage_dir,
+                &target_file_path
+            ),
+            Err(ExportError::HashMismatch { .. })
+        ));
+        assert!(!target_file_path.exists());
+
+        // Exporting the whole model fails the same way once a blob is gone, rather than panicking
+        let out_dir = Path::new(storage_dir).join("out");
+        let out_dir = out_dir.to_str().unwrap();
+        assert!(matches!(
+            export_model_by_model_id("org/model", "abc123", storage_dir, out_dir),
+            Err(ExportError::NotDownloaded { .. })
+        ));
+        model_manifest.lfs_sha256 = None;
+        manifest::save_manifest(storage_dir, &model_manifest);
+        export_model_by_model_id("org/model", "abc123", storage_dir, out_dir).unwrap();
+        let (blob_path, _) =
+            store::find_blob(storage_dir, &model_manifest.tensors["a"].hash).unwrap();
+        fs::remove_file(blob_path).unwrap();
+        assert!(matches!(
+            export_model_by_model_id("org/model", "abc123", storage_dir, out_dir),
+            Err(ExportError::MissingBlob { .. })
+        ));
+
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
 }
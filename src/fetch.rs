@@ -0,0 +1,1101 @@
+use std::env;
+use std::future::Future;
+use std::io::{self, prelude::*};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use indicatif::ProgressBar;
+use rand::Rng;
+use reqwest::header::{
+    HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE,
+    RETRY_AFTER,
+};
+use reqwest::Client;
+use reqwest::StatusCode;
+use thiserror::Error;
+use tokio::runtime::{self, Handle, Runtime};
+use tokio::task::{self, JoinHandle};
+use tokio::time::{self, Instant};
+
+use crate::byteranges::{self, ByteRange, ByteRangesEvent, ByteRangesParser, RangesWriter};
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("failed to read the response body: {0}")]
+    Body(io::Error),
+    #[error("failed to write to disk: {0}")]
+    Io(#[from] io::Error),
+    #[error("unexpected status {status} from {url}")]
+    UnexpectedStatus {
+        url: String,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+    #[error("expected the response to cover {expected} but it covered {actual:?}")]
+    InvalidContentRange {
+        expected: String,
+        actual: Option<String>,
+    },
+    #[error("invalid multipart/byteranges response: {0}")]
+    InvalidMultipart(String),
+    #[error("expected {expected} bytes but received {actual}")]
+    UnexpectedLength { expected: u64, actual: u64 },
+    #[error("invalid safetensors header: {0}")]
+    InvalidHeader(#[from] serde_json::Error),
+    #[error("invalid data offsets for tensor {0} in the safetensors header")]
+    InvalidOffsets(String),
+    #[error("expected hash {expected} but the downloaded bytes hashed to {actual}")]
+    HashMismatch { expected: String, actual: String },
+    /// The error of a request that was downloading several layers, which each of them fails with
+    #[error(transparent)]
+    Shared(Arc<DownloadError>),
+}
+
+impl DownloadError {
+    /// Whether trying the same request again could succeed
+    fn is_retryable(&self) -> bool {
+        match self {
+            DownloadError::Request(e) => e.is_timeout() || e.is_connect() || e.is_body(),
+            DownloadError::Body(_) => true,
+            DownloadError::UnexpectedStatus { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+                    || status.is_server_error()
+            }
+            // Only a truncated response is worth retrying, too many bytes means the server is misbehaving
+            DownloadError::UnexpectedLength { expected, actual } => actual < expected,
+            DownloadError::Shared(e) => e.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Whether a request for several ranges failed because the server cannot send them in one response, in which case
+    /// each range is asked for on its own instead
+    fn is_multi_range_refusal(&self) -> bool {
+        match self {
+            DownloadError::UnexpectedStatus { status, .. } => {
+                *status == StatusCode::OK || *status == StatusCode::RANGE_NOT_SATISFIABLE
+            }
+            DownloadError::InvalidContentRange { .. } | DownloadError::InvalidMultipart(_) => true,
+            _ => false,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            DownloadError::UnexpectedStatus { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with full jitter, unless the server asked us to wait for a specific duration.
+    /// Either way the wait is capped, so a server asking for a day does not hold up the download for a day.
+    fn get_backoff(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_backoff);
+        }
+
+        let max_backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        let jittered_millis = rand::thread_rng().gen_range(0..=max_backoff.as_millis() as u64);
+
+        Duration::from_millis(jittered_millis)
+    }
+}
+
+/// Requests without a body to stream, such as HEAD requests, give up if they take longer than this
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Layers can take much longer than that to download, so their requests only give up if the server stops sending
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Downloads run on a runtime of their own, so that waiting on sockets does not tie up a thread per download.
+/// It is shared by every fetcher, as the connections a client pools belong to the runtime that opened them.
+pub fn get_runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    })
+}
+
+/// Runs a download to completion from code that is not async. Blocking on the runtime from one of its own tasks would
+/// tie up the thread that the download needs, so that is caught here rather than left to deadlock or panic deep inside
+/// tokio.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    assert!(
+        Handle::try_current().is_err(),
+        "blocking downloads cannot be started from inside the async runtime, await the async version instead"
+    );
+    get_runtime().block_on(future)
+}
+
+/// How many chunks can wait for the blocking writer before the download waits for it to catch up
+const WRITE_QUEUE_LENGTH: usize = 64;
+
+/// Hands the bytes of a download over to a blocking thread, which writes and hashes them while the runtime thread goes
+/// back to receiving. Writes only wait when the blocking thread has fallen behind by more than the queue holds.
+pub struct BlockingWriter<W> {
+    sender: Option<SyncSender<Vec<u8>>>,
+    worker: JoinHandle<io::Result<W>>,
+}
+
+impl<W: Write + Send + 'static> BlockingWriter<W> {
+    pub fn new(mut writer: W) -> BlockingWriter<W> {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(WRITE_QUEUE_LENGTH);
+        let worker = task::spawn_blocking(move || {
+            for bytes in receiver {
+                writer.write_all(&bytes)?;
+            }
+            writer.flush()?;
+            Ok(writer)
+        });
+
+        BlockingWriter {
+            sender: Some(sender),
+            worker,
+        }
+    }
+
+    /// Waits for every write to land and gives the writer back, or the error that stopped the blocking thread
+    pub async fn finish(self) -> io::Result<W> {
+        // Closing the queue lets the blocking thread stop once it has written the rest
+        drop(self.sender);
+        self.worker.await.map_err(io::Error::other)?
+    }
+}
+
+impl<W> Write for BlockingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sender = self.sender.as_ref().unwrap();
+        let sent = match sender.try_send(buf.to_vec()) {
+            Ok(()) => true,
+            // Let the runtime move its other tasks to another thread while this one waits
+            Err(TrySendError::Full(bytes)) => task::block_in_place(|| sender.send(bytes)).is_ok(),
+            Err(TrySendError::Disconnected(_)) => false,
+        };
+        if !sent {
+            // The blocking thread only stops early on an error, which `finish` gives back
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the blocking writer stopped",
+            ));
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Bytes are flushed once the blocking thread has written all of them, see `finish`
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Caps the combined speed of every download that shares it
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    /// When the bytes that have been let through so far will have been used up
+    next_free: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_second,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until there is room for the given number of bytes, after the bytes of every other download
+    async fn acquire(&self, number_of_bytes: u64) {
+        let start = {
+            let mut next_free = self.next_free.lock().unwrap();
+            // Time spent idle is not saved up for a burst later on
+            let start = (*next_free).max(Instant::now());
+            *next_free = start
+                + Duration::from_secs_f64(number_of_bytes as f64 / self.bytes_per_second as f64);
+            start
+        };
+
+        time::sleep_until(start).await;
+    }
+}
+
+/// Downloads byte ranges of remote files, retrying failed requests from wherever they stopped.
+/// Clones share their connections, speed cap and what they have learned about the server.
+#[derive(Clone)]
+pub struct Fetcher {
+    // Reuse the reqwest client to enable connection pooling
+    client: Client,
+    retry_policy: RetryPolicy,
+    send_hf_token: bool,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Set once the server has shown that it cannot send several ranges in one response, after which each range is
+    /// asked for on its own
+    multi_range_unsupported: Arc<AtomicBool>,
+}
+
+impl Fetcher {
+    pub fn new(retry_policy: RetryPolicy) -> Fetcher {
+        Fetcher {
+            client: Client::new(),
+            retry_policy,
+            send_hf_token: true,
+            rate_limiter: None,
+            multi_range_unsupported: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A fetcher for the registry, which never gets to see the Hugging Face token
+    pub fn for_registry(retry_policy: RetryPolicy) -> Fetcher {
+        Fetcher {
+            send_hf_token: false,
+            ..Fetcher::new(retry_policy)
+        }
+    }
+
+    /// Shares a speed cap with every other fetcher that has the same rate limiter
+    pub fn with_rate_limiter(self, rate_limiter: Option<Arc<RateLimiter>>) -> Fetcher {
+        Fetcher {
+            rate_limiter,
+            ..self
+        }
+    }
+
+    /// Checks whether a URL can be downloaded with a HEAD request. Failures are not retried, they just mean no.
+    pub async fn exists(&self, url: &str) -> bool {
+        self.client
+            .head(url)
+            .headers(self.get_auth_headers())
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .is_ok_and(|response| response.status() == StatusCode::OK)
+    }
+
+    /// Downloads a small part of a file into memory, such as the header of a safetensors file
+    pub fn download_part_of_file(
+        &self,
+        file_url: &str,
+        byte_index: u64,
+        number_of_bytes: u64,
+        pb: Option<&ProgressBar>,
+    ) -> Result<Vec<u8>, DownloadError> {
+        let mut buffer: Vec<u8> = Vec::new();
+        block_on(self.download_part_of_file_to_writer(
+            file_url,
+            byte_index,
+            number_of_bytes,
+            pb,
+            &mut buffer,
+        ))?;
+
+        Ok(buffer)
+    }
+
+    pub async fn download_part_of_file_to_writer(
+        &self,
+        file_url: &str,
+        byte_index: u64,
+        number_of_bytes: u64,
+        pb: Option<&ProgressBar>,
+        writer: &mut impl Write,
+    ) -> Result<u64, DownloadError> {
+        let mut written_bytes: u64 = 0;
+        let mut retry: u32 = 0;
+
+        while written_bytes < number_of_bytes {
+            // Only ask for whatever is missing, in case a previous attempt stopped part of the way through
+            let result = self
+                .try_download_part_of_file_to_writer(
+                    file_url,
+                    byte_index + written_bytes,
+                    number_of_bytes - written_bytes,
+                    pb,
+                    writer,
+                    &mut written_bytes,
+                )
+                .await;
+
+            if let Err(e) = result {
+                self.wait_before_retry(e, &mut retry, pb).await?;
+            }
+        }
+
+        Ok(written_bytes)
+    }
+
+    /// Downloads several ranges of a file into a writer one after the other, with as few requests as the server
+    /// allows. Ranges are asked for together with a single `Range` header, and the server may answer with
+    /// `multipart/byteranges`, with a single range that covers some or all of them, or by ignoring the ranges, in
+    /// which case they are asked for one at a time from then on.
+    pub async fn download_ranges_of_file_to_writer(
+        &self,
+        file_url: &str,
+        ranges: &[ByteRange],
+        pb: Option<&ProgressBar>,
+        writer: &mut impl Write,
+    ) -> Result<u64, DownloadError> {
+        let mut written_bytes: u64 = 0;
+        let mut retry: u32 = 0;
+
+        loop {
+            // Only ask for whatever is missing, in case a previous attempt stopped part of the way through
+            let remaining_ranges = byteranges::skip_bytes(ranges, written_bytes);
+            if remaining_ranges.len() <= 1 || self.multi_range_unsupported.load(Ordering::Relaxed) {
+                for range in remaining_ranges {
+                    written_bytes += self
+                        .download_part_of_file_to_writer(
+                            file_url,
+                            range.start,
+                            range.size,
+                            pb,
+                            writer,
+                        )
+                        .await?;
+                }
+                return Ok(written_bytes);
+            }
+
+            let written_bytes_before = written_bytes;
+            let result = self
+                .try_download_ranges_of_file_to_writer(
+                    file_url,
+                    &remaining_ranges,
+                    pb,
+                    writer,
+                    &mut written_bytes,
+                )
+                .await;
+            match result {
+                // Servers may send fewer ranges than were asked for, so the rest are asked for again, unless the
+                // server sent none of them
+                Ok(()) if written_bytes > written_bytes_before => {}
+                Ok(()) => self.multi_range_unsupported.store(true, Ordering::Relaxed),
+                Err(e) if e.is_multi_range_refusal() => {
+                    self.multi_range_unsupported.store(true, Ordering::Relaxed)
+                }
+                Err(e) => self.wait_before_retry(e, &mut retry, pb).await?,
+            }
+        }
+    }
+
+    /// Downloads several ranges of a file like `download_ranges_of_file_to_writer`, but on a task of its own with the
+    /// writer on a blocking thread, so that writing and hashing the bytes of one request neither holds up the others
+    /// nor stalls its own stream. Gives the writer back once everything has been written.
+    pub async fn spawn_download_ranges_of_file_to_writer<W: Write + Send + 'static>(
+        &self,
+        file_url: &str,
+        ranges: &[ByteRange],
+        pb: Option<&ProgressBar>,
+        writer: W,
+    ) -> Result<W, DownloadError> {
+        let fetcher = self.clone();
+        let (file_url, ranges, pb) = (file_url.to_string(), ranges.to_vec(), pb.cloned());
+        let mut blocking_writer = BlockingWriter::new(writer);
+        let (result, blocking_writer) = tokio::spawn(async move {
+            let result = fetcher
+                .download_ranges_of_file_to_writer(
+                    &file_url,
+                    &ranges,
+                    pb.as_ref(),
+                    &mut blocking_writer,
+                )
+                .await;
+            (result, blocking_writer)
+        })
+        .await
+        .map_err(io::Error::other)?;
+
+        // A write that failed on the blocking thread only shows up in the download as a closed queue, so the error of
+        // the blocking thread is the one worth reporting
+        let writer = blocking_writer.finish().await?;
+        result?;
+
+        Ok(writer)
+    }
+
+    /// Downloads an entire file into memory, for small files that are not split into layers
+    pub fn download_file(&self, file_url: &str) -> Result<Vec<u8>, DownloadError> {
+        let mut buffer: Vec<u8> = Vec::new();
+        block_on(self.download_file_to_writer(file_url, None, &mut buffer))?;
+
+        Ok(buffer)
+    }
+
+    /// Downloads an entire file into a writer as it arrives, for files that are not split into layers.
+    /// A response that stops part of the way through is picked up with a range request if its length is known, as
+    /// the writer cannot take back the bytes it already has.
+    pub async fn download_file_to_writer(
+        &self,
+        file_url: &str,
+        pb: Option<&ProgressBar>,
+        writer: &mut impl Write,
+    ) -> Result<u64, DownloadError> {
+        let mut written_bytes: u64 = 0;
+        let mut retry: u32 = 0;
+
+        loop {
+            let mut file_size: Option<u64> = None;
+            let result = self
+                .try_download_file_to_writer(
+                    file_url,
+                    pb,
+                    writer,
+                    &mut written_bytes,
+                    &mut file_size,
+                )
+                .await;
+            match (result, file_size) {
+                (Ok(()), _) => return Ok(written_bytes),
+                (Err(e), _) if written_bytes == 0 => {
+                    self.wait_before_retry(e, &mut retry, pb).await?
+                }
+                (Err(e), Some(file_size)) if e.is_retryable() && written_bytes < file_size => {
+                    return Ok(written_bytes
+                        + self
+                            .download_part_of_file_to_writer(
+                                file_url,
+                                written_bytes,
+                                file_size - written_bytes,
+                                pb,
+                                writer,
+                            )
+                            .await?);
+                }
+                (Err(e), _) => return Err(e),
+            }
+        }
+    }
+
+    /// Sleeps before the next attempt if the error can be retried, otherwise gives the error back
+    async fn wait_before_retry(
+        &self,
+        e: DownloadError,
+        retry: &mut u32,
+        pb: Option<&ProgressBar>,
+    ) -> Result<(), DownloadError> {
+        if !e.is_retryable() || *retry >= self.retry_policy.max_retries {
+            return Err(e);
+        }
+
+        let backoff = self.retry_policy.get_backoff(*retry, e.retry_after());
+        *retry += 1;
+        let message = format!(
+            "{} (retry {}/{} in {:.1}s)",
+            e,
+            retry,
+            self.retry_policy.max_retries,
+            backoff.as_secs_f32()
+        );
+        match pb {
+            Some(pb) => pb.println(message),
+            None => println!("{}", message),
+        }
+        time::sleep(backoff).await;
+
+        Ok(())
+    }
+
+    fn get_auth_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if !self.send_hf_token {
+            return headers;
+        }
+
+        if let Ok(bearer_token) = env::var("HF_API_KEY") {
+            let bearer_value = format!("Bearer {}", bearer_token);
+            headers.insert(AUTHORIZATION, HeaderValue::from_str(&bearer_value).unwrap());
+        }
+
+        headers
+    }
+
+    async fn try_download_file_to_writer(
+        &self,
+        file_url: &str,
+        pb: Option<&ProgressBar>,
+        writer: &mut impl Write,
+        written_bytes: &mut u64,
+        file_size: &mut Option<u64>,
+    ) -> Result<(), DownloadError> {
+        let mut response = time::timeout(
+            STALL_TIMEOUT,
+            self.client
+                .get(file_url)
+                .headers(self.get_auth_headers())
+                .send(),
+        )
+        .await
+        .map_err(|_| get_stall_error())??;
+
+        if response.status() != StatusCode::OK {
+            return Err(DownloadError::UnexpectedStatus {
+                url: file_url.to_string(),
+                status: response.status(),
+                retry_after: get_retry_after(response.headers()),
+            });
+        }
+
+        // Files can be large, so like layers they are only given up on if the server stops sending
+        *file_size = response.content_length();
+        while let Some(chunk) = time::timeout(STALL_TIMEOUT, response.chunk())
+            .await
+            .map_err(|_| get_stall_error())??
+        {
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.acquire(chunk.len() as u64).await;
+            }
+            writer.write_all(&chunk)?;
+            *written_bytes += chunk.len() as u64;
+            if let Some(pb) = pb {
+                pb.inc(chunk.len() as u64);
+            }
+        }
+
+        if let Some(file_size) = *file_size {
+            if *written_bytes != file_size {
+                return Err(DownloadError::UnexpectedLength {
+                    expected: file_size,
+                    actual: *written_bytes,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn try_download_part_of_file_to_writer(
+        &self,
+        file_url: &str,
+        byte_index: u64,
+        number_of_bytes: u64,
+        pb: Option<&ProgressBar>,
+        writer: &mut impl Write,
+        written_bytes: &mut u64,
+    ) -> Result<(), DownloadError> {
+        // Set up headers
+        let mut headers = self.get_auth_headers();
+
+        // Range is inclusive. Example: 0-499 is byte 0 to byte 499, so 500 bytes in total
+        let range_end = byte_index + number_of_bytes - 1;
+        let range_header_value = format!("bytes={}-{}", byte_index, range_end);
+        headers.insert(RANGE, HeaderValue::from_str(&range_header_value).unwrap());
+        let mut response = time::timeout(
+            STALL_TIMEOUT,
+            self.client.get(file_url).headers(headers).send(),
+        )
+        .await
+        .map_err(|_| get_stall_error())??;
+
+        // Anything other than partial content is either an error page or the entire file
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(DownloadError::UnexpectedStatus {
+                url: file_url.to_string(),
+                status: response.status(),
+                retry_after: get_retry_after(response.headers()),
+            });
+        }
+
+        let content_range = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok());
+        if parse_content_range(content_range) != Some((byte_index, range_end)) {
+            return Err(DownloadError::InvalidContentRange {
+                expected: format!("bytes {}-{}", byte_index, range_end),
+                actual: content_range.map(|value| value.to_string()),
+            });
+        }
+
+        let content_length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if let Some(content_length) = content_length {
+            if content_length != number_of_bytes {
+                return Err(DownloadError::UnexpectedLength {
+                    expected: number_of_bytes,
+                    actual: content_length,
+                });
+            }
+        }
+
+        let mut received_bytes: u64 = 0;
+
+        while let Some(chunk) = time::timeout(STALL_TIMEOUT, response.chunk())
+            .await
+            .map_err(|_| get_stall_error())??
+        {
+            let chunk_size = chunk.len() as u64;
+            received_bytes += chunk_size;
+            if received_bytes > number_of_bytes {
+                return Err(DownloadError::UnexpectedLength {
+                    expected: number_of_bytes,
+                    actual: received_bytes,
+                });
+            }
+
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.acquire(chunk_size).await;
+            }
+            // Writers that do more than buffer the bytes are wrapped in a `BlockingWriter`, see
+            // `spawn_download_ranges_of_file_to_writer`
+            writer.write_all(&chunk)?;
+            *written_bytes += chunk_size;
+            if let Some(pb) = pb {
+                pb.inc(chunk_size);
+            }
+        }
+
+        if received_bytes < number_of_bytes {
+            return Err(DownloadError::UnexpectedLength {
+                expected: number_of_bytes,
+                actual: received_bytes,
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn try_download_ranges_of_file_to_writer(
+        &self,
+        file_url: &str,
+        ranges: &[ByteRange],
+        pb: Option<&ProgressBar>,
+        writer: &mut impl Write,
+        written_bytes: &mut u64,
+    ) -> Result<(), DownloadError> {
+        let mut headers = self.get_auth_headers();
+        let range_header_value = byteranges::get_range_header_value(ranges);
+        headers.insert(RANGE, HeaderValue::from_str(&range_header_value).unwrap());
+        let mut response = time::timeout(
+            STALL_TIMEOUT,
+            self.client.get(file_url).headers(headers).send(),
+        )
+        .await
+        .map_err(|_| get_stall_error())??;
+
+        // A server that ignores the ranges answers with the entire file, which is dropped without reading it
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(DownloadError::UnexpectedStatus {
+                url: file_url.to_string(),
+                status: response.status(),
+                retry_after: get_retry_after(response.headers()),
+            });
+        }
+
+        let get_header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let content_range = get_header(CONTENT_RANGE);
+        let mut parser = match get_header(CONTENT_TYPE).and_then(byteranges::get_multipart_boundary)
+        {
+            Some(boundary) => ByteRangesParser::multipart(boundary),
+            // Servers can also merge the ranges into one, or only send the first of them
+            None => match parse_content_range(content_range) {
+                Some((start, end)) if end >= start => ByteRangesParser::single(start, end),
+                _ => {
+                    return Err(DownloadError::InvalidContentRange {
+                        expected: range_header_value.replacen('=', " ", 1),
+                        actual: content_range.map(|value| value.to_string()),
+                    })
+                }
+            },
+        };
+
+        let mut ranges_writer = RangesWriter::new(ranges, writer);
+        let written_bytes_before = *written_bytes;
+        while let Some(chunk) = time::timeout(STALL_TIMEOUT, response.chunk())
+            .await
+            .map_err(|_| get_stall_error())??
+        {
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.acquire(chunk.len() as u64).await;
+            }
+
+            parser.feed(&chunk, &mut |event| match event {
+                ByteRangesEvent::Part { start, .. } => ranges_writer.start_part(start),
+                ByteRangesEvent::Data(bytes) => {
+                    let part_written_bytes = ranges_writer.write(bytes)?;
+                    *written_bytes += part_written_bytes;
+                    if let Some(pb) = pb {
+                        pb.inc(part_written_bytes);
+                    }
+                    Ok(())
+                }
+            })?;
+
+            // Whatever comes after the last range is of no use
+            if ranges_writer.is_done() {
+                return Ok(());
+            }
+        }
+
+        // Stopping part of the way through a range means the connection dropped, rather than the server sending
+        // fewer ranges
+        if !parser.is_between_parts() {
+            return Err(DownloadError::UnexpectedLength {
+                expected: ranges.iter().map(|range| range.size).sum(),
+                actual: *written_bytes - written_bytes_before,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// A server that stops sending is treated like a connection that dropped, so the download is resumed
+fn get_stall_error() -> DownloadError {
+    DownloadError::Body(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("no data received for {}s", STALL_TIMEOUT.as_secs()),
+    ))
+}
+
+/// Parses the start and end of a `Content-Range: bytes <start>-<end>/<total>` header
+pub fn parse_content_range(content_range: Option<&str>) -> Option<(u64, u64)> {
+    let range = content_range?.strip_prefix("bytes ")?;
+    let (range, _total) = range.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+
+    Some((start.trim().parse().ok()?, end.trim().parse().ok()?))
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date
+fn get_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let retry_after = headers.get(RETRY_AFTER)?.to_str().ok()?;
+
+    if let Ok(seconds) = retry_after.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let retry_at = httpdate::parse_http_date(retry_after).ok()?;
+    Some(
+        retry_at
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(
+            parse_content_range(Some("bytes 100-199/1000")),
+            Some((100, 199))
+        );
+        assert_eq!(parse_content_range(Some("bytes 0-7/*")), Some((0, 7)));
+        assert_eq!(parse_content_range(Some("bytes */1000")), None);
+        assert_eq!(parse_content_range(None), None);
+    }
+
+    #[test]
+    fn test_get_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(get_retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(get_retry_after(&headers), Some(Duration::from_secs(120)));
+
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(get_retry_after(&headers), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let retry_policy = RetryPolicy::default();
+
+        for retry in 0..20 {
+            assert!(retry_policy.get_backoff(retry, None) <= retry_policy.max_backoff);
+        }
+        assert_eq!(
+            retry_policy.get_backoff(0, Some(Duration::from_secs(10))),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            retry_policy.get_backoff(0, Some(Duration::from_secs(86400))),
+            retry_policy.max_backoff
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_is_shared_between_downloads() {
+        let rate_limiter = RateLimiter::new(10_000);
+        let started_at = std::time::Instant::now();
+
+        // The first chunk goes straight through, every other one waits for the chunks before it
+        get_runtime().block_on(async {
+            tokio::join!(
+                rate_limiter.acquire(1_000),
+                rate_limiter.acquire(1_000),
+                rate_limiter.acquire(1_000),
+            )
+        });
+        assert!(started_at.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_blocking_writer() {
+        /// Takes a few bytes, then fails
+        struct FailingWriter(Vec<u8>);
+
+        impl Write for FailingWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.0.len() >= 4 {
+                    return Err(io::Error::new(io::ErrorKind::StorageFull, "disk full"));
+                }
+                self.0.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        get_runtime()
+            .block_on(get_runtime().spawn(async {
+                // More chunks than the queue holds, so some writes wait for the blocking thread
+                let mut blocking_writer = BlockingWriter::new(Vec::new());
+                for index in 0..(WRITE_QUEUE_LENGTH * 4) {
+                    blocking_writer.write_all(&[index as u8]).unwrap();
+                }
+                let bytes = blocking_writer.finish().await.unwrap();
+                assert_eq!(bytes.len(), WRITE_QUEUE_LENGTH * 4);
+                assert!(bytes
+                    .iter()
+                    .enumerate()
+                    .all(|(index, byte)| *byte == index as u8));
+
+                // The error of the blocking thread is the one that is given back, rather than the closed queue
+                let mut blocking_writer = BlockingWriter::new(FailingWriter(Vec::new()));
+                for _ in 0..(WRITE_QUEUE_LENGTH * 4) {
+                    if blocking_writer.write_all(b"abcd").is_err() {
+                        break;
+                    }
+                }
+                let error = blocking_writer.finish().await.err().unwrap();
+                assert_eq!(error.kind(), io::ErrorKind::StorageFull);
+            }))
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot be started from inside the async runtime")]
+    fn test_block_on_inside_the_runtime_panics() {
+        get_runtime().block_on(async { block_on(async {}) });
+    }
+
+    #[test]
+    fn test_download_ranges_falls_back_without_multipart() {
+        use axum::extract::Path;
+        use axum::http::{header, HeaderMap as AxumHeaderMap, StatusCode as AxumStatusCode};
+        use axum::response::IntoResponse;
+
+        let file: Vec<u8> = (0..=255).collect();
+        let ranges = [
+            ByteRange { start: 10, size: 5 },
+            ByteRange {
+                start: 100,
+                size: 6,
+            },
+            ByteRange {
+                start: 200,
+                size: 3,
+            },
+        ];
+        let expected: Vec<u8> = [&file[10..15], &file[100..106], &file[200..203]].concat();
+
+        // Servers that cannot send multipart/byteranges either ignore the ranges and send the whole file, merge them
+        // into a single range that covers all of them, or only send the first of them
+        let requests: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+        let handler = {
+            let (file, requests) = (file.clone(), requests.clone());
+            move |Path(mode): Path<String>, headers: AxumHeaderMap| async move {
+                let range = headers[header::RANGE].to_str().unwrap();
+                requests.lock().unwrap().push(format!("{} {}", mode, range));
+                let bounds: Vec<(usize, usize)> = range
+                    .trim_start_matches("bytes=")
+                    .split(',')
+                    .map(|bound| {
+                        let (start, end) = bound.split_once('-').unwrap();
+                        (start.parse().unwrap(), end.parse().unwrap())
+                    })
+                    .collect();
+                let (start, end) = match mode.as_str() {
+                    _ if bounds.len() == 1 => bounds[0],
+                    "merge" => (bounds[0].0, bounds[bounds.len() - 1].1),
+                    "first" => bounds[0],
+                    _ => return (AxumStatusCode::OK, file.clone()).into_response(),
+                };
+                (
+                    AxumStatusCode::PARTIAL_CONTENT,
+                    [(
+                        header::CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", start, end, file.len()),
+                    )],
+                    file[start..=end].to_vec(),
+                )
+                    .into_response()
+            }
+        };
+        let app = axum::Router::new().route("/:mode", axum::routing::get(handler));
+
+        let runtime = Runtime::new().unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let server_url = format!("http://{}", listener.local_addr().unwrap());
+        runtime.spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let download = |fetcher: &Fetcher, mode: &str| {
+            requests.lock().unwrap().clear();
+            let mut written: Vec<u8> = Vec::new();
+            let written_bytes = runtime
+                .block_on(fetcher.download_ranges_of_file_to_writer(
+                    &format!("{}/{}", server_url, mode),
+                    &ranges,
+                    None,
+                    &mut written,
+                ))
+                .unwrap();
+            assert_eq!(written_bytes, expected.len() as u64);
+            assert_eq!(written, expected);
+            requests.lock().unwrap().clone()
+        };
+
+        // A whole file is dropped and each range is asked for on its own from then on
+        let fetcher = Fetcher::new(RetryPolicy::default());
+        assert_eq!(
+            download(&fetcher, "ignore"),
+            vec![
+                "ignore bytes=10-14,100-105,200-202",
+                "ignore bytes=10-14",
+                "ignore bytes=100-105",
+                "ignore bytes=200-202",
+            ]
+        );
+        assert_eq!(download(&fetcher, "ignore").len(), 3);
+
+        // The bytes between merged ranges are skipped
+        let fetcher = Fetcher::new(RetryPolicy::default());
+        assert_eq!(
+            download(&fetcher, "merge"),
+            vec!["merge bytes=10-14,100-105,200-202"]
+        );
+
+        // Ranges that were left out are asked for again
+        assert_eq!(
+            download(&fetcher, "first"),
+            vec![
+                "first bytes=10-14,100-105,200-202",
+                "first bytes=100-105,200-202",
+                "first bytes=200-202",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_download_file_resumes_with_a_range_request() {
+        use axum::body::Body;
+        use axum::http::{header, HeaderMap as AxumHeaderMap, StatusCode as AxumStatusCode};
+        use axum::response::IntoResponse;
+        use futures::StreamExt;
+
+        let file: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+
+        // The whole file stops part of the way through, ranges arrive in full
+        let requests: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+        let handler = {
+            let (file, requests) = (file.clone(), requests.clone());
+            move |headers: AxumHeaderMap| async move {
+                let Some(range) = headers.get(header::RANGE) else {
+                    requests.lock().unwrap().push("whole file".to_string());
+                    // The connection only drops once the first bytes have had time to arrive
+                    let first_chunk: io::Result<Vec<u8>> = Ok(file[..30_000].to_vec());
+                    let chunks =
+                        futures::stream::iter([first_chunk]).chain(futures::stream::once(async {
+                            time::sleep(Duration::from_millis(100)).await;
+                            Err(io::Error::other("connection dropped"))
+                        }));
+                    return (
+                        [(header::CONTENT_LENGTH, file.len().to_string())],
+                        Body::from_stream(chunks),
+                    )
+                        .into_response();
+                };
+                let range = range.to_str().unwrap().to_string();
+                requests.lock().unwrap().push(range.clone());
+                let (start, end) = range.trim_start_matches("bytes=").split_once('-').unwrap();
+                let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                (
+                    AxumStatusCode::PARTIAL_CONTENT,
+                    [(
+                        header::CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", start, end, file.len()),
+                    )],
+                    file[start..=end].to_vec(),
+                )
+                    .into_response()
+            }
+        };
+        let app = axum::Router::new().route("/file", axum::routing::get(handler));
+
+        let runtime = Runtime::new().unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let file_url = format!("http://{}/file", listener.local_addr().unwrap());
+        runtime.spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let fetcher = Fetcher::new(RetryPolicy::default());
+        let mut written: Vec<u8> = Vec::new();
+        let written_bytes = runtime
+            .block_on(fetcher.download_file_to_writer(&file_url, None, &mut written))
+            .unwrap();
+
+        assert_eq!(written_bytes, file.len() as u64);
+        assert!(written == file);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["whole file".to_string(), "bytes=30000-99999".to_string()]
+        );
+    }
+}
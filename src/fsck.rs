@@ -0,0 +1,500 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+
+use crate::config::Config;
+use crate::fetch::RetryPolicy;
+use crate::store::{self, Codec};
+use crate::{download, hasher, manifest};
+
+/// What is wrong with a file in the store
+#[derive(Debug, PartialEq)]
+enum BlobProblem {
+    /// The contents do not hash to the file name
+    Corrupt(String),
+    /// There are fewer bytes than the manifests expect, or the compressed contents end early
+    Truncated { expected: Option<u64>, actual: u64 },
+    /// The file name is not a hash, so the file is not a blob at all
+    Misnamed,
+    /// A temporary file that was left behind by a write that never finished
+    Leftover,
+}
+
+impl fmt::Display for BlobProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlobProblem::Corrupt(reason) => write!(f, "corrupt, {}", reason),
+            BlobProblem::Truncated {
+                expected: Some(expected),
+                actual,
+            } => write!(f, "truncated, {} of {} bytes", actual, expected),
+            BlobProblem::Truncated {
+                expected: None,
+                actual,
+            } => write!(f, "truncated after {} bytes", actual),
+            BlobProblem::Misnamed => write!(f, "not named after a hash"),
+            BlobProblem::Leftover => write!(f, "left behind by an unfinished write"),
+        }
+    }
+}
+
+/// What to do about the problems that are found
+#[derive(Debug, Clone, Copy)]
+pub struct FsckOptions {
+    /// Move bad files out of the way, into the `quarantine` folder of the store
+    pub quarantine: bool,
+    /// Download the blobs that are bad or missing again. Bad files are quarantined first.
+    pub refetch: bool,
+}
+
+/// Checks every file in the store, and that every blob a manifest references is there.
+/// Returns whether the store is healthy, after any repairs.
+pub fn check_store(config: &Config, options: FsckOptions) -> bool {
+    let storage_dir: &str = &config.store_dir;
+    let repair = options.quarantine || options.refetch;
+    // Reading can happen alongside downloads, moving blobs around can not
+    let _store_lock = store::lock_store(storage_dir, repair)
+        .unwrap_or_else(|e| panic!("Unable to lock {}: {}", storage_dir, e));
+
+    let manifests = manifest::load_all_manifests(storage_dir);
+    let files_manifests = manifest::load_all_files_manifests(storage_dir);
+    let expected_sizes = get_expected_sizes(&manifests, &files_manifests);
+
+    let blob_files = store::list_blob_files(storage_dir);
+    let partial_count = blob_files
+        .iter()
+        .filter(|(file_name, _)| file_name.ends_with(".partial"))
+        .count();
+    let blob_files: Vec<(String, PathBuf)> = blob_files
+        .into_iter()
+        .filter(|(file_name, _)| !file_name.ends_with(".partial"))
+        .collect();
+
+    // Rehashing reads every byte of the store, so spread it over every core
+    let pb = ProgressBar::new(blob_files.len() as u64).with_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:20.green/yellow} {pos:>6}/{len:6} files",
+        )
+        .unwrap(),
+    );
+    let mut bad_files: BTreeMap<String, (PathBuf, BlobProblem)> = blob_files
+        .par_iter()
+        .filter_map(|(file_name, blob_path)| {
+            let problem = check_blob(file_name, blob_path, &expected_sizes);
+            pb.inc(1);
+            problem.map(|problem| (file_name.to_string(), (blob_path.clone(), problem)))
+        })
+        .collect();
+    pb.finish_and_clear();
+
+    let bad_hashes: HashSet<&str> = bad_files
+        .keys()
+        .map(|file_name| Codec::from_file_name(file_name).0)
+        .collect();
+    let stored_hashes: HashSet<String> = store::list_blobs(storage_dir)
+        .into_iter()
+        .map(|(hash, _)| hash)
+        .filter(|hash| !bad_hashes.contains(hash.as_str()))
+        .collect();
+    let orphaned_count = stored_hashes
+        .iter()
+        .filter(|hash| !expected_sizes.contains_key(*hash))
+        .count();
+
+    println!("Checked {} files", blob_files.len());
+    for (file_name, (_, problem)) in &bad_files {
+        println!("{}: {}", file_name, problem);
+    }
+    if orphaned_count > 0 {
+        println!(
+            "{} blobs are not used by any model, `cake gc` deletes them",
+            orphaned_count
+        );
+    }
+    if partial_count > 0 {
+        println!(
+            "{} layers were partially downloaded, downloading their model again resumes them",
+            partial_count
+        );
+    }
+
+    if repair && !bad_files.is_empty() {
+        quarantine_files(
+            storage_dir,
+            bad_files
+                .iter()
+                .map(|(file_name, (blob_path, _))| (file_name, blob_path)),
+        );
+        bad_files.clear();
+    }
+
+    let mut missing_hashes = get_missing_hashes(&expected_sizes, &stored_hashes);
+    for (model_ref, hashes) in
+        get_models_missing_blobs(&manifests, &files_manifests, &missing_hashes)
+    {
+        println!("{} has {} missing or bad blobs", model_ref, hashes.len());
+    }
+
+    if options.refetch && !missing_hashes.is_empty() {
+        let retry_policy = RetryPolicy::default();
+        for model_manifest in &manifests {
+            let result = download::refetch_layers(
+                config,
+                model_manifest,
+                &missing_hashes,
+                config.compression,
+                retry_policy,
+            );
+            if let Err(e) = result {
+                println!(
+                    "Unable to download the layers of {}@{}: {} again: {}",
+                    model_manifest.model_id, model_manifest.revision, model_manifest.file_name, e
+                );
+            }
+        }
+        for files_manifest in &files_manifests {
+            download::refetch_files(
+                config,
+                files_manifest,
+                &missing_hashes,
+                config.compression,
+                retry_policy,
+            );
+        }
+
+        let stored_hashes: HashSet<String> = store::list_blobs(storage_dir)
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .collect();
+        missing_hashes = get_missing_hashes(&expected_sizes, &stored_hashes);
+        println!(
+            "{} blobs are still missing after downloading them again",
+            missing_hashes.len()
+        );
+    }
+
+    bad_files.is_empty() && missing_hashes.is_empty()
+}
+
+/// The size of every blob the manifests reference, by hash
+fn get_expected_sizes(
+    manifests: &[manifest::Manifest],
+    files_manifests: &[manifest::FilesManifest],
+) -> HashMap<String, u64> {
+    let mut expected_sizes: HashMap<String, u64> = HashMap::new();
+    for model_manifest in manifests {
+        for tensor in model_manifest.tensors.values() {
+            expected_sizes.insert(tensor.hash.to_string(), tensor.size);
+        }
+    }
+    for files_manifest in files_manifests {
+        for file_entry in files_manifest.files.values() {
+            expected_sizes.insert(file_entry.hash.to_string(), file_entry.size);
+        }
+    }
+
+    expected_sizes
+}
+
+fn get_missing_hashes(
+    expected_sizes: &HashMap<String, u64>,
+    stored_hashes: &HashSet<String>,
+) -> HashSet<String> {
+    expected_sizes
+        .keys()
+        .filter(|hash| !stored_hashes.contains(*hash))
+        .cloned()
+        .collect()
+}
+
+/// Groups the missing blobs by the model they belong to
+fn get_models_missing_blobs(
+    manifests: &[manifest::Manifest],
+    files_manifests: &[manifest::FilesManifest],
+    missing_hashes: &HashSet<String>,
+) -> BTreeMap<String, HashSet<String>> {
+    let mut models_missing_blobs: BTreeMap<String, HashSet<String>> = BTreeMap::new();
+
+    let model_hashes = manifests
+        .iter()
+        .flat_map(|m| {
+            let model_ref = format!("{}@{}", m.model_id, m.revision);
+            m.tensors
+                .values()
+                .map(move |t| (model_ref.clone(), &t.hash))
+        })
+        .chain(files_manifests.iter().flat_map(|m| {
+            let model_ref = format!("{}@{}", m.model_id, m.revision);
+            m.files.values().map(move |f| (model_ref.clone(), &f.hash))
+        }));
+    for (model_ref, hash) in model_hashes {
+        if missing_hashes.contains(hash) {
+            models_missing_blobs
+                .entry(model_ref)
+                .or_default()
+                .insert(hash.to_string());
+        }
+    }
+
+    models_missing_blobs
+}
+
+/// Rehashes a single file of the store, returning what is wrong with it if anything is.
+/// The file name is the one the file would have without the fan-out folders, so it starts with the whole hash.
+fn check_blob(
+    file_name: &str,
+    blob_path: &Path,
+    expected_sizes: &HashMap<String, u64>,
+) -> Option<BlobProblem> {
+    if file_name.ends_with(".tmp") {
+        return Some(BlobProblem::Leftover);
+    }
+    let (hash, codec) = Codec::from_file_name(file_name);
+    if !hasher::is_sha256(hash) {
+        return Some(BlobProblem::Misnamed);
+    }
+    // Compressed blobs record their size, in case no manifest uses them
+    let expected_size = expected_sizes.get(hash).copied().or_else(|| match codec {
+        Codec::None => None,
+        Codec::Lz4 => store::get_blob_logical_size(blob_path, codec).ok(),
+    });
+
+    let (actual_hash, actual_size) = match hash_blob_file(blob_path, codec) {
+        Ok(hash_and_size) => hash_and_size,
+        Err((e, actual_size)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Some(BlobProblem::Truncated {
+                expected: expected_size,
+                actual: actual_size,
+            })
+        }
+        Err((e, _)) => return Some(BlobProblem::Corrupt(format!("unable to read it: {}", e))),
+    };
+
+    if expected_size.is_some_and(|expected_size| actual_size < expected_size) {
+        return Some(BlobProblem::Truncated {
+            expected: expected_size,
+            actual: actual_size,
+        });
+    }
+    if actual_hash != hash {
+        return Some(BlobProblem::Corrupt(format!(
+            "the contents hash to {}",
+            actual_hash
+        )));
+    }
+
+    None
+}
+
+/// Hashes the uncompressed contents of a blob. On failure, also returns how many bytes could be read.
+fn hash_blob_file(blob_path: &Path, codec: Codec) -> Result<(String, u64), (io::Error, u64)> {
+    let blob_reader = store::open_blob_file(blob_path, codec).map_err(|e| (e, 0))?;
+
+    // Only counts down for what was actually read, so it also tells how far a failed read got
+    let mut counted_reader = blob_reader.take(u64::MAX);
+    let mut hashing_writer = hasher::HashingWriter::new(io::sink());
+    let result = hashing_writer.hash_existing(&mut counted_reader);
+    let read_bytes = u64::MAX - counted_reader.limit();
+
+    match result {
+        Ok(_) => Ok((hashing_writer.finalize(), read_bytes)),
+        Err(e) => Err((e, read_bytes)),
+    }
+}
+
+/// Moves bad files into the `quarantine` folder of the store, where they no longer count as blobs
+fn quarantine_files<'a>(
+    storage_dir: &str,
+    blob_files: impl Iterator<Item = (&'a String, &'a PathBuf)>,
+) {
+    let quarantine_dir: PathBuf = Path::new(storage_dir).join("quarantine");
+    fs::create_dir_all(&quarantine_dir).unwrap();
+
+    for (file_name, blob_path) in blob_files {
+        match fs::rename(blob_path, quarantine_dir.join(file_name)) {
+            Ok(()) => println!("Quarantined {}", file_name),
+            Err(e) => println!("Unable to quarantine {}: {}", file_name, e),
+        }
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_check_blob() {
+        let storage_dir =
+            std::env::temp_dir().join(format!("cake-test-fsck-{:016x}", rand::random::<u64>()));
+        let storage_dir = storage_dir.to_str().unwrap();
+        fs::create_dir_all(storage_dir).unwrap();
+
+        let bytes: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+        let hash = hasher::sha256_hash(&bytes);
+        let expected_sizes = HashMap::from([(hash.to_string(), bytes.len() as u64)]);
+
+        for codec in [Codec::None, Codec::Lz4] {
+            store::write_blob(storage_dir, &hash, &bytes, codec).unwrap();
+            let (blob_path, _) = store::find_blob(storage_dir, &hash).unwrap();
+            let file_name = format!(
+                "{}{}",
+                &hash[..2],
+                blob_path.file_name().unwrap().to_str().unwrap()
+            );
+            assert_eq!(check_blob(&file_name, &blob_path, &expected_sizes), None);
+
+            // Cut the blob short
+            let stored_bytes = fs::read(&blob_path).unwrap();
+            fs::write(&blob_path, &stored_bytes[..stored_bytes.len() / 2]).unwrap();
+            assert!(matches!(
+                check_blob(&file_name, &blob_path, &expected_sizes),
+                Some(BlobProblem::Truncated { .. })
+            ));
+            fs::remove_file(&blob_path).unwrap();
+        }
+
+        // Same length, different contents
+        let mut corrupt_bytes = bytes.clone();
+        corrupt_bytes[100] ^= 0xff;
+        let blob_path = store::get_blob_path(storage_dir, &hash, Codec::None).unwrap();
+        fs::write(&blob_path, &corrupt_bytes).unwrap();
+        assert!(matches!(
+            check_blob(&hash, &blob_path, &expected_sizes),
+            Some(BlobProblem::Corrupt(_))
+        ));
+
+        assert_eq!(
+            check_blob("notes.txt", &blob_path, &expected_sizes),
+            Some(BlobProblem::Misnamed)
+        );
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_store_finds_and_refetches_corrupt_blob() {
+        use axum::http::{header, HeaderMap as AxumHeaderMap, StatusCode as AxumStatusCode};
+        use std::collections::HashMap;
+
+        let test_dir = std::env::temp_dir().join(format!(
+            "cake-test-fsck-store-{:016x}",
+            rand::random::<u64>()
+        ));
+        let storage_dir = test_dir.to_str().unwrap();
+
+        // A safetensors file of two tensors, with the layer of each in the store
+        let header = r#"{"a":{"dtype":"U8","shape":[64],"data_offsets":[0,64]},"b":{"dtype":"U8","shape":[32],"data_offsets":[64,96]}}"#;
+        let tensor_data: Vec<u8> = (0..96).map(|i| (i * 3) as u8).collect();
+        let file: Vec<u8> = [
+            &(header.len() as u64).to_le_bytes()[..],
+            header.as_bytes(),
+            &tensor_data,
+        ]
+        .concat();
+        let layers_to_hashes_map = HashMap::from([
+            ("a".to_string(), hasher::sha256_hash(&tensor_data[..64])),
+            ("b".to_string(), hasher::sha256_hash(&tensor_data[64..])),
+        ]);
+        store::write_blob(
+            storage_dir,
+            &layers_to_hashes_map["a"],
+            &tensor_data[..64],
+            Codec::Lz4,
+        )
+        .unwrap();
+        store::write_blob(
+            storage_dir,
+            &layers_to_hashes_map["b"],
+            &tensor_data[64..],
+            Codec::Lz4,
+        )
+        .unwrap();
+        let model_manifest = manifest::Manifest::from_header(
+            "org/model",
+            "abc123",
+            "model.safetensors",
+            header.as_bytes(),
+            &serde_json::from_str(header).unwrap(),
+            &layers_to_hashes_map,
+        )
+        .unwrap();
+        manifest::save_manifest(storage_dir, &model_manifest);
+
+        // Serves the file as Hugging Face would, and nothing else, so the registry never has the layer
+        let handler = {
+            let file = file.clone();
+            move |headers: AxumHeaderMap| async move {
+                let range = headers[header::RANGE].to_str().unwrap().to_string();
+                let (start, end) = range.trim_start_matches("bytes=").split_once('-').unwrap();
+                let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                (
+                    AxumStatusCode::PARTIAL_CONTENT,
+                    [(
+                        header::CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", start, end, file.len()),
+                    )],
+                    file[start..=end].to_vec(),
+                )
+            }
+        };
+        let app = axum::Router::new().route(
+            "/org/model/resolve/abc123/model.safetensors",
+            axum::routing::get(handler),
+        );
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let server_url = format!("http://{}", listener.local_addr().unwrap());
+        runtime.spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let config = Config {
+            store_dir: storage_dir.to_string(),
+            hf_endpoint: server_url.to_string(),
+            registry_url: server_url.to_string(),
+            ..Config::default()
+        };
+        let check_only = FsckOptions {
+            quarantine: false,
+            refetch: false,
+        };
+        assert!(check_store(&config, check_only));
+
+        // Flip a byte of the stored layer without changing its length
+        let (blob_path, codec) = store::find_blob(storage_dir, &layers_to_hashes_map["a"]).unwrap();
+        let mut corrupt_bytes = tensor_data[..64].to_vec();
+        corrupt_bytes[10] ^= 0xff;
+        fs::remove_file(&blob_path).unwrap();
+        store::write_blob(
+            storage_dir,
+            &layers_to_hashes_map["a"],
+            &corrupt_bytes,
+            codec,
+        )
+        .unwrap();
+        assert!(!check_store(&config, check_only));
+
+        // Refetching quarantines the bad blob and downloads the layer again
+        let refetch = FsckOptions {
+            quarantine: false,
+            refetch: true,
+        };
+        assert!(check_store(&config, refetch));
+        let mut blob_bytes = Vec::new();
+        store::open_blob(storage_dir, &layers_to_hashes_map["a"])
+            .unwrap()
+            .read_to_end(&mut blob_bytes)
+            .unwrap();
+        assert_eq!(blob_bytes, &tensor_data[..64]);
+        assert_eq!(
+            fs::read_dir(test_dir.join("quarantine")).unwrap().count(),
+            1
+        );
+        assert!(check_store(&config, check_only));
+
+        fs::remove_dir_all(&test_dir).unwrap();
+    }
+}
@@ -1,11 +1,18 @@
-use std::{collections::HashMap, fs};
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
 
-use serde_json::Value;
+use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
 
 use reqwest::blocking::Client;
 
+use crate::config::Config;
 use crate::download;
+use crate::fetch::DownloadError;
+use crate::hf;
+use crate::manifest::{FilesManifest, Manifest};
+use crate::registry::{self, FileHashes, LayerHash, ModelHashes};
+use crate::shards::{self, ShardError};
 
 pub fn sha256_hash(bytes: &[u8]) -> String {
     let mut hasher = Sha256::new();
@@ -17,78 +24,412 @@ pub fn sha256_hash(bytes: &[u8]) -> String {
     hash_hex
 }
 
-pub struct ModelHeader {
-    pub raw_header: serde_json::Value,
-    pub header_length_bytes: u64,
+/// Hashes everything written through it on its way to the inner writer, so downloaded bytes are hashed as they arrive
+/// instead of being read back once they are on disk
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
 }
 
-pub fn get_locally_available_hashes(storage_dir: &str) -> Vec<String> {
-    // If storage dir does not exist, create it first
-    if fs::metadata(storage_dir).is_err() {
-        fs::create_dir_all(storage_dir).expect("Failed to create storage directory");
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
     }
-    // Search through available hases
-    let locally_available_hashes: Vec<String> = fs::read_dir(storage_dir)
-        .unwrap()
-        .filter_map(|entry| {
-            entry
-                .ok()
-                .and_then(|dir_entry| dir_entry.file_name().into_string().ok())
-        })
-        .map(|entry| entry.to_string())
-        .collect();
-
-    // println!("{}", locally_available_hashes[0]);
 
-    // println!(
-    //     "{} layers already available locally",
-    //     locally_available_hashes.len()
-    // );
+    /// Hashes bytes that the inner writer already has, such as the start of a resumed download, without writing them
+    /// again
+    pub fn hash_existing(&mut self, reader: &mut impl Read) -> io::Result<u64> {
+        io::copy(reader, &mut self.hasher)
+    }
 
-    locally_available_hashes
+    pub fn finalize(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
 }
 
-pub fn get_model_file_hashes(
-    model_id: &str,
-    file_name: &str,
-) -> (ModelHeader, HashMap<String, String>) {
-    let client = Client::new();
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Only hash what the inner writer took, so the hash always matches what was written
+        let written_size = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written_size]);
+        Ok(written_size)
+    }
 
-    // TODO: Pass this as an env var
-    // TODO: Support custom base URLs
-    let registry_base_url = "http://localhost:3000";
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-    let model_hashes_url = format!("{}/results/{}/hashes.json", registry_base_url, model_id);
+/// Whether a string is a lowercase hex SHA-256 hash, as layers are named in the store and the registry
+pub fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
 
-    // TODO: Error handling
-    // TODO: Handle the situation where the registry is unavailable by downloading all of the layers
-    let response = client.get(model_hashes_url).send().unwrap();
+pub struct ModelHeader {
+    pub raw_header: serde_json::Value,
+    pub raw_header_bytes: Vec<u8>,
+    /// Whether the header came from the registry rather than Hugging Face
+    pub from_registry: bool,
+}
 
-    let hashes: Value = response.json().unwrap();
-    let mut layer_to_hash_map: HashMap<String, String> = HashMap::new();
+/// Retrieves the header of a safetensors file, along with the hash of each of its layers from the registry.
+/// The registry is best-effort: if it is unavailable or does not know the model, no hashes are returned and
+/// the layers have to be hashed after downloading them. The header is only downloaded from Hugging Face if the
+/// registry does not have it.
+pub fn get_model_file_hashes(
+    config: &Config,
+    model_id: &str,
+    revision: &str,
+    file_name: &str,
+) -> Result<(ModelHeader, HashMap<String, String>), DownloadError> {
+    let (registry_header, layer_to_hash_map) =
+        match get_registry_file_hashes(config.registry_url(), model_id, revision, file_name) {
+            Ok(registry_file_hashes) => registry_file_hashes,
+            Err(e) => {
+                println!(
+                    "Hash registry is unavailable ({}), layers will be hashed locally",
+                    e
+                );
+                (None, HashMap::new())
+            }
+        };
 
-    for (key, value) in hashes.as_object().unwrap() {
-        // println!("{}", value);
-        if file_name == value.get("file_name").unwrap().as_str().unwrap() {
-            layer_to_hash_map.insert(
-                key.to_string(),
-                value.get("hash").unwrap().as_str().unwrap().to_string(),
-            );
+    if let Some(header_bytes) = registry_header.map(String::into_bytes) {
+        match serde_json::from_slice(&header_bytes) {
+            Ok(header) => {
+                return Ok((
+                    ModelHeader {
+                        raw_header: header,
+                        raw_header_bytes: header_bytes,
+                        from_registry: true,
+                    },
+                    layer_to_hash_map,
+                ))
+            }
+            Err(e) => println!("The registry's header of {} is invalid: {}", file_name, e),
         }
     }
 
-    let model_file_url = &download::get_download_url_from_model_id(model_id, file_name);
+    let model_file_url = &download::get_download_url_from_model_id(
+        config.hf_endpoint(),
+        model_id,
+        revision,
+        file_name,
+    );
 
     // Download the header to understand the file
-    // TODO: This could be retrieved and cached by the registry
     println!("Retrieving header for {}: {}", model_id, file_name);
-    let (header, header_length) = download::download_safetensors_header(model_file_url);
+    let (header, header_bytes) = download::download_safetensors_header(model_file_url)?;
 
-    (
+    Ok((
         ModelHeader {
             raw_header: header,
-            header_length_bytes: header_length,
+            raw_header_bytes: header_bytes,
+            from_registry: false,
         },
         layer_to_hash_map,
-    )
+    ))
+}
+
+/// Retrieves the header and layer hashes of a file from the registry in a single request.
+/// Registries from before headers were stored only have the hashes, so those are looked up on their own.
+fn get_registry_file_hashes(
+    registry_url: &str,
+    model_id: &str,
+    revision: &str,
+    file_name: &str,
+) -> Result<(Option<String>, HashMap<String, String>), reqwest::Error> {
+    let url = format!(
+        "{}/v1/models/{}/{}/files/{}",
+        registry_url,
+        model_id,
+        hf::encode_revision(revision),
+        hf::encode_path_segment(file_name)
+    );
+    let response = Client::new().get(url).send()?;
+    if !response.status().is_success() {
+        let layer_to_hash_map = get_registry_hashes(registry_url, model_id, revision, file_name)?;
+        return Ok((None, layer_to_hash_map));
+    }
+
+    let file_hashes: FileHashes = response.json()?;
+
+    // Where each layer goes in the file is read from the header, so one that does not match the hashes is not used
+    let mut header = file_hashes.header;
+    if let Some(Err(e)) = header
+        .as_ref()
+        .map(|header| registry::validate_file_header(header.as_bytes(), &file_hashes.hashes))
+    {
+        println!(
+            "The registry's header of {} does not match its hashes ({}), it will be downloaded from Hugging Face",
+            file_name, e
+        );
+        header = None;
+    }
+
+    let layer_to_hash_map = file_hashes
+        .hashes
+        .into_iter()
+        .filter(|(_, layer_hash)| is_sha256(&layer_hash.hash))
+        .map(|(tensor_name, layer_hash)| (tensor_name, layer_hash.hash))
+        .collect();
+
+    Ok((header, layer_to_hash_map))
+}
+
+/// Retrieves the hash of each layer of a file from the registry, which is empty if the registry does not know the model
+pub fn get_registry_hashes(
+    registry_url: &str,
+    model_id: &str,
+    revision: &str,
+    file_name: &str,
+) -> Result<HashMap<String, String>, reqwest::Error> {
+    let client = Client::new();
+
+    // Prefer the hashes of this exact commit. Older registries only serve the hashes files, and older results are
+    // only stored per model, for whichever commit of main was hashed at the time. Any stale hashes are caught when
+    // the downloaded layers are verified.
+    let model_hashes_urls = [
+        format!(
+            "{}/v1/models/{}/{}/hashes",
+            registry_url,
+            model_id,
+            hf::encode_revision(revision)
+        ),
+        format!(
+            "{}/results/{}/{}/hashes.json",
+            registry_url, model_id, revision
+        ),
+        format!("{}/results/{}/hashes.json", registry_url, model_id),
+    ];
+
+    let mut hashes = json!({});
+    for url in model_hashes_urls {
+        let response = client.get(url).send()?;
+        if response.status().is_success() {
+            hashes = response.json()?;
+            break;
+        }
+    }
+
+    let mut layer_to_hash_map: HashMap<String, String> = HashMap::new();
+    for (key, value) in hashes.as_object().into_iter().flatten() {
+        let layer_file_name = value.get("file_name").and_then(Value::as_str);
+        let layer_hash = value.get("hash").and_then(Value::as_str);
+        if let (Some(layer_file_name), Some(layer_hash)) = (layer_file_name, layer_hash) {
+            // Hashes name files in the store, so anything else is treated as if the registry did not have it
+            if file_name == layer_file_name && is_sha256(layer_hash) {
+                layer_to_hash_map.insert(key.to_string(), layer_hash.to_string());
+            }
+        }
+    }
+
+    Ok(layer_to_hash_map)
+}
+
+/// Collects the layer hashes of every file of a model into the format the registry stores them in.
+/// Fails if two files have a tensor of the same name, rather than keeping only one of them.
+pub fn get_model_hashes_from_manifests(manifests: &[Manifest]) -> Result<ModelHashes, ShardError> {
+    let file_hashes: Vec<ModelHashes> = manifests
+        .iter()
+        .map(|model_manifest| {
+            model_manifest
+                .tensors
+                .iter()
+                .map(|(tensor_name, tensor)| {
+                    let layer_hash = LayerHash {
+                        data_offsets: tensor.data_offsets,
+                        hash: tensor.hash.to_string(),
+                        size: tensor.size,
+                        file_name: model_manifest.file_name.to_string(),
+                    };
+                    (tensor_name.to_string(), layer_hash)
+                })
+                .collect()
+        })
+        .collect();
+
+    shards::merge_file_hashes(file_hashes)
+}
+
+/// Uploads the layer hashes of a model to the registry, replacing any it already has for that revision
+pub fn publish_model_hashes(
+    registry_url: &str,
+    model_id: &str,
+    revision: &str,
+    model_hashes: &ModelHashes,
+) -> Result<(), reqwest::Error> {
+    let url = format!(
+        "{}/v1/models/{}/{}/hashes",
+        registry_url,
+        model_id,
+        hf::encode_revision(revision)
+    );
+    Client::new()
+        .put(url)
+        .json(model_hashes)
+        .send()?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Uploads the list of the files of a model that are not safetensors, such as its config and tokenizer, to the
+/// registry. Their blobs have to be uploaded first.
+pub fn publish_model_files(
+    registry_url: &str,
+    files_manifest: &FilesManifest,
+) -> Result<(), reqwest::Error> {
+    let url = format!(
+        "{}/v1/models/{}/{}/files",
+        registry_url,
+        files_manifest.model_id,
+        hf::encode_revision(&files_manifest.revision)
+    );
+    Client::new()
+        .put(url)
+        .json(&files_manifest.files)
+        .send()?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Uploads the original header of each file of a model to the registry, so it does not have to be
+/// downloaded from Hugging Face the next time
+pub fn publish_file_headers(
+    registry_url: &str,
+    manifests: &[Manifest],
+) -> Result<(), reqwest::Error> {
+    let client = Client::new();
+    for model_manifest in manifests {
+        let url = format!(
+            "{}/v1/models/{}/{}/files/{}/header",
+            registry_url,
+            model_manifest.model_id,
+            hf::encode_revision(&model_manifest.revision),
+            hf::encode_path_segment(&model_manifest.file_name)
+        );
+        client
+            .put(url)
+            .body(model_manifest.header.to_string())
+            .send()?
+            .error_for_status()?;
+    }
+
+    Ok(())
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_get_model_hashes_from_manifests() {
+        let get_manifests = |files: &[(&str, &str)]| -> Vec<Manifest> {
+            files
+                .iter()
+                .map(|(file_name, tensor_name)| {
+                    let header_bytes = format!(
+                        r#"{{"{}":{{"dtype":"F16","shape":[2],"data_offsets":[0,4]}}}}"#,
+                        tensor_name
+                    );
+                    let header: Value = serde_json::from_str(&header_bytes).unwrap();
+                    let layers_to_hashes_map =
+                        HashMap::from([(tensor_name.to_string(), format!("hash_{}", tensor_name))]);
+                    Manifest::from_header(
+                        "org/model",
+                        "abc123",
+                        file_name,
+                        header_bytes.as_bytes(),
+                        &header,
+                        &layers_to_hashes_map,
+                    )
+                    .unwrap()
+                })
+                .collect()
+        };
+
+        let manifests = get_manifests(&[("a.safetensors", "a"), ("b.safetensors", "b")]);
+        let model_hashes = get_model_hashes_from_manifests(&manifests).unwrap();
+        assert_eq!(
+            serde_json::to_value(model_hashes).unwrap(),
+            json!({
+                "a": {"data_offsets": [0, 4], "hash": "hash_a", "size": 4, "file_name": "a.safetensors"},
+                "b": {"data_offsets": [0, 4], "hash": "hash_b", "size": 4, "file_name": "b.safetensors"},
+            })
+        );
+
+        // A tensor in two files would otherwise keep the hash of whichever file came last
+        let manifests = get_manifests(&[("a.safetensors", "a"), ("b.safetensors", "a")]);
+        assert!(matches!(
+            get_model_hashes_from_manifests(&manifests),
+            Err(ShardError::DuplicateTensor { .. })
+        ));
+    }
+
+    #[test]
+    fn test_registry_hashes_that_are_not_hashes_are_ignored() {
+        let results_dir = std::env::temp_dir().join(format!(
+            "cake-test-hasher-results-{:016x}",
+            rand::random::<u64>()
+        ));
+        let hashes_file_path = results_dir.join("org").join("model").join("hashes.json");
+        std::fs::create_dir_all(hashes_file_path.parent().unwrap()).unwrap();
+        let valid_hash = "b".repeat(64);
+        // Older registries serve the hashes files as they are, without checking them
+        std::fs::write(
+            &hashes_file_path,
+            json!({
+                "a": {"file_name": "model.safetensors", "hash": "a"},
+                "b": {"file_name": "model.safetensors", "hash": valid_hash},
+                "c": {"file_name": "model.safetensors", "hash": "../../../etc/passwd"},
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let registry_url = format!("http://{}", listener.local_addr().unwrap());
+        let registry_config = Config {
+            results_dir: results_dir.to_str().unwrap().to_string(),
+            ..Config::default()
+        };
+        let app = registry::build_router(
+            &registry_config,
+            crate::index::Index::open(std::path::Path::new(":memory:")).unwrap(),
+        );
+        runtime.spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let layer_to_hash_map =
+            get_registry_hashes(&registry_url, "org/model", "abc123", "model.safetensors").unwrap();
+        assert_eq!(
+            layer_to_hash_map,
+            HashMap::from([("b".to_string(), valid_hash)])
+        );
+
+        std::fs::remove_dir_all(results_dir).unwrap();
+    }
+
+    #[test]
+    fn test_hashing_writer_matches_hash_of_whole() {
+        let bytes: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+
+        // Resume part of the way through, like a partial download
+        let mut written_bytes: Vec<u8> = bytes[..3000].to_vec();
+        let mut hashing_writer = HashingWriter::new(&mut written_bytes);
+        hashing_writer.hash_existing(&mut &bytes[..3000]).unwrap();
+        for chunk in bytes[3000..].chunks(1024) {
+            hashing_writer.write_all(chunk).unwrap();
+        }
+
+        assert_eq!(hashing_writer.finalize(), sha256_hash(&bytes));
+        assert_eq!(written_bytes, bytes);
+    }
 }
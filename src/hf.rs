@@ -7,11 +7,33 @@ use std::result::Result::Ok as stdOk;
 
 use serde::{Deserialize, Serialize};
 
+pub const DEFAULT_REVISION: &str = "main";
+
+/// Splits `org/model@revision` into the model id and the revision, which can be a branch, tag or commit sha.
+/// Defaults to the main branch when no revision is given.
+pub fn parse_model_id_and_revision(model_ref: &str) -> (&str, &str) {
+    match model_ref.split_once('@') {
+        Some((model_id, revision)) if !revision.is_empty() => (model_id, revision),
+        Some((model_id, _)) => (model_id, DEFAULT_REVISION),
+        None => (model_ref, DEFAULT_REVISION),
+    }
+}
+
+/// Revisions such as `refs/pr/1` contain slashes, which have to be encoded to be used in a single path segment
+pub fn encode_revision(revision: &str) -> String {
+    encode_path_segment(revision)
+}
+
+/// Encodes the slashes of a revision or a file in a subfolder, for APIs that expect it in a single path segment
+pub fn encode_path_segment(path: &str) -> String {
+    path.replace('/', "%2F")
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct ModelInfo {
     id: String,
     author: Option<String>,
-    sha: Option<String>,
+    pub sha: Option<String>,
     private: bool,
     disabled: Option<bool>,
     downloads: i32,
@@ -25,6 +47,33 @@ pub struct ModelInfo {
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Sibling {
     pub rfilename: String,
+    // Only returned when blobs are requested, and only for files stored with git LFS
+    #[serde(default)]
+    pub lfs: Option<LfsInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct LfsInfo {
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl ModelInfo {
+    /// The commit sha that the requested revision pointed to, so the download is reproducible even if a branch moves
+    pub fn get_commit_sha(&self, revision: &str) -> String {
+        self.sha.clone().unwrap_or_else(|| revision.to_string())
+    }
+
+    pub fn get_lfs(&self, file_name: &str) -> Option<&LfsInfo> {
+        self.siblings
+            .iter()
+            .find(|s| s.rfilename == file_name)
+            .and_then(|s| s.lfs.as_ref())
+    }
+
+    pub fn get_lfs_sha256(&self, file_name: &str) -> Option<String> {
+        self.get_lfs(file_name).map(|lfs| lfs.sha256.to_string())
+    }
 }
 
 fn fill_model_info_from_json(json_string: &str) -> Result<ModelInfo, serde_json::Error> {
@@ -45,14 +94,20 @@ fn fill_model_info_from_json(json_string: &str) -> Result<ModelInfo, serde_json:
     stdOk(model_info)
 }
 
-pub fn get_model_info(model_id: &str) -> Result<ModelInfo, Error> {
+pub fn get_model_info(
+    hf_endpoint: &str,
+    model_id: &str,
+    revision: &str,
+) -> Result<ModelInfo, Error> {
     // TODO: setup headers?
     let client = Client::new();
 
-    // TODO: handle non-main revisions in future
+    // Requesting the blobs includes the LFS SHA-256 of each file, which is used to verify exports
     let url = format!(
-        "https://huggingface.co/api/models/{}/revision/main",
-        model_id
+        "{}/api/models/{}/revision/{}?blobs=true",
+        hf_endpoint,
+        model_id,
+        encode_revision(revision)
     );
 
     // Set up headers
@@ -74,6 +129,7 @@ pub fn get_model_info(model_id: &str) -> Result<ModelInfo, Error> {
     Ok(result)
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct FileInfo {
     pub path: String,
@@ -91,15 +147,12 @@ fn _fill_file_info_from_json(json_string: &str) -> Result<Vec<FileInfo>, Error>
     Ok(file_infos)
 }
 
-pub fn _get_model_files(model_id: &str) -> Result<Vec<FileInfo>, Error> {
+pub fn _get_model_files(hf_endpoint: &str, model_id: &str) -> Result<Vec<FileInfo>, Error> {
     // TODO: setup headers?
     let client = Client::new();
 
     // TODO: handle non-main revisions in future
-    let url = format!(
-        "https://huggingface.co/api/models/{}/paths-info/main",
-        model_id
-    );
+    let url = format!("{}/api/models/{}/paths-info/main", hf_endpoint, model_id);
     // TOOD: pass in the paths required here: seems we need to get that from the tree url
     // Looks like passing a glob of *.safetensors does not work. We can look at how huggingface-cli doe sit
     // See: https://github.com/huggingface/huggingface_hub/blob/main/src/huggingface_hub/hf_api.py#L2793C10-L2794C103
@@ -127,6 +180,26 @@ mod tests {
     #[allow(unused_imports)]
     use super::*;
 
+    #[test]
+    fn test_parse_model_id_and_revision() {
+        assert_eq!(
+            parse_model_id_and_revision("org/model"),
+            ("org/model", DEFAULT_REVISION)
+        );
+        assert_eq!(
+            parse_model_id_and_revision("org/model@v1.0"),
+            ("org/model", "v1.0")
+        );
+        assert_eq!(
+            parse_model_id_and_revision("org/model@refs/pr/1"),
+            ("org/model", "refs/pr/1")
+        );
+        assert_eq!(
+            parse_model_id_and_revision("org/model@"),
+            ("org/model", DEFAULT_REVISION)
+        );
+    }
+
     #[test]
     fn test_fill_model_info_from_json() {
         // Mock JSON string for testing
@@ -158,6 +231,7 @@ mod tests {
             pipeline_tag: Some("pipeline_tag".to_string()),
             siblings: vec![Sibling {
                 rfilename: "foo.safetensors".to_string(),
+                lfs: None,
             }],
         };
 
@@ -323,57 +397,75 @@ mod tests {
             siblings: vec![
                 Sibling {
                     rfilename: ".gitattributes".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "LICENSE".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "Notice".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "README.md".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "added_tokens.json".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "config.json".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "generation_config.json".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "model-00001-of-00006.safetensors".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "model-00002-of-00006.safetensors".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "model-00003-of-00006.safetensors".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "model-00004-of-00006.safetensors".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "model-00005-of-00006.safetensors".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "model-00006-of-00006.safetensors".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "model.safetensors.index.json".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "quantize_config.json".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "special_tokens_map.json".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "tokenizer.model".to_string(),
+                    lfs: None,
                 },
                 Sibling {
                     rfilename: "tokenizer_config.json".to_string(),
+                    lfs: None,
                 },
             ],
         };
@@ -385,6 +477,34 @@ mod tests {
         assert_eq!(expected_model_info, actual_model_info);
     }
 
+    #[test]
+    fn test_fill_model_info_with_lfs_from_json() {
+        let json_string = r#"{
+            "id": "model_id",
+            "private": false,
+            "downloads": 100,
+            "likes": 50,
+            "tags": [],
+            "siblings": [
+                {"rfilename": "config.json", "blobId": "abc", "size": 10},
+                {
+                    "rfilename": "model.safetensors",
+                    "blobId": "def",
+                    "size": 2048,
+                    "lfs": {"sha256": "sha256_value", "size": 2048, "pointerSize": 134}
+                }
+            ]
+        }"#;
+
+        let actual_model_info = fill_model_info_from_json(json_string).unwrap();
+
+        assert_eq!(actual_model_info.get_lfs_sha256("config.json"), None);
+        assert_eq!(
+            actual_model_info.get_lfs_sha256("model.safetensors"),
+            Some("sha256_value".to_string())
+        );
+    }
+
     #[test]
     fn test_fill_file_info_from_json() {
         // Mock JSON string for testing
@@ -0,0 +1,340 @@
+use std::path::Path;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::registry::{LayerHash, LayerReference, ModelHashes};
+
+/// Hashes uploaded before revisions were tracked are stored with an empty revision, so they are still unique
+const NO_REVISION: &str = "";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS models (
+    id INTEGER PRIMARY KEY,
+    model_id TEXT NOT NULL,
+    revision TEXT NOT NULL,
+    UNIQUE (model_id, revision)
+);
+
+CREATE TABLE IF NOT EXISTS files (
+    id INTEGER PRIMARY KEY,
+    model_row_id INTEGER NOT NULL REFERENCES models (id) ON DELETE CASCADE,
+    file_name TEXT NOT NULL,
+    UNIQUE (model_row_id, file_name)
+);
+
+CREATE TABLE IF NOT EXISTS tensors (
+    file_row_id INTEGER NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    offset_start INTEGER NOT NULL,
+    offset_end INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    PRIMARY KEY (file_row_id, name)
+);
+
+CREATE INDEX IF NOT EXISTS tensors_by_hash ON tensors (hash);
+
+-- Kept apart from the hashes, so uploading the hashes of a revision again does not lose its headers
+CREATE TABLE IF NOT EXISTS headers (
+    model_id TEXT NOT NULL,
+    revision TEXT NOT NULL,
+    file_name TEXT NOT NULL,
+    header TEXT NOT NULL,
+    PRIMARY KEY (model_id, revision, file_name)
+);
+";
+
+/// The registry's database of which layers make up each model, so questions across models can be answered
+/// without reading every hashes file.
+pub struct Index {
+    conn: Connection,
+}
+
+impl Index {
+    /// Opens the database, creating it and its tables if they do not exist yet
+    pub fn open(index_path: &Path) -> rusqlite::Result<Index> {
+        let conn = Connection::open(index_path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.pragma_update(None, "journal_mode", "wal")?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(Index { conn })
+    }
+
+    pub fn is_empty(&self) -> rusqlite::Result<bool> {
+        let model_count: u64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM models", [], |row| row.get(0))?;
+
+        Ok(model_count == 0)
+    }
+
+    /// Stores the hashes of a model at a revision, replacing any that were stored for it before
+    pub fn put_model_hashes(
+        &mut self,
+        model_id: &str,
+        revision: Option<&str>,
+        model_hashes: &ModelHashes,
+    ) -> rusqlite::Result<()> {
+        let revision = revision.unwrap_or(NO_REVISION);
+        let tx = self.conn.transaction()?;
+
+        // Files and tensors are removed along with the model
+        tx.execute(
+            "DELETE FROM models WHERE model_id = ?1 AND revision = ?2",
+            params![model_id, revision],
+        )?;
+        tx.execute(
+            "INSERT INTO models (model_id, revision) VALUES (?1, ?2)",
+            params![model_id, revision],
+        )?;
+        let model_row_id = tx.last_insert_rowid();
+
+        {
+            let mut insert_file = tx.prepare(
+                "INSERT INTO files (model_row_id, file_name) VALUES (?1, ?2)
+                 ON CONFLICT (model_row_id, file_name) DO UPDATE SET file_name = file_name
+                 RETURNING id",
+            )?;
+            let mut insert_tensor = tx.prepare(
+                "INSERT INTO tensors (file_row_id, name, hash, offset_start, offset_end, size)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+
+            for (tensor_name, layer_hash) in model_hashes {
+                let file_row_id: i64 = insert_file
+                    .query_row(params![model_row_id, layer_hash.file_name], |row| {
+                        row.get(0)
+                    })?;
+                insert_tensor.execute(params![
+                    file_row_id,
+                    tensor_name,
+                    layer_hash.hash,
+                    layer_hash.data_offsets[0],
+                    layer_hash.data_offsets[1],
+                    layer_hash.size,
+                ])?;
+            }
+        }
+
+        tx.commit()
+    }
+
+    /// Returns the hashes of a model at a revision, or None if they have never been stored
+    pub fn get_model_hashes(
+        &self,
+        model_id: &str,
+        revision: Option<&str>,
+    ) -> rusqlite::Result<Option<ModelHashes>> {
+        let revision = revision.unwrap_or(NO_REVISION);
+        let model_row_id: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT id FROM models WHERE model_id = ?1 AND revision = ?2",
+                params![model_id, revision],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(model_row_id) = model_row_id else {
+            return Ok(None);
+        };
+
+        let mut select_tensors = self.conn.prepare(
+            "SELECT tensors.name, tensors.hash, tensors.offset_start, tensors.offset_end, tensors.size,
+                    files.file_name
+             FROM tensors JOIN files ON files.id = tensors.file_row_id
+             WHERE files.model_row_id = ?1",
+        )?;
+        let model_hashes = select_tensors
+            .query_map(params![model_row_id], |row| {
+                Ok((
+                    row.get(0)?,
+                    LayerHash {
+                        hash: row.get(1)?,
+                        data_offsets: [row.get(2)?, row.get(3)?],
+                        size: row.get(4)?,
+                        file_name: row.get(5)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<ModelHashes>>()?;
+
+        Ok(Some(model_hashes))
+    }
+
+    /// Stores the raw JSON header of a safetensors file, replacing any that was stored for it before
+    pub fn put_file_header(
+        &self,
+        model_id: &str,
+        revision: &str,
+        file_name: &str,
+        header: &str,
+    ) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT INTO headers (model_id, revision, file_name, header) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (model_id, revision, file_name) DO UPDATE SET header = excluded.header",
+            params![model_id, revision, file_name, header],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_file_header(
+        &self,
+        model_id: &str,
+        revision: &str,
+        file_name: &str,
+    ) -> rusqlite::Result<Option<String>> {
+        self.conn
+            .query_row(
+                "SELECT header FROM headers WHERE model_id = ?1 AND revision = ?2 AND file_name = ?3",
+                params![model_id, revision, file_name],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Finds every tensor of every model that is made of the given layer
+    pub fn find_layer_references(&self, hash: &str) -> rusqlite::Result<Vec<LayerReference>> {
+        let mut select_references = self.conn.prepare(
+            "SELECT models.model_id, models.revision, files.file_name, tensors.name
+             FROM tensors
+             JOIN files ON files.id = tensors.file_row_id
+             JOIN models ON models.id = files.model_row_id
+             WHERE tensors.hash = ?1
+             ORDER BY models.model_id, models.revision, files.file_name, tensors.name",
+        )?;
+        let layer_references = select_references
+            .query_map(params![hash], |row| {
+                let revision: String = row.get(1)?;
+                Ok(LayerReference {
+                    model_id: row.get(0)?,
+                    revision: Some(revision).filter(|r| r != NO_REVISION),
+                    file_name: row.get(2)?,
+                    tensor_name: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<LayerReference>>>()?;
+
+        Ok(layer_references)
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn get_layer_hash(hash: &str, file_name: &str) -> LayerHash {
+        LayerHash {
+            data_offsets: [0, 4],
+            hash: hash.to_string(),
+            size: 4,
+            file_name: file_name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_index_round_trip() {
+        let mut index = Index::open(Path::new(":memory:")).unwrap();
+        assert!(index.is_empty().unwrap());
+
+        let model_hashes = ModelHashes::from([
+            ("a".to_string(), get_layer_hash("hash_a", "1.safetensors")),
+            ("b".to_string(), get_layer_hash("hash_b", "2.safetensors")),
+        ]);
+        index
+            .put_model_hashes("org/model", Some("abc123"), &model_hashes)
+            .unwrap();
+
+        assert_eq!(
+            index.get_model_hashes("org/model", Some("abc123")).unwrap(),
+            Some(model_hashes)
+        );
+        assert_eq!(index.get_model_hashes("org/model", None).unwrap(), None);
+
+        // Storing a revision again replaces everything that was stored for it
+        let model_hashes =
+            ModelHashes::from([("c".to_string(), get_layer_hash("hash_c", "1.safetensors"))]);
+        index
+            .put_model_hashes("org/model", Some("abc123"), &model_hashes)
+            .unwrap();
+        assert_eq!(
+            index.get_model_hashes("org/model", Some("abc123")).unwrap(),
+            Some(model_hashes)
+        );
+        assert_eq!(index.find_layer_references("hash_a").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_index_file_headers() {
+        let mut index = Index::open(Path::new(":memory:")).unwrap();
+        index
+            .put_file_header("org/model", "abc123", "model.safetensors", "{}  ")
+            .unwrap();
+        index
+            .put_file_header("org/model", "abc123", "model.safetensors", r#"{"a":{}}"#)
+            .unwrap();
+
+        // Headers outlive the hashes of their revision being replaced
+        let model_hashes = ModelHashes::from([(
+            "a".to_string(),
+            get_layer_hash("hash_a", "model.safetensors"),
+        )]);
+        index
+            .put_model_hashes("org/model", Some("abc123"), &model_hashes)
+            .unwrap();
+
+        assert_eq!(
+            index
+                .get_file_header("org/model", "abc123", "model.safetensors")
+                .unwrap(),
+            Some(r#"{"a":{}}"#.to_string())
+        );
+        assert_eq!(
+            index
+                .get_file_header("org/model", "def456", "model.safetensors")
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_index_find_layer_references() {
+        let mut index = Index::open(Path::new(":memory:")).unwrap();
+        let model_hashes = ModelHashes::from([
+            (
+                "a".to_string(),
+                get_layer_hash("shared", "model.safetensors"),
+            ),
+            (
+                "b".to_string(),
+                get_layer_hash("hash_b", "model.safetensors"),
+            ),
+        ]);
+        index
+            .put_model_hashes("org/base", Some("abc123"), &model_hashes)
+            .unwrap();
+        index
+            .put_model_hashes("org/fine-tune", None, &model_hashes)
+            .unwrap();
+
+        assert_eq!(
+            index.find_layer_references("shared").unwrap(),
+            vec![
+                LayerReference {
+                    model_id: "org/base".to_string(),
+                    revision: Some("abc123".to_string()),
+                    file_name: "model.safetensors".to_string(),
+                    tensor_name: "a".to_string(),
+                },
+                LayerReference {
+                    model_id: "org/fine-tune".to_string(),
+                    revision: None,
+                    file_name: "model.safetensors".to_string(),
+                    tensor_name: "a".to_string(),
+                },
+            ]
+        );
+    }
+}
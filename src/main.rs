@@ -1,21 +1,35 @@
 use std::env;
 use std::fs::{self, File};
 use std::io::Read;
+use std::path::Path;
 use std::time::Duration;
 
 use clap::{Args, Parser, Subcommand};
 
-use rayon::iter::ParallelIterator;
-use serde_json::{json, Map, Value};
+use serde_json::Value;
 
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
+use config::{Config, ConfigArgs};
+use fetch::{DownloadError, RetryPolicy};
+use registry::{LayerHash, ModelHashes};
+
+mod byteranges;
 mod compare;
+mod config;
 mod download;
 mod export;
+mod fetch;
+mod fsck;
 mod hasher;
 mod hf;
+mod index;
+mod manifest;
+mod plan;
+mod push;
 mod registry;
+mod shards;
+mod store;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -23,6 +37,8 @@ mod registry;
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    #[command(flatten)]
+    config_args: ConfigArgs,
 }
 
 #[derive(Subcommand)]
@@ -44,12 +60,100 @@ enum Commands {
 
     Download(DownloadArgs),
 
-    Registry {},
+    Export(ExportArgs),
+
+    /// Upload a downloaded model's layers and hashes to the registry
+    Push(PushArgs),
+
+    List {},
+
+    /// Forget a downloaded model, or one revision of it with `<MODEL_ID>@<REVISION>`. Run `gc` afterwards to free up
+    /// the space its layers took up.
+    Rm(RmArgs),
+
+    /// Delete the layers and files that no downloaded model uses anymore
+    Gc(GcArgs),
+
+    /// Check that every layer and file in the store is intact, and that no downloaded model is missing any
+    Fsck(FsckArgs),
+
+    #[command(subcommand)]
+    Store(StoreCommands),
+
+    /// Serve layer hashes over HTTP
+    Registry {
+        #[command(subcommand)]
+        command: Option<RegistryCommands>,
+        /// Address to listen on, such as `0.0.0.0:3000`
+        #[arg(long)]
+        listen: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RegistryCommands {
+    /// Add the hashes files in the results folder to the registry's index
+    Import {},
+}
+
+#[derive(Subcommand)]
+enum StoreCommands {
+    /// Compare the space the local store takes up against the uncompressed layers
+    Stats {},
 }
 
 #[derive(Args)]
 struct DownloadArgs {
     model_id: String,
+    /// How many times a failed range request is retried before giving up on a layer
+    #[arg(long, default_value_t = RetryPolicy::default().max_retries)]
+    max_retries: u32,
+    /// Only download the other files of the repo that match these glob patterns, such as `*.json`
+    #[arg(long)]
+    include: Vec<String>,
+    /// Skip the other files of the repo that match these glob patterns
+    #[arg(long, default_values_t = download::DEFAULT_EXCLUDE_PATTERNS.map(String::from))]
+    exclude: Vec<String>,
+    /// Upload the hashes of any layers the registry did not know about to it
+    #[arg(long)]
+    publish: bool,
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    model_id: String,
+    #[arg(long)]
+    out: String,
+}
+
+#[derive(Args)]
+struct RmArgs {
+    model_id: String,
+}
+
+#[derive(Args)]
+struct GcArgs {
+    /// Only show how much space would be freed up
+    #[arg(long)]
+    dry_run: bool,
+    /// Also delete the partial files of interrupted downloads, which would otherwise resume from them
+    #[arg(long)]
+    partials: bool,
+}
+
+#[derive(Args)]
+struct FsckArgs {
+    /// Move bad files into the `quarantine` folder of the store
+    #[arg(long)]
+    quarantine: bool,
+    /// Download bad and missing layers and files again, after quarantining the bad ones
+    #[arg(long)]
+    refetch: bool,
+}
+
+#[derive(Args)]
+struct PushArgs {
+    model_id: String,
 }
 
 #[derive(Args)]
@@ -65,26 +169,114 @@ struct HashSingleModelArgs {
 
 fn main() {
     let cli = Cli::parse();
+    let mut config =
+        Config::load(&cli.config_args).unwrap_or_else(|e| panic!("Unable to load config: {}", e));
 
     match &cli.command {
         Some(Commands::HashingExperiment {}) => {
-            run_hashing_experiment();
+            run_hashing_experiment(&config);
         }
         Some(Commands::HashSingleModel(hash_single_model_args)) => {
-            generate_hashes_by_model_id(&hash_single_model_args.model_id);
+            generate_hashes_by_model_id(&config, &hash_single_model_args.model_id);
         }
         Some(Commands::Compare { a, b }) => {
             compare::compare_tensors_between_files(a, b);
         }
         Some(Commands::CompareHashes(compare_hashes_args)) => compare::compare_hashes_via_registry(
+            &config,
             &compare_hashes_args.model_id_a,
             &compare_hashes_args.model_id_b,
         ),
         Some(Commands::Download(download_args)) => {
             // Download safetensor files one at a time, parallelising layers of the same file.
-            // Known issue: using this will not create an equivalent file to that available on huggingface due to
-            // differences in how the json header is formatted, however it will create a valid safetensors file.
-            download::download_safetensors_file_by_model_id(&download_args.model_id)
+            // The original header is kept in the manifest so that exporting recreates the exact file on huggingface.
+            let (model_id, revision) = hf::parse_model_id_and_revision(&download_args.model_id);
+            let file_filter =
+                download::FileFilter::new(&download_args.include, &download_args.exclude)
+                    .unwrap_or_else(|e| panic!("Invalid file pattern: {}", e));
+            let download_options = download::DownloadOptions {
+                codec: config.compression,
+                retry_policy: RetryPolicy {
+                    max_retries: download_args.max_retries,
+                    ..RetryPolicy::default()
+                },
+                file_filter,
+                publish: download_args.publish,
+            };
+            download::download_safetensors_file_by_model_id(
+                &config,
+                model_id,
+                revision,
+                &download_options,
+            )
+        }
+        Some(Commands::Export(export_args)) => {
+            let (model_id, revision) = hf::parse_model_id_and_revision(&export_args.model_id);
+            if let Err(e) = export::export_model_by_model_id(
+                model_id,
+                revision,
+                &config.store_dir,
+                &export_args.out,
+            ) {
+                println!("Export failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Fsck(fsck_args)) => {
+            let fsck_options = fsck::FsckOptions {
+                quarantine: fsck_args.quarantine,
+                refetch: fsck_args.refetch,
+            };
+            if fsck::check_store(&config, fsck_options) {
+                println!("The store is healthy");
+            } else {
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Push(push_args)) => {
+            let (model_id, revision) = hf::parse_model_id_and_revision(&push_args.model_id);
+            if let Err(e) = push::push_model_by_model_id(&config, model_id, revision) {
+                println!("Push failed: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::List {}) => list_downloaded_models(&config.store_dir),
+        Some(Commands::Rm(rm_args)) => {
+            // Without a revision every revision is removed, rather than only main
+            let (model_id, revision) = hf::parse_model_id_and_revision(&rm_args.model_id);
+            let revision = rm_args.model_id.contains('@').then_some(revision);
+            match manifest::remove_model(&config.store_dir, model_id, revision) {
+                Ok(true) => println!(
+                    "Removed {}, run `cake gc` to delete the layers it no longer needs",
+                    rm_args.model_id
+                ),
+                Ok(false) => println!("{} has not been downloaded", rm_args.model_id),
+                Err(e) => panic!("Unable to remove {}: {}", rm_args.model_id, e),
+            }
+        }
+        Some(Commands::Gc(gc_args)) => {
+            let (blob_count, blob_bytes) =
+                store::collect_garbage(&config.store_dir, gc_args.dry_run, gc_args.partials)
+                    .unwrap_or_else(|e| panic!("Unable to collect garbage: {}", e));
+            let what = if gc_args.partials {
+                "blobs and partial downloads"
+            } else {
+                "blobs"
+            };
+            if gc_args.dry_run {
+                println!(
+                    "{} {} are no longer used, deleting them would free up {} bytes",
+                    blob_count, what, blob_bytes
+                );
+            } else {
+                println!(
+                    "Deleted {} {}, freeing up {} bytes",
+                    blob_count, what, blob_bytes
+                );
+            }
+        }
+        Some(Commands::Store(StoreCommands::Stats {})) => {
+            store::print_store_stats(&config.store_dir)
         }
         Some(Commands::CheckModels {}) => {
             // Get the model ids and file names from the JSON file
@@ -109,8 +301,11 @@ fn main() {
                         file_name
                     );
                     let model_parts: Vec<&str> = model_id.split('/').collect();
-                    let hashes_file_path =
-                        get_hashes_file_dir_and_path(model_parts[0], model_parts[1]);
+                    let hashes_file_path = get_hashes_file_dir_and_path(
+                        &config.results_dir,
+                        model_parts[0],
+                        model_parts[1],
+                    );
                     let hashes_file_path_clone = hashes_file_path.1.clone();
                     let hashes_file_exists = fs::metadata(hashes_file_path_clone).is_ok();
                     if hashes_file_exists {
@@ -119,28 +314,73 @@ fn main() {
                     }
                     // Download just the header and try to parse it
                     let url = &download::get_download_url_from_model_id(
+                        config.hf_endpoint(),
                         model_id,
+                        hf::DEFAULT_REVISION,
                         file_name.as_str().unwrap(),
                     );
-                    download::download_safetensors_header(url);
+                    if let Err(e) = download::download_safetensors_header(url) {
+                        println!("Unable to download the header of {}: {}", model_id, e);
+                    }
                 }
             }
         }
-        Some(Commands::Registry {}) => {
-            registry::run_registry();
+        Some(Commands::Registry {
+            command: Some(RegistryCommands::Import {}),
+            ..
+        }) => {
+            let mut index = index::Index::open(Path::new(&config.registry_index)).unwrap();
+            let (imported_count, skipped_count) =
+                registry::import_results(&mut index, Path::new(&config.results_dir)).unwrap();
+            println!(
+                "Imported {} hashes files into {}, {} skipped",
+                imported_count, config.registry_index, skipped_count
+            );
+        }
+        Some(Commands::Registry {
+            command: None,
+            listen,
+        }) => {
+            if let Some(listen) = listen {
+                config.registry_listen_addr.clone_from(listen);
+            }
+            registry::run_registry(&config);
         }
         None => {}
     }
 }
 
-fn generate_hashes_by_model_id(model_id: &str) {
-    let model_info_result = hf::get_model_info(model_id);
+fn list_downloaded_models(storage_dir: &str) {
+    let manifests = manifest::load_all_manifests(storage_dir);
+    if manifests.is_empty() {
+        println!("No models have been downloaded yet");
+        return;
+    }
+
+    for model_manifest in manifests {
+        let model_file_size = model_manifest.file_size();
+        println!(
+            "{}@{}: {} ({} tensors, {} bytes)",
+            model_manifest.model_id,
+            model_manifest.revision,
+            model_manifest.file_name,
+            model_manifest.tensors.len(),
+            model_file_size
+        );
+    }
+}
+
+fn generate_hashes_by_model_id(config: &Config, model_id: &str) {
+    let model_info_result =
+        hf::get_model_info(config.hf_endpoint(), model_id, hf::DEFAULT_REVISION);
     if model_info_result.is_err() {
         // TODO: Handle better, print the error message too
         panic!("Unable to retrieve model info when attempting download!")
     }
 
     let model_info = model_info_result.unwrap();
+    // Every file and index is read from the same commit, in case the branch moves while the model is hashed
+    let commit_sha = model_info.get_commit_sha(hf::DEFAULT_REVISION);
 
     let model_filenames: Vec<&String> = model_info
         .siblings
@@ -155,7 +395,8 @@ fn generate_hashes_by_model_id(model_id: &str) {
         .collect();
 
     let model_parts: Vec<&str> = model_id.split('/').collect();
-    let hashes_file_path = get_hashes_file_dir_and_path(model_parts[0], model_parts[1]);
+    let hashes_file_path =
+        get_hashes_file_dir_and_path(&config.results_dir, model_parts[0], model_parts[1]);
     let hashes_file_path_clone = hashes_file_path.1.clone();
     let target_path = hashes_file_path_clone.clone();
     let hashes_file_exists = fs::metadata(hashes_file_path_clone).is_ok();
@@ -167,7 +408,7 @@ fn generate_hashes_by_model_id(model_id: &str) {
     // Download each file separately and then merge the results if there are multiple files
     let mut file_index = 0;
     let file_count = safetensors_filenames.len();
-    let mut file_results: Vec<Map<String, Value>> = Vec::new();
+    let mut file_results: Vec<ModelHashes> = Vec::new();
     for file_name in safetensors_filenames {
         // For now skip adapter models as they are not being parsed correctly
         if file_name.contains("adapter_model") {
@@ -184,7 +425,14 @@ fn generate_hashes_by_model_id(model_id: &str) {
             file_count,
             file_name,
         );
-        let hashed_layers_result = download_and_hash_layers(model_id, file_name);
+        let hashed_layers_result =
+            match download_and_hash_layers(config, model_id, &commit_sha, file_name) {
+                Ok(hashed_layers_result) => hashed_layers_result,
+                Err(e) => {
+                    println!("{} skipped due to a download error: {}", model_id, e);
+                    continue;
+                }
+            };
         // If no results are returned, skip this file
         if hashed_layers_result.is_empty() {
             println!("{} skipped due to invalid header length", model_id);
@@ -194,16 +442,32 @@ fn generate_hashes_by_model_id(model_id: &str) {
         file_index += 1;
     }
 
-    // Merge the results together if there are multiple
-    let mut output_result: Map<String, Value> = Map::new();
-    if file_results.len() == 1 {
-        // TODO: Not sure if there's an idiomatic way to avoid this clone
-        output_result.clone_from(file_results.first().unwrap())
-    } else {
-        for result in file_results.iter() {
-            for (key, value) in result {
-                // TODO: Another clone which feels like it can be avoided
-                output_result.insert(key.to_string(), value.clone());
+    // The shards of a model are one set of tensors, which their index has to agree with
+    let model_hashes = match shards::merge_file_hashes(file_results) {
+        Ok(model_hashes) => model_hashes,
+        Err(e) => {
+            println!("{} skipped as its files cannot be merged: {}", model_id, e);
+            return;
+        }
+    };
+    let index_file_names: Vec<&String> = model_info
+        .siblings
+        .iter()
+        .map(|s| &s.rfilename)
+        .filter(|f| shards::is_index_file(f))
+        .collect();
+    if !index_file_names.is_empty() {
+        let (fetcher, _) = download::get_fetchers(config, RetryPolicy::default());
+        let shard_tensors = shards::get_shard_tensors(&model_hashes);
+        for index_file_name in index_file_names {
+            let validation_result =
+                download::get_shard_index(config, model_id, &commit_sha, index_file_name, &fetcher)
+                    .and_then(|index| Ok(index.validate(index_file_name, &shard_tensors)?));
+            if let Err(e) = validation_result {
+                println!(
+                    "WARNING: {} does not agree with the shards of {}: {}",
+                    index_file_name, model_id, e
+                );
             }
         }
     }
@@ -211,10 +475,10 @@ fn generate_hashes_by_model_id(model_id: &str) {
     fs::create_dir_all(hashes_file_path.0).unwrap();
     let file = File::create(hashes_file_path.1).unwrap();
     println!("Outputting hash results to {}...", target_path);
-    serde_json::to_writer_pretty(file, &output_result).unwrap();
+    serde_json::to_writer_pretty(file, &model_hashes).unwrap();
 }
 
-fn run_hashing_experiment() {
+fn run_hashing_experiment(config: &Config) {
     // Get the model ids and file names from the JSON file
     let mut file = File::open("safetensor-models-text-gen.json").unwrap();
     let mut models_json_str = String::new();
@@ -237,7 +501,8 @@ fn run_hashing_experiment() {
         }
 
         let model_parts: Vec<&str> = model_id.split('/').collect();
-        let hashes_file_path = get_hashes_file_dir_and_path(model_parts[0], model_parts[1]);
+        let hashes_file_path =
+            get_hashes_file_dir_and_path(&config.results_dir, model_parts[0], model_parts[1]);
         let hashes_file_path_clone = hashes_file_path.1.clone();
         let hashes_file_exists = fs::metadata(hashes_file_path_clone).is_ok();
         if hashes_file_exists {
@@ -246,7 +511,7 @@ fn run_hashing_experiment() {
         }
 
         // Download each file separately and then merge the results if there are multiple files
-        let mut file_results: Vec<Map<String, Value>> = Vec::new();
+        let mut file_results: Vec<ModelHashes> = Vec::new();
         for file_name_val in file_names.as_array().unwrap() {
             let file_name = file_name_val.as_str().unwrap().to_string();
             // For now skip adapter models as they are not being parsed correctly
@@ -264,7 +529,18 @@ fn run_hashing_experiment() {
                 file_names.as_array().unwrap().len(),
                 file_name,
             );
-            let hashed_layers_result = download_and_hash_layers(model_id, &file_name);
+            let hashed_layers_result = match download_and_hash_layers(
+                config,
+                model_id,
+                hf::DEFAULT_REVISION,
+                &file_name,
+            ) {
+                Ok(hashed_layers_result) => hashed_layers_result,
+                Err(e) => {
+                    println!("{} skipped due to a download error: {}", model_id, e);
+                    continue;
+                }
+            };
             // If no results are returned, skip this file
             if hashed_layers_result.is_empty() {
                 println!("{} skipped due to invalid header length", model_id);
@@ -274,32 +550,29 @@ fn run_hashing_experiment() {
             file_index += 1;
         }
 
-        // Merge the results together if there are multiple
-        let mut output_result: Map<String, Value> = Map::new();
-        if file_results.len() == 1 {
-            // TODO: Not sure if there's an idiomatic way to avoid this clone
-            output_result.clone_from(file_results.first().unwrap())
-        } else {
-            for result in file_results.iter() {
-                for (key, value) in result {
-                    // TODO: Another clone which feels like it can be avoided
-                    output_result.insert(key.to_string(), value.clone());
-                }
+        let model_hashes = match shards::merge_file_hashes(file_results) {
+            Ok(model_hashes) => model_hashes,
+            Err(e) => {
+                println!("{} skipped as its files cannot be merged: {}", model_id, e);
+                continue;
             }
-        }
+        };
 
         fs::create_dir_all(hashes_file_path.0).unwrap();
         let file = File::create(hashes_file_path.1).unwrap();
         println!("Outputting hash results...");
-        serde_json::to_writer_pretty(file, &output_result).unwrap();
+        serde_json::to_writer_pretty(file, &model_hashes).unwrap();
     }
 }
 
 #[derive(Debug, Eq, Ord, PartialEq, PartialOrd, Clone)]
 struct Layer {
     name: String,
+    /// Offsets relative to the start of the tensor data, as in the header
     offset_start: u64,
     offset_end: u64,
+    /// Where the tensor starts in the file, which is what has to be requested
+    file_offset: u64,
     size: u64,
 }
 
@@ -309,17 +582,26 @@ struct LayerMetadata {
     size: u64,
 }
 
-fn download_and_hash_layers(model_id: &str, file_name: &str) -> Map<String, Value> {
-    // Create a new map and insert processed entries
-    let mut result_obj: Map<String, Value> = Map::new();
+fn download_and_hash_layers(
+    config: &Config,
+    model_id: &str,
+    revision: &str,
+    file_name: &str,
+) -> Result<ModelHashes, DownloadError> {
+    let mut layer_hashes = ModelHashes::new();
 
     // Get the header of the model
-    let url = download::get_download_url_from_model_id(model_id, file_name);
-    let (header, header_length) = download::download_safetensors_header(&url);
-    if header_length == 0 {
+    let url = download::get_download_url_from_model_id(
+        config.hf_endpoint(),
+        model_id,
+        revision,
+        file_name,
+    );
+    let (header, header_bytes) = download::download_safetensors_header(&url)?;
+    if header_bytes.is_empty() {
         println!("No header returned!");
         println!("{}", header);
-        return result_obj;
+        return Ok(layer_hashes);
     }
 
     // Setup the progress bars
@@ -334,47 +616,59 @@ fn download_and_hash_layers(model_id: &str, file_name: &str) -> Map<String, Valu
     let mp: MultiProgress = MultiProgress::new();
     mp.add(main_bar);
 
-    let layers_metadata: Vec<LayerMetadata> = download::par_download_layers(header, url, None, mp)
-        .map(|(layer, tensor)| {
-            // Perform the hashing
-            main_bar_clone.set_message(format!("Hashing: {}", layer.name));
-            let hash = hasher::sha256_hash(&tensor);
-            main_bar_clone.inc(1);
-            main_bar_clone.set_message("Waiting...");
-
-            LayerMetadata {
-                layer,
-                hash,
-                size: tensor.len() as u64,
-            }
+    let (fetcher, _) = download::get_fetchers(config, RetryPolicy::default());
+    let layers_metadata: Vec<LayerMetadata> = download::hash_layers(
+        &header,
+        header_bytes.len() as u64,
+        &url,
+        &fetcher,
+        config.download_jobs,
+        mp,
+        |_, _| main_bar_clone.inc(1),
+    )?
+    .into_iter()
+    .map(|(layer, hash)| {
+        Ok(LayerMetadata {
+            size: layer.size,
+            layer,
+            hash: hash?,
         })
-        .collect();
+    })
+    .collect::<Result<Vec<LayerMetadata>, DownloadError>>()?;
 
     main_bar_clone.finish_with_message("All done!");
 
     for layer_metadata in layers_metadata {
-        let tensor_result = json!({
-            "data_offsets": vec![layer_metadata.layer.offset_start, layer_metadata.layer.offset_end],
-            "hash": layer_metadata.hash,
-            "size": layer_metadata.size,
-            "file_name": file_name,
-        });
-        result_obj.insert(layer_metadata.layer.name, tensor_result);
+        layer_hashes.insert(
+            layer_metadata.layer.name,
+            LayerHash {
+                data_offsets: [
+                    layer_metadata.layer.offset_start,
+                    layer_metadata.layer.offset_end,
+                ],
+                hash: layer_metadata.hash,
+                size: layer_metadata.size,
+                file_name: file_name.to_string(),
+            },
+        );
     }
 
-    result_obj
+    Ok(layer_hashes)
 }
 
-fn get_hashes_file_dir_and_path(model_account: &str, model_name: &str) -> (String, String) {
+fn get_hashes_file_dir_and_path(
+    results_dir: &str,
+    model_account: &str,
+    model_name: &str,
+) -> (String, String) {
+    // Relative results folders are resolved against the current directory, absolute ones are used as is
     let abs_dir: String = env::current_dir()
         .unwrap()
+        .join(results_dir)
+        .join(model_account)
+        .join(model_name)
         .to_string_lossy()
-        .into_owned()
-        .clone()
-        + "/results/"
-        + model_account
-        + "/"
-        + model_name;
+        .into_owned();
     let target_file_path = abs_dir.to_owned() + "/hashes.json";
 
     (abs_dir, target_file_path)
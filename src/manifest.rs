@@ -0,0 +1,430 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::store;
+
+/// Records which blobs in the layer store make up a single safetensors file of a model.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct Manifest {
+    pub model_id: String,
+    pub revision: String,
+    pub file_name: String,
+    /// The JSON header exactly as it was read from the original file, including any padding
+    pub header: String,
+    pub tensors: BTreeMap<String, TensorEntry>,
+    /// SHA-256 of the original file as published by Hugging Face, used to verify exports
+    #[serde(default)]
+    pub lfs_sha256: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct TensorEntry {
+    pub hash: String,
+    pub data_offsets: [u64; 2],
+    pub size: u64,
+}
+
+/// Records which blobs make up the files of a model that are not safetensors, such as its config and tokenizer.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct FilesManifest {
+    pub model_id: String,
+    pub revision: String,
+    pub files: BTreeMap<String, FileEntry>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct FileEntry {
+    pub hash: String,
+    pub size: u64,
+}
+
+impl Manifest {
+    /// Builds a manifest from a parsed header and the hashes of its layers.
+    /// Returns None if any tensor in the header does not have a hash or ends before it starts, or the header is not
+    /// valid UTF-8.
+    pub fn from_header(
+        model_id: &str,
+        revision: &str,
+        file_name: &str,
+        header_bytes: &[u8],
+        header: &Value,
+        layers_to_hashes_map: &HashMap<String, String>,
+    ) -> Option<Manifest> {
+        let mut tensors: BTreeMap<String, TensorEntry> = BTreeMap::new();
+
+        for (name, metadata) in header.as_object()? {
+            if name == "__metadata__" {
+                continue;
+            }
+            let offsets = metadata.get("data_offsets").and_then(Value::as_array)?;
+            let offset_start = offsets.first()?.as_u64()?;
+            let offset_end = offsets.get(1)?.as_u64()?;
+            let size = offset_end.checked_sub(offset_start)?;
+            let hash = layers_to_hashes_map.get(name)?;
+
+            tensors.insert(
+                name.to_string(),
+                TensorEntry {
+                    hash: hash.to_string(),
+                    data_offsets: [offset_start, offset_end],
+                    size,
+                },
+            );
+        }
+
+        Some(Manifest {
+            model_id: model_id.to_string(),
+            revision: revision.to_string(),
+            file_name: file_name.to_string(),
+            header: String::from_utf8(header_bytes.to_vec()).ok()?,
+            tensors,
+            lfs_sha256: None,
+        })
+    }
+
+    /// Size of the original safetensors file: the header length, the header and the tensor data
+    pub fn file_size(&self) -> u64 {
+        let data_size = self
+            .tensors
+            .values()
+            .map(|t| t.data_offsets[1])
+            .max()
+            .unwrap_or(0);
+
+        8 + self.header.len() as u64 + data_size
+    }
+}
+
+pub fn get_manifests_dir(storage_dir: &str, model_id: &str, revision: &str) -> PathBuf {
+    let mut manifests_dir = PathBuf::new();
+    manifests_dir.push(storage_dir);
+    manifests_dir.push("manifests");
+    manifests_dir.push(model_id);
+    manifests_dir.push(revision);
+
+    manifests_dir
+}
+
+fn get_manifest_path(
+    storage_dir: &str,
+    model_id: &str,
+    revision: &str,
+    file_name: &str,
+) -> PathBuf {
+    get_manifests_dir(storage_dir, model_id, revision).join(format!("{}.json", file_name))
+}
+
+pub fn save_manifest(storage_dir: &str, manifest: &Manifest) {
+    let manifest_path = get_manifest_path(
+        storage_dir,
+        &manifest.model_id,
+        &manifest.revision,
+        &manifest.file_name,
+    );
+    fs::create_dir_all(manifest_path.parent().unwrap()).unwrap();
+
+    let manifest_json = serde_json::to_vec_pretty(manifest).unwrap();
+    store::write_file_atomically(&manifest_path, &manifest_json).unwrap();
+}
+
+fn get_files_manifest_path(storage_dir: &str, model_id: &str, revision: &str) -> PathBuf {
+    let mut files_manifest_path = PathBuf::new();
+    files_manifest_path.push(storage_dir);
+    files_manifest_path.push("files");
+    files_manifest_path.push(model_id);
+    files_manifest_path.push(format!("{}.json", revision));
+
+    files_manifest_path
+}
+
+pub fn save_files_manifest(storage_dir: &str, files_manifest: &FilesManifest) {
+    let files_manifest_path = get_files_manifest_path(
+        storage_dir,
+        &files_manifest.model_id,
+        &files_manifest.revision,
+    );
+    fs::create_dir_all(files_manifest_path.parent().unwrap()).unwrap();
+
+    let files_manifest_json = serde_json::to_vec_pretty(files_manifest).unwrap();
+    store::write_file_atomically(&files_manifest_path, &files_manifest_json).unwrap();
+}
+
+/// Loads the non-safetensors files of a model, or an empty manifest if none have been downloaded
+pub fn load_files_manifest(storage_dir: &str, model_id: &str, revision: &str) -> FilesManifest {
+    let files_manifest_path = get_files_manifest_path(storage_dir, model_id, revision);
+    let files_manifest: Option<FilesManifest> = File::open(files_manifest_path)
+        .ok()
+        .and_then(|file| serde_json::from_reader(file).ok());
+
+    files_manifest.unwrap_or_else(|| FilesManifest {
+        model_id: model_id.to_string(),
+        revision: revision.to_string(),
+        files: BTreeMap::new(),
+    })
+}
+
+fn get_revision_ref_path(storage_dir: &str, model_id: &str, revision: &str) -> PathBuf {
+    let mut ref_path = PathBuf::new();
+    ref_path.push(storage_dir);
+    ref_path.push("refs");
+    ref_path.push(model_id);
+    ref_path.push(revision);
+
+    ref_path
+}
+
+/// Records which commit a branch or tag pointed to when it was downloaded, similar to a git ref
+pub fn save_revision_ref(storage_dir: &str, model_id: &str, revision: &str, commit_sha: &str) {
+    if revision == commit_sha {
+        return;
+    }
+
+    let ref_path = get_revision_ref_path(storage_dir, model_id, revision);
+    fs::create_dir_all(ref_path.parent().unwrap()).unwrap();
+    store::write_file_atomically(&ref_path, commit_sha.as_bytes()).unwrap();
+}
+
+/// Resolves a branch or tag to the commit sha it pointed to when it was downloaded.
+/// Commit shas, and revisions that were never downloaded, are returned unchanged.
+pub fn resolve_revision(storage_dir: &str, model_id: &str, revision: &str) -> String {
+    let ref_path = get_revision_ref_path(storage_dir, model_id, revision);
+    match fs::read_to_string(ref_path) {
+        Ok(commit_sha) => commit_sha.trim().to_string(),
+        Err(_) => revision.to_string(),
+    }
+}
+
+/// Loads every manifest stored for a model at the given revision, sorted by file name.
+pub fn load_manifests(storage_dir: &str, model_id: &str, revision: &str) -> Vec<Manifest> {
+    let manifests_dir = get_manifests_dir(storage_dir, model_id, revision);
+
+    let mut manifests: Vec<Manifest> = Vec::new();
+    collect_manifests(&manifests_dir, &mut manifests);
+    manifests.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+
+    manifests
+}
+
+/// Loads every manifest in the store, across all models and revisions.
+pub fn load_all_manifests(storage_dir: &str) -> Vec<Manifest> {
+    let mut manifests_dir = PathBuf::new();
+    manifests_dir.push(storage_dir);
+    manifests_dir.push("manifests");
+
+    let mut manifests: Vec<Manifest> = Vec::new();
+    collect_manifests(&manifests_dir, &mut manifests);
+    manifests.sort_by(|a, b| {
+        (&a.model_id, &a.revision, &a.file_name).cmp(&(&b.model_id, &b.revision, &b.file_name))
+    });
+
+    manifests
+}
+
+/// Loads the non-safetensors files manifests of every model and revision in the store
+pub fn load_all_files_manifests(storage_dir: &str) -> Vec<FilesManifest> {
+    let mut files_manifests_dir = PathBuf::new();
+    files_manifests_dir.push(storage_dir);
+    files_manifests_dir.push("files");
+
+    let mut files_manifests: Vec<FilesManifest> = Vec::new();
+    collect_manifests(&files_manifests_dir, &mut files_manifests);
+
+    files_manifests
+}
+
+/// Forgets a model, so the layers only it used can be garbage collected. Only the given revision is forgotten if
+/// there is one, otherwise every revision of the model is. Returns whether there was anything to forget.
+pub fn remove_model(storage_dir: &str, model_id: &str, revision: Option<&str>) -> io::Result<bool> {
+    let Some(revision) = revision else {
+        let mut removed = false;
+        for folder in ["manifests", "files", "refs"] {
+            removed |= remove_if_exists(&Path::new(storage_dir).join(folder).join(model_id))?;
+        }
+        return Ok(removed);
+    };
+
+    let commit_sha = resolve_revision(storage_dir, model_id, revision);
+    let mut removed = remove_if_exists(&get_manifests_dir(storage_dir, model_id, &commit_sha))?;
+    removed |= remove_if_exists(&get_files_manifest_path(storage_dir, model_id, &commit_sha))?;
+
+    // Branches and tags that pointed to the commit would point to nothing otherwise
+    let mut ref_paths: Vec<PathBuf> = Vec::new();
+    collect_files(
+        &Path::new(storage_dir).join("refs").join(model_id),
+        &mut ref_paths,
+    );
+    for ref_path in ref_paths {
+        if fs::read_to_string(&ref_path).is_ok_and(|ref_sha| ref_sha.trim() == commit_sha) {
+            removed |= remove_if_exists(&ref_path)?;
+        }
+    }
+
+    Ok(removed)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<bool> {
+    let result = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn collect_files(dir: &Path, file_paths: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            // Revisions can contain folders, such as `refs/pr/1`
+            collect_files(&path, file_paths);
+        } else {
+            file_paths.push(path);
+        }
+    }
+}
+
+fn collect_manifests<T: DeserializeOwned>(dir: &Path, manifests: &mut Vec<T>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            // File names can contain folders, such as `unet/diffusion_pytorch_model.safetensors`
+            collect_manifests(&path, manifests);
+        } else if path.extension().is_some_and(|ext| ext == "json") {
+            let file = File::open(&path).unwrap();
+            match serde_json::from_reader(file) {
+                Ok(manifest) => manifests.push(manifest),
+                Err(e) => println!("Skipping invalid manifest {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_remove_model_revision() {
+        let storage_dir = std::env::temp_dir().join(format!(
+            "cake-test-manifest-remove-{:016x}",
+            rand::random::<u64>()
+        ));
+        let storage_dir = storage_dir.to_str().unwrap();
+
+        for commit_sha in ["abc123", "def456"] {
+            save_manifest(
+                storage_dir,
+                &Manifest {
+                    model_id: "org/model".to_string(),
+                    revision: commit_sha.to_string(),
+                    file_name: "model.safetensors".to_string(),
+                    header: "{}".to_string(),
+                    tensors: BTreeMap::new(),
+                    lfs_sha256: None,
+                },
+            );
+        }
+        save_revision_ref(storage_dir, "org/model", "main", "abc123");
+        save_revision_ref(storage_dir, "org/model", "refs/pr/1", "def456");
+
+        assert!(remove_model(storage_dir, "org/model", Some("main")).unwrap());
+        assert!(load_manifests(storage_dir, "org/model", "abc123").is_empty());
+        assert_eq!(resolve_revision(storage_dir, "org/model", "main"), "main");
+        assert_eq!(load_manifests(storage_dir, "org/model", "def456").len(), 1);
+        assert_eq!(
+            resolve_revision(storage_dir, "org/model", "refs/pr/1"),
+            "def456"
+        );
+
+        assert!(remove_model(storage_dir, "org/model", None).unwrap());
+        assert!(load_all_manifests(storage_dir).is_empty());
+        assert!(!remove_model(storage_dir, "org/model", None).unwrap());
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_from_header() {
+        let header_bytes = br#"{"__metadata__":{"format":"pt"},"b":{"dtype":"F16","shape":[2],"data_offsets":[4,8]},"a":{"dtype":"F16","shape":[2],"data_offsets":[0,4]}}"#;
+        let header: Value = serde_json::from_slice(header_bytes).unwrap();
+        let layers_to_hashes_map = HashMap::from([
+            ("a".to_string(), "hash_a".to_string()),
+            ("b".to_string(), "hash_b".to_string()),
+        ]);
+
+        let manifest = Manifest::from_header(
+            "org/model",
+            "main",
+            "model.safetensors",
+            header_bytes,
+            &header,
+            &layers_to_hashes_map,
+        )
+        .unwrap();
+
+        assert_eq!(manifest.header.as_bytes(), header_bytes);
+        assert_eq!(manifest.tensors.len(), 2);
+        assert_eq!(
+            manifest.tensors.get("b"),
+            Some(&TensorEntry {
+                hash: "hash_b".to_string(),
+                data_offsets: [4, 8],
+                size: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn test_manifest_from_header_with_missing_hash() {
+        let header_bytes = br#"{"a":{"dtype":"F16","shape":[2],"data_offsets":[0,4]}}"#;
+        let header: Value = serde_json::from_slice(header_bytes).unwrap();
+
+        let manifest = Manifest::from_header(
+            "org/model",
+            "main",
+            "model.safetensors",
+            header_bytes,
+            &header,
+            &HashMap::new(),
+        );
+
+        assert_eq!(manifest, None);
+    }
+
+    #[test]
+    fn test_manifest_from_header_with_reversed_offsets() {
+        let header_bytes = br#"{"a":{"dtype":"F16","shape":[2],"data_offsets":[4,0]}}"#;
+        let header: Value = serde_json::from_slice(header_bytes).unwrap();
+
+        let manifest = Manifest::from_header(
+            "org/model",
+            "main",
+            "model.safetensors",
+            header_bytes,
+            &header,
+            &HashMap::from([("a".to_string(), "a".repeat(64))]),
+        );
+
+        assert_eq!(manifest, None);
+    }
+}
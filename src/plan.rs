@@ -0,0 +1,333 @@
+use crate::byteranges::ByteRange;
+
+/// Layers are downloaded with a single request while it stays under this size, so small tensors such as layernorms
+/// and biases do not each cost a round trip
+const MAX_COALESCED_SIZE: u64 = 16 * 1024 * 1024;
+/// Layers that are not next to each other are downloaded with a single request for several ranges, up to this many so
+/// the `Range` header stays a reasonable size
+const MAX_RANGES_PER_REQUEST: usize = 32;
+/// Each layer of a request has a partial file that stays open for the whole request, so a request covers at most this
+/// many layers, however small they are, to keep clear of the limit on open files
+const MAX_PARTS_PER_REQUEST: usize = 32;
+/// Layers with more than this left to download are split into pieces of this size, which are downloaded in parallel
+const SPLIT_PIECE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// What is left to download of a layer
+#[derive(Debug, Clone, Copy)]
+pub struct RemainingLayer {
+    /// Where the layer starts in the file
+    pub file_offset: u64,
+    pub size: u64,
+    /// How much of the start of the layer has already been downloaded
+    pub downloaded_bytes: u64,
+}
+
+impl RemainingLayer {
+    fn remaining_bytes(&self) -> u64 {
+        self.size.saturating_sub(self.downloaded_bytes)
+    }
+
+    /// Where in the file the bytes that are left start
+    fn remaining_file_offset(&self) -> u64 {
+        self.file_offset + self.downloaded_bytes
+    }
+}
+
+/// The bytes of a range request that belong to a single layer
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangePart {
+    /// Which of the planned layers the bytes belong to
+    pub layer_index: usize,
+    /// Which piece of the layer this is if the layer is split, in which case the piece has a partial file of its own
+    pub piece: Option<usize>,
+    /// Where the part starts within the layer
+    pub layer_offset: u64,
+    pub size: u64,
+}
+
+/// A single ranged GET, whose ranges are split between the parts in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeRequest {
+    pub ranges: Vec<ByteRange>,
+    pub parts: Vec<RangePart>,
+}
+
+impl RangeRequest {
+    pub fn size(&self) -> u64 {
+        self.ranges.iter().map(|range| range.size).sum()
+    }
+}
+
+/// Decides which byte ranges to request for a set of layers
+#[derive(Debug, Clone, Copy)]
+pub struct RangePlanner {
+    pub max_coalesced_size: u64,
+    pub max_ranges_per_request: usize,
+    pub max_parts_per_request: usize,
+    /// Layers that are split always use the same pieces, so each piece can be resumed
+    pub split_piece_size: u64,
+}
+
+impl Default for RangePlanner {
+    fn default() -> Self {
+        RangePlanner {
+            max_coalesced_size: MAX_COALESCED_SIZE,
+            max_ranges_per_request: MAX_RANGES_PER_REQUEST,
+            max_parts_per_request: MAX_PARTS_PER_REQUEST,
+            split_piece_size: SPLIT_PIECE_SIZE,
+        }
+    }
+}
+
+impl RangePlanner {
+    /// A planner that never splits layers, for when the bytes of a layer have to arrive in order
+    pub fn without_splitting() -> RangePlanner {
+        RangePlanner {
+            split_piece_size: u64::MAX,
+            ..RangePlanner::default()
+        }
+    }
+
+    /// Plans the requests that download whatever is left of the layers, largest first so that the biggest requests
+    /// are not left running on their own at the end. Layers with nothing left are not part of any request.
+    pub fn plan(&self, layers: &[RemainingLayer]) -> Vec<RangeRequest> {
+        let mut layer_indexes: Vec<usize> = (0..layers.len())
+            .filter(|i| layers[*i].remaining_bytes() > 0)
+            .collect();
+        layer_indexes.sort_by_key(|i| layers[*i].remaining_file_offset());
+
+        let mut requests: Vec<RangeRequest> = Vec::new();
+        let mut coalesced_request: Option<RangeRequest> = None;
+        for layer_index in layer_indexes {
+            let layer = &layers[layer_index];
+            let remaining_bytes = layer.remaining_bytes();
+
+            // Small layers on either side of a large one can still share a request for several ranges
+            if remaining_bytes > self.split_piece_size {
+                requests.extend(self.split_layer(layer_index, layer));
+                continue;
+            }
+
+            let part = RangePart {
+                layer_index,
+                piece: None,
+                layer_offset: layer.downloaded_bytes,
+                size: remaining_bytes,
+            };
+            let range = ByteRange {
+                start: layer.remaining_file_offset(),
+                size: remaining_bytes,
+            };
+            match &mut coalesced_request {
+                Some(request) if self.can_coalesce(request, &range) => {
+                    let last_range = request.ranges.last_mut().unwrap();
+                    if last_range.end() == range.start {
+                        last_range.size += range.size;
+                    } else {
+                        request.ranges.push(range);
+                    }
+                    request.parts.push(part);
+                }
+                _ => {
+                    requests.extend(coalesced_request.replace(RangeRequest {
+                        ranges: vec![range],
+                        parts: vec![part],
+                    }));
+                }
+            }
+        }
+        requests.extend(coalesced_request);
+
+        requests.sort_by_key(|request| std::cmp::Reverse(request.size()));
+        requests
+    }
+
+    /// Whether a range can be added to a request, either by extending its last range or as a range of its own
+    fn can_coalesce(&self, request: &RangeRequest, range: &ByteRange) -> bool {
+        let is_contiguous = request
+            .ranges
+            .last()
+            .is_some_and(|last_range| last_range.end() == range.start);
+
+        request.size() + range.size <= self.max_coalesced_size
+            && request.parts.len() < self.max_parts_per_request
+            && (is_contiguous || request.ranges.len() < self.max_ranges_per_request)
+    }
+
+    /// Splits what is left of a layer into pieces, counting from the first byte that is left
+    fn split_layer(&self, layer_index: usize, layer: &RemainingLayer) -> Vec<RangeRequest> {
+        let piece_count = layer.remaining_bytes().div_ceil(self.split_piece_size);
+
+        (0..piece_count)
+            .map(|piece| {
+                let layer_offset = layer.downloaded_bytes + piece * self.split_piece_size;
+                let size = self.split_piece_size.min(layer.size - layer_offset);
+                RangeRequest {
+                    ranges: vec![ByteRange {
+                        start: layer.file_offset + layer_offset,
+                        size,
+                    }],
+                    parts: vec![RangePart {
+                        layer_index,
+                        piece: Some(piece as usize),
+                        layer_offset,
+                        size,
+                    }],
+                }
+            })
+            .collect()
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn layer(file_offset: u64, size: u64, downloaded_bytes: u64) -> RemainingLayer {
+        RemainingLayer {
+            file_offset,
+            size,
+            downloaded_bytes,
+        }
+    }
+
+    #[test]
+    fn test_plan_coalesces_contiguous_layers() {
+        let planner = RangePlanner {
+            max_coalesced_size: 100,
+            max_ranges_per_request: 1,
+            max_parts_per_request: 10,
+            split_piece_size: 1000,
+        };
+        // Three contiguous layers, then a gap, then a layer that was partially downloaded before
+        let layers = [
+            layer(130, 20, 0),
+            layer(100, 30, 0),
+            layer(150, 60, 0),
+            layer(300, 40, 10),
+        ];
+
+        let requests = planner.plan(&layers);
+        assert_eq!(
+            requests
+                .iter()
+                .map(|r| (r.ranges[0].start, r.size()))
+                .collect::<Vec<_>>(),
+            vec![(150, 60), (100, 50), (310, 30)]
+        );
+        assert_eq!(
+            requests[1]
+                .parts
+                .iter()
+                .map(|p| p.layer_index)
+                .collect::<Vec<_>>(),
+            vec![1, 0]
+        );
+        assert_eq!(requests[2].parts[0].layer_offset, 10);
+    }
+
+    #[test]
+    fn test_plan_splits_large_layers() {
+        let planner = RangePlanner {
+            max_coalesced_size: 100,
+            max_ranges_per_request: 1,
+            max_parts_per_request: 10,
+            split_piece_size: 100,
+        };
+        let layers = [layer(0, 250, 20), layer(250, 10, 0), layer(260, 100, 100)];
+
+        let requests = planner.plan(&layers);
+        assert_eq!(
+            requests
+                .iter()
+                .map(|r| (r.ranges[0].start, r.size(), r.parts[0].piece))
+                .collect::<Vec<_>>(),
+            vec![
+                (20, 100, Some(0)),
+                (120, 100, Some(1)),
+                (220, 30, Some(2)),
+                (250, 10, None)
+            ]
+        );
+        assert_eq!(requests[2].parts[0].layer_offset, 220);
+    }
+
+    #[test]
+    fn test_plan_groups_scattered_layers() {
+        let planner = RangePlanner {
+            max_coalesced_size: 100,
+            max_ranges_per_request: 3,
+            max_parts_per_request: 10,
+            split_piece_size: 100,
+        };
+        // Small layers that are far apart, with a large layer in between that is split
+        let layers = [
+            layer(0, 10, 0),
+            layer(10, 10, 0),
+            layer(100, 10, 0),
+            layer(200, 150, 0),
+            layer(400, 10, 0),
+            layer(500, 10, 0),
+        ];
+
+        let requests = planner.plan(&layers);
+        let grouped_requests: Vec<&RangeRequest> = requests
+            .iter()
+            .filter(|r| r.parts[0].piece.is_none())
+            .collect();
+        assert_eq!(
+            grouped_requests
+                .iter()
+                .map(|r| r.ranges.iter().map(|range| range.start).collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+            vec![vec![0, 100, 400], vec![500]]
+        );
+        assert_eq!(grouped_requests[0].size(), 40);
+        assert_eq!(
+            grouped_requests[0]
+                .parts
+                .iter()
+                .map(|p| p.layer_index)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2, 4]
+        );
+    }
+
+    #[test]
+    fn test_plan_limits_parts_per_request() {
+        let planner = RangePlanner {
+            max_coalesced_size: 1000,
+            max_ranges_per_request: 10,
+            max_parts_per_request: 4,
+            split_piece_size: 1000,
+        };
+        // Tiny contiguous layers, which would otherwise all share a single range
+        let layers: Vec<RemainingLayer> = (0..10).map(|i| layer(i * 10, 10, 0)).collect();
+
+        let requests = planner.plan(&layers);
+        assert_eq!(
+            requests
+                .iter()
+                .map(|r| (r.ranges.clone(), r.parts.len()))
+                .collect::<Vec<_>>(),
+            vec![
+                (vec![ByteRange { start: 0, size: 40 }], 4),
+                (
+                    vec![ByteRange {
+                        start: 40,
+                        size: 40
+                    }],
+                    4
+                ),
+                (
+                    vec![ByteRange {
+                        start: 80,
+                        size: 20
+                    }],
+                    2
+                ),
+            ]
+        );
+    }
+}
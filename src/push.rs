@@ -0,0 +1,306 @@
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Context};
+use indicatif::{ProgressBar, ProgressStyle};
+use reqwest::blocking::{Body, Client};
+use reqwest::StatusCode;
+
+use crate::config::Config;
+use crate::registry::{BlobsQuery, MissingBlobs, MAX_BLOBS_QUERY_SIZE};
+use crate::{hasher, manifest, store};
+
+/// Uploads a downloaded model to the registry: first every layer and other file the registry is missing, then the
+/// hashes, headers and list of other files of the model.
+/// Layers go first so the registry never has hashes it can not serve the layers of.
+pub fn push_model_by_model_id(
+    config: &Config,
+    model_id: &str,
+    revision: &str,
+) -> anyhow::Result<()> {
+    let storage_dir: &str = &config.store_dir;
+    let _store_lock = store::lock_store(storage_dir, false)
+        .with_context(|| format!("unable to lock {}", storage_dir))?;
+    let commit_sha = manifest::resolve_revision(storage_dir, model_id, revision);
+    let manifests = manifest::load_manifests(storage_dir, model_id, &commit_sha);
+    let files_manifest = manifest::load_files_manifest(storage_dir, model_id, &commit_sha);
+
+    if manifests.is_empty() {
+        bail!("{}@{} has not been downloaded yet", model_id, revision)
+    }
+
+    // Large uploads can take much longer than the default timeout
+    let client = Client::builder().timeout(None).build()?;
+    let registry_url = config.registry_url();
+
+    let model_hashes = hasher::get_model_hashes_from_manifests(&manifests)
+        .with_context(|| format!("unable to collect the hashes of {}", model_id))?;
+    // Other files, such as the config and tokenizer, are stored as blobs too and are uploaded along with the layers
+    let layer_hashes: Vec<String> = model_hashes
+        .values()
+        .map(|layer_hash| layer_hash.hash.to_string())
+        .chain(files_manifest.files.values().map(|f| f.hash.to_string()))
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect();
+
+    let missing_hashes = get_missing_blobs(&client, registry_url, &layer_hashes)
+        .with_context(|| format!("unable to reach the registry at {}", registry_url))?;
+    match missing_hashes {
+        None => println!("The registry does not store layers, only the hashes will be pushed"),
+        Some(missing_hashes) => {
+            println!(
+                "{} of {} layers are missing from the registry",
+                missing_hashes.len(),
+                layer_hashes.len()
+            );
+            for (blob_index, hash) in missing_hashes.iter().enumerate() {
+                println!(
+                    "Uploading layer {} of {}: {}",
+                    blob_index + 1,
+                    missing_hashes.len(),
+                    hash
+                );
+                upload_blob(&client, registry_url, storage_dir, hash)
+                    .with_context(|| format!("unable to upload layer {}", hash))?;
+            }
+        }
+    }
+
+    hasher::publish_model_hashes(registry_url, model_id, &commit_sha, &model_hashes)
+        .with_context(|| format!("unable to push the hashes of {}", model_id))?;
+    hasher::publish_file_headers(registry_url, &manifests)
+        .with_context(|| format!("unable to push the headers of {}", model_id))?;
+    if !files_manifest.files.is_empty() {
+        hasher::publish_model_files(registry_url, &files_manifest)
+            .with_context(|| format!("unable to push the other files of {}", model_id))?;
+    }
+    println!("Pushed {}@{} to {}", model_id, commit_sha, registry_url);
+
+    Ok(())
+}
+
+/// Asks the registry which of the layers it does not have, in batches.
+/// Returns None if the registry only stores hashes.
+fn get_missing_blobs(
+    client: &Client,
+    registry_url: &str,
+    hashes: &[String],
+) -> Result<Option<Vec<String>>, reqwest::Error> {
+    let url = format!("{}/v1/blobs/missing", registry_url);
+    let mut missing_hashes: Vec<String> = Vec::new();
+
+    for hashes_batch in hashes.chunks(MAX_BLOBS_QUERY_SIZE) {
+        let response = client
+            .post(&url)
+            .json(&BlobsQuery {
+                hashes: hashes_batch.to_vec(),
+            })
+            .send()?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        // Only the layers that were asked about can be uploaded, anything else the registry answers is ignored
+        let missing_blobs: MissingBlobs = response.error_for_status()?.json()?;
+        missing_hashes.extend(
+            missing_blobs
+                .missing
+                .into_iter()
+                .filter(|hash| hashes_batch.contains(hash)),
+        );
+    }
+
+    Ok(Some(missing_hashes))
+}
+
+/// Streams a layer from the local store to the registry, decompressing it on the way
+fn upload_blob(
+    client: &Client,
+    registry_url: &str,
+    storage_dir: &str,
+    hash: &str,
+) -> anyhow::Result<()> {
+    let (blob_path, codec) = store::find_blob(storage_dir, hash)
+        .ok_or_else(|| anyhow::anyhow!("layer {} is not available locally", hash))?;
+    let blob_size = store::get_blob_logical_size(&blob_path, codec)?;
+
+    let pb = ProgressBar::new(blob_size).with_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:20.cyan/blue} {bytes:>10}/{total_bytes:10} {bytes_per_sec}",
+        )
+        .unwrap(),
+    );
+    let blob_reader = pb.wrap_read(store::open_blob(storage_dir, hash)?);
+
+    client
+        .put(format!("{}/v1/blobs/{}", registry_url, hash))
+        .body(Body::sized(blob_reader, blob_size))
+        .send()?
+        .error_for_status()?;
+    pb.finish_and_clear();
+
+    Ok(())
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use crate::index::Index;
+    #[allow(unused_imports)]
+    use crate::manifest::{FileEntry, FilesManifest, Manifest, TensorEntry};
+    #[allow(unused_imports)]
+    use crate::registry;
+    #[allow(unused_imports)]
+    use std::collections::BTreeMap;
+    #[allow(unused_imports)]
+    use std::fs;
+    #[allow(unused_imports)]
+    use std::io::Read;
+    #[allow(unused_imports)]
+    use std::path::Path;
+
+    #[test]
+    fn test_push_model_to_registry() {
+        let test_dir =
+            std::env::temp_dir().join(format!("cake-test-push-{:016x}", rand::random::<u64>()));
+        let storage_dir = test_dir.join("store").to_str().unwrap().to_string();
+        let blobs_dir = test_dir.join("registry").to_str().unwrap().to_string();
+
+        let tensors = [("a", b"abcd".to_vec()), ("b", vec![7; 100_000])];
+        let mut tensor_entries: BTreeMap<String, TensorEntry> = BTreeMap::new();
+        let mut data_offset = 0;
+        for (tensor_name, tensor_bytes) in &tensors {
+            let hash = hasher::sha256_hash(tensor_bytes);
+            store::write_blob(&storage_dir, &hash, tensor_bytes, store::Codec::Lz4).unwrap();
+            let size = tensor_bytes.len() as u64;
+            tensor_entries.insert(
+                tensor_name.to_string(),
+                TensorEntry {
+                    hash,
+                    data_offsets: [data_offset, data_offset + size],
+                    size,
+                },
+            );
+            data_offset += size;
+        }
+        manifest::save_manifest(
+            &storage_dir,
+            &Manifest {
+                model_id: "org/model".to_string(),
+                revision: "abc123".to_string(),
+                file_name: "model.safetensors".to_string(),
+                header: r#"{"a":{"dtype":"U8","shape":[4],"data_offsets":[0,4]},"b":{"dtype":"U8","shape":[100000],"data_offsets":[4,100004]}}"#.to_string(),
+                tensors: tensor_entries.clone(),
+                lfs_sha256: None,
+            },
+        );
+
+        let config_bytes = br#"{"architectures":["Model"]}"#;
+        let config_hash = hasher::sha256_hash(config_bytes);
+        store::write_blob(&storage_dir, &config_hash, config_bytes, store::Codec::Lz4).unwrap();
+        manifest::save_files_manifest(
+            &storage_dir,
+            &FilesManifest {
+                model_id: "org/model".to_string(),
+                revision: "abc123".to_string(),
+                files: BTreeMap::from([(
+                    "config.json".to_string(),
+                    FileEntry {
+                        hash: config_hash.to_string(),
+                        size: config_bytes.len() as u64,
+                    },
+                )]),
+            },
+        );
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let registry_url = format!("http://{}", listener.local_addr().unwrap());
+        let registry_config = Config {
+            results_dir: test_dir.join("results").to_str().unwrap().to_string(),
+            registry_blobs_dir: Some(blobs_dir.to_string()),
+            ..Config::default()
+        };
+        let app = registry::build_router(
+            &registry_config,
+            Index::open(Path::new(":memory:")).unwrap(),
+        );
+        runtime.spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = Config {
+            store_dir: storage_dir.to_string(),
+            registry_url: registry_url.to_string(),
+            ..Config::default()
+        };
+        let client = Client::new();
+        let hashes: Vec<String> = tensor_entries
+            .values()
+            .map(|t| t.hash.to_string())
+            .chain([config_hash.to_string()])
+            .collect();
+        let missing_hashes = get_missing_blobs(&client, &registry_url, &hashes).unwrap();
+        assert_eq!(missing_hashes.unwrap().len(), 3);
+
+        push_model_by_model_id(&config, "org/model", "abc123").unwrap();
+
+        let missing_hashes = get_missing_blobs(&client, &registry_url, &hashes).unwrap();
+        assert_eq!(missing_hashes, Some(Vec::new()));
+        for (tensor_name, tensor_bytes) in &tensors {
+            let mut blob_bytes = Vec::new();
+            store::open_blob(&blobs_dir, &tensor_entries[*tensor_name].hash)
+                .unwrap()
+                .read_to_end(&mut blob_bytes)
+                .unwrap();
+            assert_eq!(&blob_bytes, tensor_bytes);
+        }
+        let registry_hashes =
+            hasher::get_registry_hashes(&registry_url, "org/model", "abc123", "model.safetensors")
+                .unwrap();
+        assert_eq!(registry_hashes["b"], tensor_entries["b"].hash);
+        let (model_header, _) =
+            hasher::get_model_file_hashes(&config, "org/model", "abc123", "model.safetensors")
+                .unwrap();
+        assert!(model_header.from_registry);
+
+        // The other files of the model are pushed along with its layers
+        let mut blob_bytes = Vec::new();
+        store::open_blob(&blobs_dir, &config_hash)
+            .unwrap()
+            .read_to_end(&mut blob_bytes)
+            .unwrap();
+        assert_eq!(blob_bytes, config_bytes);
+        let model_files: registry::ModelFiles = client
+            .get(format!("{}/v1/models/org/model/abc123/files", registry_url))
+            .send()
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(model_files["config.json"].hash, config_hash);
+        assert_eq!(model_files["config.json"].size, config_bytes.len() as u64);
+
+        // Uploads of the same layer at once must all succeed, however they race to store it
+        let hash = hasher::sha256_hash(b"uploaded at once");
+        store::write_blob(&storage_dir, &hash, b"uploaded at once", store::Codec::None).unwrap();
+        std::thread::scope(|scope| {
+            let uploads: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| upload_blob(&client, &registry_url, &storage_dir, &hash)))
+                .collect();
+            for upload in uploads {
+                upload.join().unwrap().unwrap();
+            }
+        });
+        assert!(store::find_blob(&blobs_dir, &hash).is_some());
+
+        // Models that have not been downloaded are an error rather than a panic
+        let unknown_model = push_model_by_model_id(&config, "org/unknown", "main");
+        assert!(unknown_model.is_err());
+
+        fs::remove_dir_all(test_dir).unwrap();
+    }
+}
@@ -1,27 +1,1182 @@
-use axum::{routing::get, Router};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use axum::body::{Body, Bytes};
+use axum::extract::{DefaultBodyLimit, Path as UrlPath, State};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tower_http::{services::ServeDir, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+use crate::config::Config;
+use crate::hasher::is_sha256;
+use crate::index::Index;
+use crate::manifest::FileEntry;
+use crate::store;
+
+/// The hash of a single tensor, in the same format as the `hashes.json` files written by `hash-single-model`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayerHash {
+    pub data_offsets: [u64; 2],
+    pub hash: String,
+    pub size: u64,
+    pub file_name: String,
+}
+
+/// The hashes of every tensor of a model at a revision, keyed by tensor name
+pub type ModelHashes = BTreeMap<String, LayerHash>;
+
+/// A tensor of a model that is made of a given layer
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LayerReference {
+    pub model_id: String,
+    /// Hashes uploaded before revisions were tracked are stored without one
+    pub revision: Option<String>,
+    pub file_name: String,
+    pub tensor_name: String,
+}
+
+/// Everything needed to download a safetensors file without asking Hugging Face for its header
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileHashes {
+    /// The JSON header exactly as it is in the file, if it has been uploaded
+    pub header: Option<String>,
+    /// The hashes of the tensors of this file only
+    pub hashes: ModelHashes,
+}
+
+/// The files of a model at a revision that are not safetensors, such as its config and tokenizer, keyed by path
+pub type ModelFiles = BTreeMap<String, FileEntry>;
+
+/// The body of `POST /v1/blobs/missing`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlobsQuery {
+    pub hashes: Vec<String>,
+}
+
+/// The answer to `POST /v1/blobs/missing`: the hashes that were asked about which the registry does not have
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MissingBlobs {
+    pub missing: Vec<String>,
+}
+
+struct RegistryState {
+    results_dir: PathBuf,
+    index: Mutex<Index>,
+    /// The store layers are served from, if this registry serves them at all
+    blobs_dir: Option<String>,
+}
+
+/// How much of a blob is sent per chunk of a response
+const BLOB_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Uploaded layers are compressed the same way `cake download` compresses them by default
+const BLOB_CODEC: store::Codec = store::Codec::Lz4;
+
+/// The largest header the safetensors format allows
+const MAX_HEADER_SIZE: usize = 100_000_000;
+
+/// The largest hashes upload that is accepted. A tensor takes up about 200 bytes of JSON, so this fits models of
+/// around 300,000 tensors, several times more than the largest models have. Axum's default of 2 MB would only fit
+/// about 10,000.
+const MAX_MODEL_HASHES_SIZE: usize = 64 * 1024 * 1024;
+
+/// The largest layer that can be uploaded, as uploads are streamed to disk without knowing their size up front
+const MAX_BLOB_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+/// How many hashes can be asked about in a single `POST /v1/blobs/missing`
+pub const MAX_BLOBS_QUERY_SIZE: usize = 10_000;
+
 #[tokio::main]
-pub async fn run_registry() {
+pub async fn run_registry(config: &Config) {
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let results_dir = PathBuf::from(&config.results_dir);
+    let mut index = Index::open(Path::new(&config.registry_index)).unwrap();
+
+    // Pick up the hashes files of registries from before the index existed
+    if index.is_empty().unwrap() {
+        let (imported_count, _) = import_results(&mut index, &results_dir).unwrap();
+        println!("Imported {} hashes files into the index", imported_count);
+    }
+
+    // Uploads in progress are temporary files, which must not be swept away by another cake process
+    let _blobs_lock = config
+        .registry_blobs_dir
+        .as_ref()
+        .map(|blobs_dir| store::lock_store(blobs_dir, false).unwrap());
+
+    let app = build_router(config, index);
+
+    // run our app with hyper, listening globally on port 3000 by default
+    println!(
+        "Starting Cake registry on {}...",
+        config.registry_listen_addr
+    );
+    let listener = tokio::net::TcpListener::bind(&config.registry_listen_addr)
+        .await
+        .unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+/// The routes of the registry, serving the hashes in the index and the layers in the blobs folder of the config
+pub fn build_router(config: &Config, index: Index) -> Router {
+    let state = Arc::new(RegistryState {
+        results_dir: PathBuf::from(&config.results_dir),
+        index: Mutex::new(index),
+        blobs_dir: config.registry_blobs_dir.clone(),
+    });
+
     // build our application with a route
-    let app = Router::new()
+    Router::new()
         // `GET /` goes to `root`
         .route("/", get(root))
-        .nest_service("/results", ServeDir::new("./results"))
-        .layer(TraceLayer::new_for_http());
-
-    // run our app with hyper, listening globally on port 3000
-    println!("Starting Cake registry...");
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+        .route(
+            "/v1/models/:org/:name/:revision/hashes",
+            get(get_model_hashes)
+                .put(put_model_hashes)
+                .layer(DefaultBodyLimit::max(MAX_MODEL_HASHES_SIZE)),
+        )
+        .route(
+            "/v1/models/:org/:name/:revision/files",
+            get(get_model_files).put(put_model_files),
+        )
+        .route(
+            "/v1/models/:org/:name/:revision/files/:file_name",
+            get(get_file_hashes),
+        )
+        .route(
+            "/v1/models/:org/:name/:revision/files/:file_name/header",
+            put(put_file_header).layer(DefaultBodyLimit::max(MAX_HEADER_SIZE)),
+        )
+        .route("/v1/layers/:hash", get(get_layer_references))
+        // Also answers HEAD requests, so clients can check whether a layer is here before downloading it
+        .route("/v1/blobs/:hash", get(get_blob).put(put_blob))
+        .route("/v1/blobs/missing", post(get_missing_blobs))
+        // Older clients fetch the hashes files directly
+        .nest_service("/results", ServeDir::new(&config.results_dir))
+        .layer(TraceLayer::new_for_http())
+        .with_state(state)
 }
 
 // basic handler that responds with a static string
 async fn root() -> &'static str {
     "Hello, World! This is the Cake registry speaking."
 }
+
+enum ApiError {
+    BadRequest(String),
+    NotFound(String),
+    /// Carries the size of the blob, as the response has to include it
+    RangeNotSatisfiable(u64),
+    PayloadTooLarge(String),
+    Internal(String),
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        ApiError::Internal(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(e: rusqlite::Error) -> Self {
+        ApiError::Internal(e.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for ApiError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        ApiError::Internal(e.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            ApiError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            ApiError::RangeNotSatisfiable(blob_size) => {
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{}", blob_size))],
+                    Json(json!({ "error": "range not satisfiable" })),
+                )
+                    .into_response();
+            }
+            ApiError::PayloadTooLarge(message) => (StatusCode::PAYLOAD_TOO_LARGE, message),
+            ApiError::Internal(message) => (StatusCode::INTERNAL_SERVER_ERROR, message),
+        };
+
+        (status, Json(json!({ "error": message }))).into_response()
+    }
+}
+
+async fn get_model_hashes(
+    State(state): State<Arc<RegistryState>>,
+    UrlPath((org, name, revision)): UrlPath<(String, String, String)>,
+) -> Result<Json<ModelHashes>, ApiError> {
+    validate_path_segments(&[&org, &name])?;
+    validate_nested_path(&revision)?;
+
+    let model_id = format!("{}/{}", org, name);
+    let model_ref = format!("{}@{}", model_id, revision);
+    let model_hashes = tokio::task::spawn_blocking(move || {
+        let index = state.index.lock().unwrap();
+        index.get_model_hashes(&model_id, Some(&revision))
+    })
+    .await??;
+
+    match model_hashes {
+        Some(model_hashes) => Ok(Json(model_hashes)),
+        None => Err(ApiError::NotFound(format!("no hashes for {}", model_ref))),
+    }
+}
+
+async fn put_model_hashes(
+    State(state): State<Arc<RegistryState>>,
+    UrlPath((org, name, revision)): UrlPath<(String, String, String)>,
+    body: axum::body::Bytes,
+) -> Result<StatusCode, ApiError> {
+    validate_path_segments(&[&org, &name])?;
+    validate_nested_path(&revision)?;
+
+    // Parse the body here rather than with the Json extractor, so schema errors are reported like any other error
+    let model_hashes: ModelHashes = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::BadRequest(format!("invalid hashes: {}", e)))?;
+    validate_model_hashes(&model_hashes).map_err(ApiError::BadRequest)?;
+
+    tokio::task::spawn_blocking(move || -> Result<(), ApiError> {
+        let model_id = format!("{}/{}", org, name);
+        let mut index = state.index.lock().unwrap();
+        index.put_model_hashes(&model_id, Some(&revision), &model_hashes)?;
+
+        // Keep writing the hashes files too, as older clients fetch them directly
+        let hashes_file_path =
+            get_hashes_file_path(&state.results_dir, &org, &name, Some(&revision));
+        write_model_hashes(&hashes_file_path, &model_hashes)?;
+
+        Ok(())
+    })
+    .await??;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_file_hashes(
+    State(state): State<Arc<RegistryState>>,
+    UrlPath((org, name, revision, file_name)): UrlPath<(String, String, String, String)>,
+) -> Result<Json<FileHashes>, ApiError> {
+    validate_path_segments(&[&org, &name])?;
+    validate_nested_path(&revision)?;
+    validate_nested_path(&file_name)?;
+
+    let model_id = format!("{}/{}", org, name);
+    let file_ref = format!("{}@{}: {}", model_id, revision, file_name);
+    let (header, file_hashes) = tokio::task::spawn_blocking(move || -> rusqlite::Result<_> {
+        let index = state.index.lock().unwrap();
+        let header = index.get_file_header(&model_id, &revision, &file_name)?;
+        let model_hashes = index.get_model_hashes(&model_id, Some(&revision))?;
+
+        // Only the tensors of this file are needed to download it
+        let file_hashes: ModelHashes = model_hashes
+            .into_iter()
+            .flatten()
+            .filter(|(_, layer_hash)| layer_hash.file_name == file_name)
+            .collect();
+
+        Ok((header, file_hashes))
+    })
+    .await??;
+
+    if header.is_none() && file_hashes.is_empty() {
+        return Err(ApiError::NotFound(format!(
+            "nothing is known about {}",
+            file_ref
+        )));
+    }
+
+    Ok(Json(FileHashes {
+        header,
+        hashes: file_hashes,
+    }))
+}
+
+async fn get_model_files(
+    State(state): State<Arc<RegistryState>>,
+    UrlPath((org, name, revision)): UrlPath<(String, String, String)>,
+) -> Result<Json<ModelFiles>, ApiError> {
+    validate_path_segments(&[&org, &name])?;
+    validate_nested_path(&revision)?;
+
+    let files_file_path = get_files_file_path(&state.results_dir, &org, &name, &revision);
+    let model_files = tokio::task::spawn_blocking(move || -> Option<ModelFiles> {
+        let file = File::open(files_file_path).ok()?;
+        serde_json::from_reader(io::BufReader::new(file)).ok()
+    })
+    .await?;
+
+    model_files
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("no files for {}/{}@{}", org, name, revision)))
+}
+
+async fn put_model_files(
+    State(state): State<Arc<RegistryState>>,
+    UrlPath((org, name, revision)): UrlPath<(String, String, String)>,
+    body: axum::body::Bytes,
+) -> Result<StatusCode, ApiError> {
+    validate_path_segments(&[&org, &name])?;
+    validate_nested_path(&revision)?;
+
+    let model_files: ModelFiles = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::BadRequest(format!("invalid files: {}", e)))?;
+    for (file_name, file_entry) in &model_files {
+        validate_nested_path(file_name)?;
+        validate_hash(&file_entry.hash)?;
+    }
+
+    tokio::task::spawn_blocking(move || -> Result<(), ApiError> {
+        // Like layers, the files have to be uploaded first, so the registry never lists a file it can not serve
+        if let Some(blobs_dir) = &state.blobs_dir {
+            let missing_file = model_files
+                .iter()
+                .find(|(_, file_entry)| store::find_blob(blobs_dir, &file_entry.hash).is_none());
+            if let Some((file_name, file_entry)) = missing_file {
+                return Err(ApiError::BadRequest(format!(
+                    "the blob {} of {} has to be uploaded before the files",
+                    file_entry.hash, file_name
+                )));
+            }
+        }
+
+        let files_file_path = get_files_file_path(&state.results_dir, &org, &name, &revision);
+        fs::create_dir_all(files_file_path.parent().unwrap())?;
+        let files_bytes = serde_json::to_vec_pretty(&model_files).map_err(io::Error::from)?;
+        store::write_file_atomically(&files_file_path, &files_bytes)?;
+
+        Ok(())
+    })
+    .await??;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn put_file_header(
+    State(state): State<Arc<RegistryState>>,
+    UrlPath((org, name, revision, file_name)): UrlPath<(String, String, String, String)>,
+    body: axum::body::Bytes,
+) -> Result<StatusCode, ApiError> {
+    validate_path_segments(&[&org, &name])?;
+    validate_nested_path(&revision)?;
+    validate_nested_path(&file_name)?;
+
+    // Stored as it was sent, padding included, so exports stay byte-identical
+    let header = String::from_utf8(body.to_vec())
+        .map_err(|_| ApiError::BadRequest("the header is not valid UTF-8".to_string()))?;
+
+    tokio::task::spawn_blocking(move || -> Result<(), ApiError> {
+        let model_id = format!("{}/{}", org, name);
+        let index = state.index.lock().unwrap();
+
+        // Clients trust the header to tell them where each layer goes, so it has to agree with the hashes
+        let file_hashes: ModelHashes = index
+            .get_model_hashes(&model_id, Some(&revision))?
+            .into_iter()
+            .flatten()
+            .filter(|(_, layer_hash)| layer_hash.file_name == file_name)
+            .collect();
+        if file_hashes.is_empty() {
+            return Err(ApiError::BadRequest(format!(
+                "the hashes of {} have to be uploaded before its header",
+                file_name
+            )));
+        }
+        validate_file_header(header.as_bytes(), &file_hashes).map_err(ApiError::BadRequest)?;
+
+        index.put_file_header(&model_id, &revision, &file_name, &header)?;
+
+        Ok(())
+    })
+    .await??;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_layer_references(
+    State(state): State<Arc<RegistryState>>,
+    UrlPath(hash): UrlPath<String>,
+) -> Result<Json<Vec<LayerReference>>, ApiError> {
+    validate_hash(&hash)?;
+
+    let layer_references = tokio::task::spawn_blocking(move || {
+        let index = state.index.lock().unwrap();
+        index.find_layer_references(&hash)
+    })
+    .await??;
+
+    Ok(Json(layer_references))
+}
+
+async fn get_blob(
+    State(state): State<Arc<RegistryState>>,
+    UrlPath(hash): UrlPath<String>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    validate_hash(&hash)?;
+    let blobs_dir = get_blobs_dir(&state)?;
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map(str::to_string);
+
+    let (blob_size, byte_range, blob_reader) =
+        tokio::task::spawn_blocking(move || -> Result<_, ApiError> {
+            let (blob_path, codec) = store::find_blob(&blobs_dir, &hash)
+                .ok_or_else(|| ApiError::NotFound(format!("no layer {}", hash)))?;
+            let blob_size = store::get_blob_logical_size(&blob_path, codec)?;
+
+            let byte_range = match range.map(|range| parse_byte_range(&range, blob_size)) {
+                None | Some(ByteRange::Whole) => None,
+                Some(ByteRange::Part(byte_range)) => Some(byte_range),
+                Some(ByteRange::Unsatisfiable) => {
+                    return Err(ApiError::RangeNotSatisfiable(blob_size))
+                }
+            };
+
+            // Compressed blobs have to be decompressed up to the start of the range, which a HEAD request can skip
+            let blob_reader = if method == Method::HEAD {
+                None
+            } else {
+                let Range { start, end } = byte_range.clone().unwrap_or(0..blob_size);
+                Some(store::open_blob_at(&blobs_dir, &hash, start)?.take(end - start))
+            };
+
+            Ok((blob_size, byte_range, blob_reader))
+        })
+        .await??;
+
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::ACCEPT_RANGES, "bytes");
+    let response = match &byte_range {
+        Some(byte_range) => response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                header::CONTENT_RANGE,
+                format!(
+                    "bytes {}-{}/{}",
+                    byte_range.start,
+                    byte_range.end - 1,
+                    blob_size
+                ),
+            )
+            .header(header::CONTENT_LENGTH, byte_range.end - byte_range.start),
+        None => response
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, blob_size),
+    };
+
+    let body = match blob_reader {
+        Some(blob_reader) => stream_blob(blob_reader),
+        None => Body::empty(),
+    };
+
+    response
+        .body(body)
+        .map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+async fn put_blob(
+    State(state): State<Arc<RegistryState>>,
+    UrlPath(hash): UrlPath<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<StatusCode, ApiError> {
+    validate_hash(&hash)?;
+    let blobs_dir = get_blobs_dir(&state)?;
+
+    // Uploads that say up front they are too large are turned away before anything is written
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.is_some_and(|content_length| content_length > MAX_BLOB_SIZE) {
+        return Err(get_blob_too_large_error());
+    }
+
+    // Several clients may upload the same layer at once, so each upload gets a file of its own, and is compressed
+    // into a file of its own too before it is renamed into place
+    let blob_dir = store::get_blob_dir(&blobs_dir, &hash)?;
+    let upload_path = store::get_temp_path(&store::get_blob_path(
+        &blobs_dir,
+        &hash,
+        store::Codec::None,
+    )?);
+    fs::create_dir_all(&blob_dir)?;
+
+    let uploaded_hash = match receive_blob(body, &upload_path).await {
+        Ok(uploaded_hash) => uploaded_hash,
+        Err(e) => {
+            let _ = fs::remove_file(&upload_path);
+            return Err(e);
+        }
+    };
+    if uploaded_hash != hash {
+        fs::remove_file(&upload_path)?;
+        return Err(ApiError::BadRequest(format!(
+            "the uploaded bytes hash to {} instead of {}",
+            uploaded_hash, hash
+        )));
+    }
+
+    tokio::task::spawn_blocking(move || -> io::Result<()> {
+        if store::find_blob(&blobs_dir, &hash).is_some() {
+            return fs::remove_file(&upload_path);
+        }
+        let result = store::promote_blob_file(&blobs_dir, &hash, &upload_path, BLOB_CODEC);
+        if result.is_err() {
+            let _ = fs::remove_file(&upload_path);
+        }
+        result
+    })
+    .await??;
+
+    Ok(StatusCode::CREATED)
+}
+
+fn get_blob_too_large_error() -> ApiError {
+    ApiError::PayloadTooLarge(format!("layers can be at most {} bytes", MAX_BLOB_SIZE))
+}
+
+/// Writes an uploaded blob to a file as it arrives, and returns the hash of what was received
+async fn receive_blob(body: Body, upload_path: &Path) -> Result<String, ApiError> {
+    let mut upload_file = tokio::fs::File::create(upload_path).await?;
+    let mut hasher = Sha256::new();
+
+    let mut uploaded_size = 0;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| ApiError::BadRequest(format!("upload failed: {}", e)))?;
+        uploaded_size += chunk.len() as u64;
+        if uploaded_size > MAX_BLOB_SIZE {
+            return Err(get_blob_too_large_error());
+        }
+        hasher.update(&chunk);
+        upload_file.write_all(&chunk).await?;
+    }
+    upload_file.sync_all().await?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+async fn get_missing_blobs(
+    State(state): State<Arc<RegistryState>>,
+    body: axum::body::Bytes,
+) -> Result<Json<MissingBlobs>, ApiError> {
+    let blobs_query: BlobsQuery = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::BadRequest(format!("invalid query: {}", e)))?;
+    if blobs_query.hashes.len() > MAX_BLOBS_QUERY_SIZE {
+        return Err(ApiError::BadRequest(format!(
+            "at most {} hashes can be asked about at once",
+            MAX_BLOBS_QUERY_SIZE
+        )));
+    }
+    for hash in &blobs_query.hashes {
+        validate_hash(hash)?;
+    }
+    let blobs_dir = get_blobs_dir(&state)?;
+
+    let missing = tokio::task::spawn_blocking(move || {
+        blobs_query
+            .hashes
+            .into_iter()
+            .filter(|hash| store::find_blob(&blobs_dir, hash).is_none())
+            .collect()
+    })
+    .await?;
+
+    Ok(Json(MissingBlobs { missing }))
+}
+
+/// Reads the blob on a blocking thread and sends it over as it is read, so it never has to fit in memory
+fn stream_blob(mut blob_reader: impl Read + Send + 'static) -> Body {
+    let (sender, receiver) = tokio::sync::mpsc::channel::<io::Result<Bytes>>(4);
+
+    tokio::task::spawn_blocking(move || {
+        let mut buffer = vec![0u8; BLOB_CHUNK_SIZE];
+        loop {
+            let chunk = match blob_reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => Ok(Bytes::copy_from_slice(&buffer[..n])),
+                Err(e) => Err(e),
+            };
+            let is_error = chunk.is_err();
+            // The client has gone away if the receiver was dropped
+            if sender.blocking_send(chunk).is_err() || is_error {
+                break;
+            }
+        }
+    });
+
+    Body::from_stream(ReceiverStream::new(receiver))
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Whole,
+    Part(Range<u64>),
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header for a blob of the given size. Only single ranges are supported,
+/// anything else is answered with the whole blob, as HTTP allows.
+fn parse_byte_range(range: &str, blob_size: u64) -> ByteRange {
+    let Some((start, end)) = range
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|ranges| !ranges.contains(','))
+        .and_then(|range| range.split_once('-'))
+    else {
+        return ByteRange::Whole;
+    };
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        // The last n bytes
+        ("", suffix_length) => match suffix_length.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix_length) => (blob_size.saturating_sub(suffix_length), blob_size),
+            Err(_) => return ByteRange::Whole,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => (start, blob_size),
+            Err(_) => return ByteRange::Whole,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.saturating_add(1).min(blob_size)),
+            _ => return ByteRange::Whole,
+        },
+    };
+
+    if start >= blob_size {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Part(start..end)
+    }
+}
+
+/// Rejects anything that could escape the results folder once used as part of a path
+fn validate_path_segments(segments: &[&str]) -> Result<(), ApiError> {
+    for segment in segments {
+        let is_valid = !segment.is_empty()
+            && *segment != "."
+            && *segment != ".."
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        if !is_valid {
+            return Err(ApiError::BadRequest(format!(
+                "{} is not a valid model id, revision or file name",
+                segment
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Revisions such as `refs/pr/1` and files in subfolders have slashes, which clients send encoded so they stay in
+/// one segment of the URL. Each folder of the path is then validated on its own.
+fn validate_nested_path(path: &str) -> Result<(), ApiError> {
+    let segments: Vec<&str> = path.split('/').collect();
+    validate_path_segments(&segments)
+}
+
+fn validate_hash(hash: &str) -> Result<(), ApiError> {
+    if !is_sha256(hash) {
+        return Err(ApiError::BadRequest(format!(
+            "{} is not a SHA-256 hash",
+            hash
+        )));
+    }
+
+    Ok(())
+}
+
+fn get_blobs_dir(state: &RegistryState) -> Result<String, ApiError> {
+    state
+        .blobs_dir
+        .clone()
+        .ok_or_else(|| ApiError::NotFound("this registry does not serve layers".to_string()))
+}
+
+fn validate_model_hashes(model_hashes: &ModelHashes) -> Result<(), String> {
+    if model_hashes.is_empty() {
+        return Err("no tensors were given".to_string());
+    }
+
+    for (tensor_name, layer_hash) in model_hashes {
+        let [offset_start, offset_end] = layer_hash.data_offsets;
+        if offset_end < offset_start {
+            return Err(format!("{} ends before it starts", tensor_name));
+        }
+        if layer_hash.size != offset_end - offset_start {
+            return Err(format!(
+                "{} has a size of {} but its offsets span {} bytes",
+                tensor_name,
+                layer_hash.size,
+                offset_end - offset_start
+            ));
+        }
+        if !is_sha256(&layer_hash.hash) {
+            return Err(format!(
+                "{} does not have a lowercase SHA-256 hash",
+                tensor_name
+            ));
+        }
+        if layer_hash.file_name.is_empty() {
+            return Err(format!("{} does not have a file name", tensor_name));
+        }
+    }
+
+    Ok(())
+}
+
+/// The size of an element of each dtype of the safetensors format
+fn get_dtype_size(dtype: &str) -> Option<u64> {
+    match dtype {
+        "BOOL" | "U8" | "I8" | "F8_E5M2" | "F8_E4M3" => Some(1),
+        "U16" | "I16" | "F16" | "BF16" => Some(2),
+        "U32" | "I32" | "F32" => Some(4),
+        "U64" | "I64" | "F64" => Some(8),
+        _ => None,
+    }
+}
+
+/// Checks that a header describes exactly the tensors of a file that there are hashes of, at the same offsets, so
+/// a header that does not belong with the hashes can not be used to assemble a file from the wrong layers.
+/// `file_hashes` only has the hashes of the tensors of this file. Returns the parsed header.
+pub fn validate_file_header(
+    header_bytes: &[u8],
+    file_hashes: &ModelHashes,
+) -> Result<serde_json::Value, String> {
+    if header_bytes.len() > MAX_HEADER_SIZE {
+        return Err(format!(
+            "the header is {} bytes, more than the {} that safetensors allows",
+            header_bytes.len(),
+            MAX_HEADER_SIZE
+        ));
+    }
+    let header: serde_json::Value = serde_json::from_slice(header_bytes)
+        .map_err(|e| format!("the header is not valid JSON: {}", e))?;
+    let tensors = header
+        .as_object()
+        .ok_or_else(|| "the header is not a JSON object".to_string())?;
+
+    let mut tensor_count = 0;
+    for (tensor_name, tensor) in tensors {
+        if tensor_name == "__metadata__" {
+            continue;
+        }
+        tensor_count += 1;
+
+        let layer_hash = file_hashes
+            .get(tensor_name)
+            .ok_or_else(|| format!("{} is in the header but has no hash", tensor_name))?;
+        let dtype = tensor.get("dtype").and_then(serde_json::Value::as_str);
+        let shape: Option<Vec<u64>> = tensor
+            .get("shape")
+            .and_then(serde_json::Value::as_array)
+            .and_then(|shape| shape.iter().map(serde_json::Value::as_u64).collect());
+        let data_offsets: Option<Vec<u64>> = tensor
+            .get("data_offsets")
+            .and_then(serde_json::Value::as_array)
+            .and_then(|offsets| offsets.iter().map(serde_json::Value::as_u64).collect());
+        let (Some(dtype), Some(shape), Some(data_offsets)) = (dtype, shape, data_offsets) else {
+            return Err(format!(
+                "{} does not have a dtype, shape and data offsets",
+                tensor_name
+            ));
+        };
+
+        if data_offsets != layer_hash.data_offsets {
+            return Err(format!(
+                "{} is at {:?} in the header but at {:?} in the hashes",
+                tensor_name, data_offsets, layer_hash.data_offsets
+            ));
+        }
+
+        // Dtypes this does not know of are only checked against the hashes
+        if let Some(dtype_size) = get_dtype_size(dtype) {
+            let tensor_size = shape
+                .iter()
+                .try_fold(dtype_size, |size, dimension| size.checked_mul(*dimension));
+            if tensor_size != Some(layer_hash.size) {
+                return Err(format!(
+                    "{} is a {} tensor of shape {:?}, which does not take up {} bytes",
+                    tensor_name, dtype, shape, layer_hash.size
+                ));
+            }
+        }
+    }
+
+    if tensor_count != file_hashes.len() {
+        return Err(format!(
+            "the header has {} tensors but there are hashes of {}",
+            tensor_count,
+            file_hashes.len()
+        ));
+    }
+
+    Ok(header)
+}
+
+/// Hashes are stored per revision, or per model for results from before revisions were tracked
+fn get_hashes_file_path(
+    results_dir: &Path,
+    org: &str,
+    name: &str,
+    revision: Option<&str>,
+) -> PathBuf {
+    let mut hashes_file_path = results_dir.join(org).join(name);
+    if let Some(revision) = revision {
+        hashes_file_path.push(revision);
+    }
+    hashes_file_path.push("hashes.json");
+
+    hashes_file_path
+}
+
+/// The files of a model are only stored per revision, next to its hashes
+fn get_files_file_path(results_dir: &Path, org: &str, name: &str, revision: &str) -> PathBuf {
+    get_hashes_file_path(results_dir, org, name, Some(revision)).with_file_name("files.json")
+}
+
+fn read_model_hashes(hashes_file_path: &Path) -> Option<ModelHashes> {
+    let file = File::open(hashes_file_path).ok()?;
+    serde_json::from_reader(io::BufReader::new(file)).ok()
+}
+
+fn write_model_hashes(hashes_file_path: &Path, model_hashes: &ModelHashes) -> io::Result<()> {
+    fs::create_dir_all(hashes_file_path.parent().unwrap())?;
+
+    // Readers should never see a half written file, and concurrent uploads of the same revision must not share a
+    // temporary file
+    let hashes_bytes = serde_json::to_vec_pretty(model_hashes)?;
+    store::write_file_atomically(hashes_file_path, &hashes_bytes)
+}
+
+/// Lists every hashes file in the results folder, along with the model and revision it belongs to
+fn list_hashes_files(results_dir: &Path) -> Vec<(String, Option<String>, PathBuf)> {
+    let mut hashes_files: Vec<(String, Option<String>, PathBuf)> = Vec::new();
+
+    for (org, org_dir) in list_dirs(results_dir) {
+        for (name, model_dir) in list_dirs(&org_dir) {
+            let model_id = format!("{}/{}", org, name);
+            hashes_files.push((model_id.to_string(), None, model_dir.join("hashes.json")));
+            hashes_files.extend(list_revision_dirs(&model_dir, "").into_iter().map(
+                |(revision, revision_dir)| {
+                    (
+                        model_id.to_string(),
+                        Some(revision),
+                        revision_dir.join("hashes.json"),
+                    )
+                },
+            ));
+        }
+    }
+
+    hashes_files.retain(|(_, _, hashes_file_path)| hashes_file_path.is_file());
+
+    hashes_files
+}
+
+/// Adds every hashes file in the results folder to the index, such as those written by `hash-single-model`.
+/// Returns how many were imported and how many were skipped for being invalid.
+pub fn import_results(index: &mut Index, results_dir: &Path) -> rusqlite::Result<(u64, u64)> {
+    let mut imported_count = 0;
+    let mut skipped_count = 0;
+
+    for (model_id, revision, hashes_file_path) in list_hashes_files(results_dir) {
+        let model_hashes = read_model_hashes(&hashes_file_path)
+            .ok_or_else(|| "not a valid hashes file".to_string())
+            .and_then(|model_hashes| {
+                validate_model_hashes(&model_hashes)?;
+                Ok(model_hashes)
+            });
+
+        match model_hashes {
+            Ok(model_hashes) => {
+                index.put_model_hashes(&model_id, revision.as_deref(), &model_hashes)?;
+                imported_count += 1;
+            }
+            Err(e) => {
+                println!("Skipping {}: {}", hashes_file_path.display(), e);
+                skipped_count += 1;
+            }
+        }
+    }
+
+    Ok((imported_count, skipped_count))
+}
+
+/// The folders below a model folder, named by their path from it, as revisions such as `refs/pr/1` are nested
+fn list_revision_dirs(dir: &Path, parent_revision: &str) -> Vec<(String, PathBuf)> {
+    let mut revision_dirs: Vec<(String, PathBuf)> = Vec::new();
+    for (name, revision_dir) in list_dirs(dir) {
+        let revision = format!("{}{}", parent_revision, name);
+        revision_dirs.extend(list_revision_dirs(&revision_dir, &format!("{}/", revision)));
+        revision_dirs.push((revision, revision_dir));
+    }
+
+    revision_dirs
+}
+
+fn list_dirs(dir: &Path) -> Vec<(String, PathBuf)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut dirs: Vec<(String, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .filter_map(|entry| Some((entry.file_name().into_string().ok()?, entry.path())))
+        .collect();
+    dirs.sort();
+
+    dirs
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn get_layer_hash(hash: &str) -> LayerHash {
+        LayerHash {
+            data_offsets: [0, 4],
+            hash: hash.to_string(),
+            size: 4,
+            file_name: "model.safetensors".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_put_large_model_hashes() {
+        let results_dir = std::env::temp_dir().join(format!(
+            "cake-test-registry-large-{:016x}",
+            rand::random::<u64>()
+        ));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let registry_url = format!("http://{}", listener.local_addr().unwrap());
+        let config = Config {
+            results_dir: results_dir.to_str().unwrap().to_string(),
+            ..Config::default()
+        };
+        let app = build_router(&config, Index::open(Path::new(":memory:")).unwrap());
+        runtime.spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        // Well over the 2 MB axum accepts by default
+        let model_hashes: ModelHashes = (0..50_000)
+            .map(|i| {
+                let tensor_name = format!("model.layers.{}.mlp.experts.weight", i);
+                (tensor_name, get_layer_hash(&format!("{:064x}", i)))
+            })
+            .collect();
+        assert!(serde_json::to_vec(&model_hashes).unwrap().len() > 2 * 1024 * 1024);
+        crate::hasher::publish_model_hashes(&registry_url, "org/model", "abc123", &model_hashes)
+            .unwrap();
+        assert_eq!(
+            read_model_hashes(&get_hashes_file_path(
+                &results_dir,
+                "org",
+                "model",
+                Some("abc123")
+            )),
+            Some(model_hashes)
+        );
+
+        let response = reqwest::blocking::Client::new()
+            .put(format!(
+                "{}/v1/models/org/model/abc123/hashes",
+                registry_url
+            ))
+            .body(vec![b' '; MAX_MODEL_HASHES_SIZE + 1])
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+
+        fs::remove_dir_all(&results_dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_model_hashes() {
+        let valid_hash = "a".repeat(64);
+        let model_hashes = ModelHashes::from([("a".to_string(), get_layer_hash(&valid_hash))]);
+        assert_eq!(validate_model_hashes(&model_hashes), Ok(()));
+
+        let model_hashes = ModelHashes::from([("a".to_string(), get_layer_hash("abc"))]);
+        assert!(validate_model_hashes(&model_hashes).is_err());
+
+        let wrong_size = LayerHash {
+            size: 5,
+            ..get_layer_hash(&valid_hash)
+        };
+        let model_hashes = ModelHashes::from([("a".to_string(), wrong_size)]);
+        assert!(validate_model_hashes(&model_hashes).is_err());
+
+        assert!(serde_json::from_str::<ModelHashes>(r#"{"a": {"hash": "abc"}}"#).is_err());
+    }
+
+    #[test]
+    fn test_validate_file_header() {
+        let file_hashes = ModelHashes::from([
+            ("a".to_string(), get_layer_hash(&"a".repeat(64))),
+            (
+                "b".to_string(),
+                LayerHash {
+                    data_offsets: [4, 12],
+                    size: 8,
+                    ..get_layer_hash(&"b".repeat(64))
+                },
+            ),
+        ]);
+        let validate = |header: &str| validate_file_header(header.as_bytes(), &file_hashes);
+
+        assert!(validate(
+            r#"{"__metadata__":{"format":"pt"},"a":{"dtype":"F16","shape":[2],"data_offsets":[0,4]},"b":{"dtype":"F32","shape":[1,2],"data_offsets":[4,12]}}  "#
+        )
+        .is_ok());
+        // Moved to where another layer is
+        assert!(validate(
+            r#"{"a":{"dtype":"F16","shape":[2],"data_offsets":[4,8]},"b":{"dtype":"F32","shape":[2],"data_offsets":[4,12]}}"#
+        )
+        .is_err());
+        // Does not fit in its layer
+        assert!(validate(
+            r#"{"a":{"dtype":"F32","shape":[2],"data_offsets":[0,4]},"b":{"dtype":"F32","shape":[2],"data_offsets":[4,12]}}"#
+        )
+        .is_err());
+        // A tensor short, or one too many
+        assert!(validate(r#"{"a":{"dtype":"F16","shape":[2],"data_offsets":[0,4]}}"#).is_err());
+        assert!(validate(
+            r#"{"a":{"dtype":"F16","shape":[2],"data_offsets":[0,4]},"b":{"dtype":"F32","shape":[2],"data_offsets":[4,12]},"c":{"dtype":"U8","shape":[0],"data_offsets":[12,12]}}"#
+        )
+        .is_err());
+        assert!(validate(r#"{"a":{"dtype":"F16","shape":[2]}}"#).is_err());
+        assert!(validate("[]").is_err());
+    }
+
+    #[test]
+    fn test_validate_path_segments() {
+        assert!(validate_path_segments(&["org", "model-7B_v0.1", "main"]).is_ok());
+        assert!(validate_path_segments(&["org", "..", "main"]).is_err());
+        assert!(validate_path_segments(&["org", "model", "refs/pr/1"]).is_err());
+        assert!(validate_path_segments(&["", "model", "main"]).is_err());
+
+        assert!(validate_nested_path("refs/pr/1").is_ok());
+        assert!(validate_nested_path("text_encoder/model.safetensors").is_ok());
+        assert!(validate_nested_path("refs/../../x").is_err());
+        assert!(validate_nested_path("/refs").is_err());
+        assert!(validate_nested_path("refs/").is_err());
+    }
+
+    #[test]
+    fn test_parse_byte_range() {
+        assert_eq!(
+            parse_byte_range("bytes=0-99", 1000),
+            ByteRange::Part(0..100)
+        );
+        assert_eq!(
+            parse_byte_range("bytes=900-", 1000),
+            ByteRange::Part(900..1000)
+        );
+        assert_eq!(
+            parse_byte_range("bytes=-100", 1000),
+            ByteRange::Part(900..1000)
+        );
+        // Ranges that run past the end are cut short
+        assert_eq!(
+            parse_byte_range("bytes=900-2000", 1000),
+            ByteRange::Part(900..1000)
+        );
+        assert_eq!(
+            parse_byte_range("bytes=-2000", 1000),
+            ByteRange::Part(0..1000)
+        );
+
+        assert_eq!(
+            parse_byte_range("bytes=1000-", 1000),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(parse_byte_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_byte_range("bytes=0-0", 0), ByteRange::Unsatisfiable);
+
+        assert_eq!(parse_byte_range("bytes=0-1,5-9", 1000), ByteRange::Whole);
+        assert_eq!(parse_byte_range("bytes=9-5", 1000), ByteRange::Whole);
+        assert_eq!(parse_byte_range("items=0-1", 1000), ByteRange::Whole);
+    }
+
+    #[test]
+    fn test_import_results() {
+        let results_dir = std::env::temp_dir().join(format!(
+            "cake-test-registry-import-{:016x}",
+            rand::random::<u64>()
+        ));
+        let shared_hash = "a".repeat(64);
+
+        let model_hashes = ModelHashes::from([
+            ("a".to_string(), get_layer_hash(&shared_hash)),
+            ("b".to_string(), get_layer_hash(&"b".repeat(64))),
+        ]);
+        write_model_hashes(
+            &get_hashes_file_path(&results_dir, "org", "base", Some("abc123")),
+            &model_hashes,
+        )
+        .unwrap();
+        write_model_hashes(
+            &get_hashes_file_path(&results_dir, "org", "fine-tune", None),
+            &model_hashes,
+        )
+        .unwrap();
+        let invalid_hashes_file_path = get_hashes_file_path(&results_dir, "org", "broken", None);
+        fs::create_dir_all(invalid_hashes_file_path.parent().unwrap()).unwrap();
+        fs::write(invalid_hashes_file_path, "{}").unwrap();
+
+        let mut index = Index::open(Path::new(":memory:")).unwrap();
+        assert_eq!(import_results(&mut index, &results_dir).unwrap(), (2, 1));
+        assert_eq!(
+            index.get_model_hashes("org/fine-tune", None).unwrap(),
+            Some(model_hashes)
+        );
+        assert_eq!(
+            index
+                .find_layer_references(&shared_hash)
+                .unwrap()
+                .iter()
+                .map(|r| (r.model_id.as_str(), r.revision.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![("org/base", Some("abc123")), ("org/fine-tune", None)]
+        );
+        fs::remove_dir_all(&results_dir).unwrap();
+    }
+}
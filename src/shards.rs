@@ -0,0 +1,341 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::manifest::Manifest;
+use crate::registry::ModelHashes;
+
+/// Sharded models list which shard each tensor is in, in a file such as `model.safetensors.index.json`
+const INDEX_FILE_SUFFIX: &str = ".safetensors.index.json";
+
+#[derive(Debug, Error)]
+pub enum ShardError {
+    #[error("invalid index: {0}")]
+    InvalidIndex(#[from] serde_json::Error),
+    #[error("{tensor} is in both {first_shard} and {second_shard}")]
+    DuplicateTensor {
+        tensor: String,
+        first_shard: String,
+        second_shard: String,
+    },
+    #[error("{tensor} is listed in {listed_shard} but is not in its header")]
+    MissingTensor {
+        tensor: String,
+        listed_shard: String,
+    },
+    #[error("{tensor} is in {shard} but is not listed in the index")]
+    UnlistedTensor { tensor: String, shard: String },
+    #[error("{tensor} is in {shard} but the index lists it in {listed_shard}")]
+    MisplacedTensor {
+        tensor: String,
+        shard: String,
+        listed_shard: String,
+    },
+}
+
+/// The index of a sharded model, as written by `transformers` when it saves a model in several files
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct ShardIndex {
+    /// Kept as is when the index is regenerated, apart from the total size
+    #[serde(default)]
+    pub metadata: Map<String, Value>,
+    /// The shard each tensor is in, relative to the folder of the index
+    pub weight_map: BTreeMap<String, String>,
+}
+
+pub fn is_index_file(file_name: &str) -> bool {
+    file_name.ends_with(INDEX_FILE_SUFFIX)
+}
+
+/// The folder of an index, with a trailing slash unless it is at the root of the repo
+fn get_index_folder(index_file_name: &str) -> &str {
+    match index_file_name.rfind('/') {
+        Some(slash_index) => &index_file_name[..=slash_index],
+        None => "",
+    }
+}
+
+impl ShardIndex {
+    pub fn from_slice(bytes: &[u8]) -> Result<ShardIndex, ShardError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    /// The file names of the shards in the repo, rather than relative to the index
+    pub fn get_shard_file_names(&self, index_file_name: &str) -> BTreeSet<String> {
+        let index_folder = get_index_folder(index_file_name);
+        self.weight_map
+            .values()
+            .map(|shard| format!("{}{}", index_folder, shard))
+            .collect()
+    }
+
+    /// Checks that every tensor of the shards is listed in the shard it is in, and that they are all listed once.
+    /// `shard_tensors` has the tensor names in the header of each file; files the index does not list are ignored,
+    /// and so are the tensors listed in shards that are not in `shard_tensors`.
+    pub fn validate(
+        &self,
+        index_file_name: &str,
+        shard_tensors: &BTreeMap<String, Vec<String>>,
+    ) -> Result<(), ShardError> {
+        let index_folder = get_index_folder(index_file_name);
+        let shard_file_names = self.get_shard_file_names(index_file_name);
+
+        let mut tensor_shards: BTreeMap<&str, &str> = BTreeMap::new();
+        for (shard, tensor_names) in shard_tensors {
+            if !shard_file_names.contains(shard) {
+                continue;
+            }
+
+            for tensor in tensor_names {
+                if let Some(first_shard) = tensor_shards.insert(tensor, shard) {
+                    return Err(ShardError::DuplicateTensor {
+                        tensor: tensor.to_string(),
+                        first_shard: first_shard.to_string(),
+                        second_shard: shard.to_string(),
+                    });
+                }
+
+                let listed_shard = match self.weight_map.get(tensor) {
+                    Some(listed_shard) => format!("{}{}", index_folder, listed_shard),
+                    None => {
+                        return Err(ShardError::UnlistedTensor {
+                            tensor: tensor.to_string(),
+                            shard: shard.to_string(),
+                        })
+                    }
+                };
+                if listed_shard != *shard {
+                    return Err(ShardError::MisplacedTensor {
+                        tensor: tensor.to_string(),
+                        shard: shard.to_string(),
+                        listed_shard,
+                    });
+                }
+            }
+        }
+
+        // Every tensor in a shard has been found where it is listed, so only tensors that are in no shard are left
+        for (tensor, listed_shard) in &self.weight_map {
+            let listed_shard = format!("{}{}", index_folder, listed_shard);
+            if shard_tensors.contains_key(&listed_shard)
+                && !tensor_shards.contains_key(tensor.as_str())
+            {
+                return Err(ShardError::MissingTensor {
+                    tensor: tensor.to_string(),
+                    listed_shard,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds the index from the manifests of the shards it lists, so it agrees with them even if the original did
+    /// not. Manifests of files the index does not list are ignored. Shards without a manifest, such as shards that
+    /// were not downloaded, keep their entries from the original, along with its total size as theirs is not known.
+    pub fn regenerate(&self, index_file_name: &str, manifests: &[Manifest]) -> ShardIndex {
+        let index_folder = get_index_folder(index_file_name);
+        let shard_file_names = self.get_shard_file_names(index_file_name);
+
+        let mut weight_map: BTreeMap<String, String> = BTreeMap::new();
+        let mut total_size = 0;
+        let mut regenerated_shards: BTreeSet<&str> = BTreeSet::new();
+        for shard_manifest in manifests
+            .iter()
+            .filter(|m| shard_file_names.contains(&m.file_name))
+        {
+            let shard = &shard_manifest.file_name[index_folder.len()..];
+            regenerated_shards.insert(shard);
+            for (tensor_name, tensor) in &shard_manifest.tensors {
+                weight_map.insert(tensor_name.to_string(), shard.to_string());
+                total_size += tensor.size;
+            }
+        }
+
+        let mut metadata = self.metadata.clone();
+        let mut is_complete = true;
+        for (tensor_name, shard) in &self.weight_map {
+            if !regenerated_shards.contains(shard.as_str()) {
+                weight_map
+                    .entry(tensor_name.to_string())
+                    .or_insert_with(|| shard.to_string());
+                is_complete = false;
+            }
+        }
+        if is_complete {
+            metadata.insert("total_size".to_string(), Value::from(total_size));
+        }
+
+        ShardIndex {
+            metadata,
+            weight_map,
+        }
+    }
+
+    /// Formats the index the way `transformers` does, so an index that was already correct is exported unchanged
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap() + "\n"
+    }
+}
+
+/// Merges the hashes of each file of a model into the hashes of the whole model.
+/// Fails if two files have a tensor of the same name, since the hashes are keyed by tensor name.
+pub fn merge_file_hashes(file_hashes: Vec<ModelHashes>) -> Result<ModelHashes, ShardError> {
+    let mut model_hashes = ModelHashes::new();
+    for layer_hashes in file_hashes {
+        for (tensor_name, layer_hash) in layer_hashes {
+            if let Some(first_layer_hash) = model_hashes.get(&tensor_name) {
+                return Err(ShardError::DuplicateTensor {
+                    tensor: tensor_name,
+                    first_shard: first_layer_hash.file_name.to_string(),
+                    second_shard: layer_hash.file_name,
+                });
+            }
+            model_hashes.insert(tensor_name, layer_hash);
+        }
+    }
+
+    Ok(model_hashes)
+}
+
+/// The tensor names of each file that the hashes of a model are from, to validate them against an index
+pub fn get_shard_tensors(model_hashes: &ModelHashes) -> BTreeMap<String, Vec<String>> {
+    let mut shard_tensors: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (tensor_name, layer_hash) in model_hashes {
+        shard_tensors
+            .entry(layer_hash.file_name.to_string())
+            .or_default()
+            .push(tensor_name.to_string());
+    }
+
+    shard_tensors
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use crate::manifest::TensorEntry;
+
+    #[allow(dead_code)]
+    fn shard_tensors(shards: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        shards
+            .iter()
+            .map(|(shard, tensors)| {
+                (
+                    shard.to_string(),
+                    tensors.iter().map(|t| t.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_index_against_shards() {
+        let index = ShardIndex::from_slice(
+            br#"{"metadata":{"total_size":12},"weight_map":{"a":"model-1.safetensors","b":"model-2.safetensors","c":"model-2.safetensors"}}"#,
+        )
+        .unwrap();
+        let index_file_name = "text_encoder/model.safetensors.index.json";
+
+        let valid_shards = shard_tensors(&[
+            ("text_encoder/model-1.safetensors", &["a"]),
+            ("text_encoder/model-2.safetensors", &["c", "b"]),
+            // Not a shard of this index, so it is not checked
+            ("unet/model-1.safetensors", &["a"]),
+        ]);
+        assert!(index.validate(index_file_name, &valid_shards).is_ok());
+
+        let missing_tensor = shard_tensors(&[("text_encoder/model-2.safetensors", &["c"])]);
+        assert!(matches!(
+            index.validate(index_file_name, &missing_tensor),
+            Err(ShardError::MissingTensor { tensor, .. }) if tensor == "b"
+        ));
+
+        let misplaced_tensor = shard_tensors(&[
+            ("text_encoder/model-1.safetensors", &["a", "b"]),
+            ("text_encoder/model-2.safetensors", &["c"]),
+        ]);
+        assert!(matches!(
+            index.validate(index_file_name, &misplaced_tensor),
+            Err(ShardError::MisplacedTensor { tensor, .. }) if tensor == "b"
+        ));
+
+        let unlisted_tensor = shard_tensors(&[("text_encoder/model-1.safetensors", &["a", "d"])]);
+        assert!(matches!(
+            index.validate(index_file_name, &unlisted_tensor),
+            Err(ShardError::UnlistedTensor { tensor, .. }) if tensor == "d"
+        ));
+    }
+
+    #[test]
+    fn test_regenerate_index_from_manifests() {
+        let index = ShardIndex::from_slice(
+            br#"{"metadata":{"format":"pt","total_size":1},"weight_map":{"a":"model-1.safetensors","stale":"model-2.safetensors"}}"#,
+        )
+        .unwrap();
+        let manifest = |file_name: &str, tensors: &[(&str, u64)]| Manifest {
+            model_id: "org/model".to_string(),
+            revision: "abc123".to_string(),
+            file_name: file_name.to_string(),
+            header: "{}".to_string(),
+            tensors: tensors
+                .iter()
+                .map(|(name, size)| {
+                    (
+                        name.to_string(),
+                        TensorEntry {
+                            hash: format!("hash_{}", name),
+                            data_offsets: [0, *size],
+                            size: *size,
+                        },
+                    )
+                })
+                .collect(),
+            lfs_sha256: None,
+        };
+        let manifests = [
+            manifest("model-1.safetensors", &[("a", 4), ("b", 8)]),
+            manifest("model-2.safetensors", &[("c", 2)]),
+            manifest("consolidated.safetensors", &[("x", 14)]),
+        ];
+
+        let regenerated_index = index.regenerate("model.safetensors.index.json", &manifests);
+        assert_eq!(
+            regenerated_index.to_json(),
+            r#"{
+  "metadata": {
+    "format": "pt",
+    "total_size": 14
+  },
+  "weight_map": {
+    "a": "model-1.safetensors",
+    "b": "model-1.safetensors",
+    "c": "model-2.safetensors"
+  }
+}
+"#
+        );
+
+        // Without the second shard, its tensors are kept as the original lists them
+        let regenerated_index = index.regenerate("model.safetensors.index.json", &manifests[..1]);
+        assert_eq!(
+            regenerated_index.to_json(),
+            r#"{
+  "metadata": {
+    "format": "pt",
+    "total_size": 1
+  },
+  "weight_map": {
+    "a": "model-1.safetensors",
+    "b": "model-1.safetensors",
+    "stale": "model-2.safetensors"
+  }
+}
+"#
+        );
+    }
+}
@@ -0,0 +1,799 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, TryLockError};
+use std::io::{self, prelude::*, BufReader};
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{hasher, manifest};
+
+/// How a layer is compressed in the local store. The codec of each blob is recorded in its file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    None,
+    Lz4,
+}
+
+impl Codec {
+    const ALL: [Codec; 2] = [Codec::None, Codec::Lz4];
+
+    fn extension(&self) -> Option<&'static str> {
+        match self {
+            Codec::None => None,
+            Codec::Lz4 => Some("lz4"),
+        }
+    }
+
+    pub fn from_file_name(file_name: &str) -> (&str, Codec) {
+        match file_name.strip_suffix(".lz4") {
+            Some(hash) => (hash, Codec::Lz4),
+            None => (file_name, Codec::None),
+        }
+    }
+}
+
+/// Blobs are spread over 256 folders named after the first two characters of their hash, like git and docker do,
+/// so no single folder grows too large to list
+const BLOBS_DIR: &str = "sha256";
+
+/// Returns the folder a blob is stored in, such as `sha256/ab` for the blob with hash `abcdef…`.
+/// Fails for anything that is not a hash, such as a hash from a registry that is too short to be split.
+pub fn get_blob_dir(storage_dir: &str, hash: &str) -> io::Result<PathBuf> {
+    if !hasher::is_sha256(hash) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a SHA-256 hash", hash),
+        ));
+    }
+
+    let mut blob_dir = PathBuf::new();
+    blob_dir.push(storage_dir);
+    blob_dir.push(BLOBS_DIR);
+    blob_dir.push(&hash[..2]);
+
+    Ok(blob_dir)
+}
+
+/// The rest of the hash is the file name within the blob's folder
+fn get_blob_file_name(hash: &str, suffix: &str) -> String {
+    format!("{}{}", &hash[2..], suffix)
+}
+
+pub fn get_blob_path(storage_dir: &str, hash: &str, codec: Codec) -> io::Result<PathBuf> {
+    let suffix = match codec.extension() {
+        Some(extension) => format!(".{}", extension),
+        None => String::new(),
+    };
+
+    Ok(get_blob_dir(storage_dir, hash)?.join(get_blob_file_name(hash, &suffix)))
+}
+
+/// Finds the blob with the given hash regardless of how it was compressed. Nothing is ever stored under anything
+/// that is not a hash.
+pub fn find_blob(storage_dir: &str, hash: &str) -> Option<(PathBuf, Codec)> {
+    Codec::ALL
+        .iter()
+        .filter_map(|codec| Some((get_blob_path(storage_dir, hash, *codec).ok()?, *codec)))
+        .find(|(blob_path, _)| blob_path.is_file())
+}
+
+/// Lists every file in the blob folders, along with the name it would have without the fan-out folders,
+/// which is its hash followed by any extension such as `.lz4` or `.partial`
+pub fn list_blob_files(storage_dir: &str) -> Vec<(String, PathBuf)> {
+    let mut blob_files: Vec<(String, PathBuf)> = Vec::new();
+
+    for (prefix, prefix_dir) in list_dir(&Path::new(storage_dir).join(BLOBS_DIR), true) {
+        for (file_name, file_path) in list_dir(&prefix_dir, false) {
+            blob_files.push((format!("{}{}", prefix, file_name), file_path));
+        }
+    }
+    blob_files.sort();
+
+    blob_files
+}
+
+fn list_dir(dir: &Path, dirs: bool) -> Vec<(String, PathBuf)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir() == dirs))
+        .filter_map(|entry| Some((entry.file_name().into_string().ok()?, entry.path())))
+        .collect()
+}
+
+/// Returns the hash and codec of every blob in the store
+pub fn list_blobs(storage_dir: &str) -> Vec<(String, Codec)> {
+    list_blob_files(storage_dir)
+        .into_iter()
+        // Skip layers that have not finished downloading or compressing yet, and anything else that is not named
+        // after its hash, which `cake fsck` reports instead
+        .filter_map(|(file_name, _)| {
+            let (hash, codec) = Codec::from_file_name(&file_name);
+            hasher::is_sha256(hash).then(|| (hash.to_string(), codec))
+        })
+        .collect()
+}
+
+/// Layers are downloaded into a partial file first, so an interrupted download can be resumed later on
+pub fn get_partial_blob_path(storage_dir: &str, hash: &str) -> io::Result<PathBuf> {
+    Ok(get_blob_dir(storage_dir, hash)?.join(get_blob_file_name(hash, ".partial")))
+}
+
+/// Large layers are downloaded in pieces, each of which has a partial file of its own until they are joined together.
+/// Pieces are named after where they start in the layer, so a piece always holds the same bytes however the rest of
+/// the layer was split.
+pub fn get_partial_piece_path(
+    storage_dir: &str,
+    hash: &str,
+    layer_offset: u64,
+) -> io::Result<PathBuf> {
+    Ok(get_blob_dir(storage_dir, hash)?.join(get_blob_file_name(
+        hash,
+        &format!(".{}.partial", layer_offset),
+    )))
+}
+
+/// Deletes the partial files of every piece of a layer, such as pieces that were joined or are no longer needed
+pub fn remove_partial_pieces(storage_dir: &str, hash: &str) -> io::Result<()> {
+    let piece_prefix = get_blob_file_name(hash, ".");
+    for (file_name, file_path) in list_dir(&get_blob_dir(storage_dir, hash)?, false) {
+        let is_piece = file_name
+            .strip_prefix(&piece_prefix)
+            .and_then(|rest| rest.strip_suffix(".partial"))
+            .is_some_and(|layer_offset| layer_offset.parse::<u64>().is_ok());
+        if is_piece {
+            fs::remove_file(file_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the blobs of a store from before blobs were spread over folders into their folders.
+/// Partial and temporary files are moved along with them. Returns how many files were moved.
+pub fn migrate_flat_store(storage_dir: &str) -> io::Result<u64> {
+    let mut moved_count = 0;
+
+    for (file_name, file_path) in list_dir(Path::new(storage_dir), false) {
+        let hash = file_name.split('.').next().unwrap_or_default();
+        if !hasher::is_sha256(hash) {
+            continue;
+        }
+
+        let blob_dir = get_blob_dir(storage_dir, hash)?;
+        fs::create_dir_all(&blob_dir)?;
+        let migrated_path = blob_dir.join(&file_name[2..]);
+        match fs::rename(file_path, migrated_path) {
+            Ok(()) => moved_count += 1,
+            // Another process moved it first
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(moved_count)
+}
+
+/// Moves a verified file with the bytes of a blob into the store, compressing it if necessary, and deletes the file.
+/// Compression goes to a temporary file of its own, so several processes can promote the same blob at once.
+pub fn promote_blob_file(
+    storage_dir: &str,
+    hash: &str,
+    source_path: &Path,
+    codec: Codec,
+) -> io::Result<()> {
+    let blob_path = get_blob_path(storage_dir, hash, codec)?;
+
+    match codec {
+        Codec::None => {
+            File::open(source_path)?.sync_all()?;
+            fs::rename(source_path, &blob_path)?;
+        }
+        Codec::Lz4 => {
+            let source_size = fs::metadata(source_path)?.len();
+            let mut source_file = BufReader::new(File::open(source_path)?);
+
+            // Compress next to the final path, then rename it into place
+            let compressed_blob_path = get_temp_path(&blob_path);
+
+            // Record the uncompressed size in the frame so it can be read back without decompressing
+            let result = File::create(&compressed_blob_path).and_then(|compressed_file| {
+                let mut encoder = lz4::EncoderBuilder::new()
+                    .content_size(source_size)
+                    .build(compressed_file)?;
+                io::copy(&mut source_file, &mut encoder)?;
+                let (file, result) = encoder.finish();
+                result?;
+                file.sync_all()
+            });
+            if result.is_err() {
+                let _ = fs::remove_file(&compressed_blob_path);
+            }
+            result?;
+
+            fs::rename(compressed_blob_path, &blob_path)?;
+            fs::remove_file(source_path)?;
+        }
+    }
+
+    sync_dir(blob_path.parent().unwrap())
+}
+
+/// A temporary file next to the given path that no other process will use, which `lock_store` sweeps away if it
+/// is left behind
+pub fn get_temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.to_path_buf().into_os_string();
+    temp_path.push(format!(".{:016x}.tmp", rand::thread_rng().gen::<u64>()));
+
+    PathBuf::from(temp_path)
+}
+
+/// Writes a file so that it either has all of its contents or does not exist, even if cake or the machine crashes
+/// part of the way through: the contents go to a temporary file next to it, which is flushed to disk and then
+/// renamed into place.
+pub fn write_file_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    // Several processes may write the same file at once, so each gets a temporary file of its own
+    let temp_path = get_temp_path(path);
+
+    let result = File::create(&temp_path)
+        .and_then(|mut temp_file| {
+            temp_file.write_all(bytes)?;
+            temp_file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result?;
+
+    sync_dir(path.parent().unwrap())
+}
+
+/// A rename is only durable once the folder it happened in is flushed to disk as well
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Stores bytes that are already in memory, such as the blobs of a test store. Nothing is written if the blob already
+/// exists. Downloads stream into partial files instead, so this is not used outside of tests.
+#[allow(dead_code)]
+pub fn write_blob(storage_dir: &str, hash: &str, bytes: &[u8], codec: Codec) -> io::Result<()> {
+    if find_blob(storage_dir, hash).is_some() {
+        return Ok(());
+    }
+
+    // A temporary file of its own, so a download of the same blob keeps its partial file
+    let blob_dir = get_blob_dir(storage_dir, hash)?;
+    fs::create_dir_all(&blob_dir)?;
+    let temp_path = get_temp_path(&blob_dir.join(get_blob_file_name(hash, "")));
+    fs::write(&temp_path, bytes)?;
+
+    promote_blob_file(storage_dir, hash, &temp_path, codec)
+}
+
+/// Opens a blob for reading, decompressing it if necessary
+pub fn open_blob(storage_dir: &str, hash: &str) -> io::Result<Box<dyn Read + Send>> {
+    open_blob_at(storage_dir, hash, 0)
+}
+
+/// Opens a blob for reading from the given offset of its uncompressed bytes
+pub fn open_blob_at(
+    storage_dir: &str,
+    hash: &str,
+    offset: u64,
+) -> io::Result<Box<dyn Read + Send>> {
+    let (blob_path, codec) = find_blob(storage_dir, hash).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Layer {} is not available locally", hash),
+        )
+    })?;
+    let mut file = BufReader::new(File::open(blob_path)?);
+
+    match codec {
+        Codec::None => {
+            file.seek(io::SeekFrom::Start(offset))?;
+            Ok(Box::new(file))
+        }
+        Codec::Lz4 => {
+            // Frames can not be seeked into, so decompress up to the offset and throw that away
+            let mut decoder = lz4::Decoder::new(file)?;
+            io::copy(&mut (&mut decoder).take(offset), &mut io::sink())?;
+            Ok(Box::new(decoder))
+        }
+    }
+}
+
+/// Opens a blob file for reading its uncompressed bytes, whether or not it is named after what it holds
+pub fn open_blob_file(blob_path: &Path, codec: Codec) -> io::Result<Box<dyn Read + Send>> {
+    let file = BufReader::new(File::open(blob_path)?);
+
+    match codec {
+        Codec::None => Ok(Box::new(file)),
+        Codec::Lz4 => Ok(Box::new(lz4::Decoder::new(file)?)),
+    }
+}
+
+/// Returns the uncompressed size of a blob
+pub fn get_blob_logical_size(blob_path: &Path, codec: Codec) -> io::Result<u64> {
+    match codec {
+        Codec::None => Ok(fs::metadata(blob_path)?.len()),
+        Codec::Lz4 => {
+            // Frame header: 4 byte magic number, FLG byte, BD byte, then the optional 8 byte content size
+            let mut frame_header = [0u8; 14];
+            File::open(blob_path)?.read_exact(&mut frame_header)?;
+            let has_content_size = frame_header[4] & 0b0000_1000 != 0;
+            if has_content_size {
+                Ok(u64::from_le_bytes(frame_header[6..14].try_into().unwrap()))
+            } else {
+                let mut decoder = lz4::Decoder::new(File::open(blob_path)?)?;
+                io::copy(&mut decoder, &mut io::sink())
+            }
+        }
+    }
+}
+
+/// Held for as long as a command uses the store, and released when dropped
+pub struct StoreLock {
+    _lock_file: File,
+}
+
+/// Locks the store. Downloads hold a shared lock while they add blobs that no manifest references yet, and garbage
+/// collection holds an exclusive one, so it never deletes a blob that a download is about to record.
+/// Stores from before blobs were spread over folders are migrated once the lock is held, and temporary files that a
+/// crashed process left behind are deleted whenever no one else is using the store.
+pub fn lock_store(storage_dir: &str, exclusive: bool) -> io::Result<StoreLock> {
+    fs::create_dir_all(storage_dir)?;
+    let lock_file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(Path::new(storage_dir).join(".lock"))?;
+
+    // A temporary file can only be told apart from one that is still being written while no one else holds the lock
+    let mut is_locked = false;
+    if lock_file.try_lock().is_ok() {
+        let swept_count = sweep_temp_files(Path::new(storage_dir))?;
+        if swept_count > 0 {
+            println!(
+                "Deleted {} temporary files left behind in {}",
+                swept_count, storage_dir
+            );
+        }
+
+        if exclusive {
+            is_locked = true;
+        } else {
+            lock_file.unlock()?;
+        }
+    }
+
+    if !is_locked {
+        let result = if exclusive {
+            lock_file.try_lock()
+        } else {
+            lock_file.try_lock_shared()
+        };
+        match result {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                println!(
+                    "Waiting for another cake process to finish with {}...",
+                    storage_dir
+                );
+                if exclusive {
+                    lock_file.lock()?;
+                } else {
+                    lock_file.lock_shared()?;
+                }
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+    }
+
+    let migrated_count = migrate_flat_store(storage_dir)?;
+    if migrated_count > 0 {
+        println!(
+            "Moved {} blobs of {} into the {} folder",
+            migrated_count, storage_dir, BLOBS_DIR
+        );
+    }
+
+    Ok(StoreLock {
+        _lock_file: lock_file,
+    })
+}
+
+/// Deletes the temporary files under a folder of the store, except for the quarantined ones which are kept for
+/// inspection. Partial downloads are kept as well, as they can be resumed. Returns how many files were deleted.
+fn sweep_temp_files(dir: &Path) -> io::Result<u64> {
+    let mut swept_count = 0;
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() && entry.file_name() != "quarantine" {
+            swept_count += sweep_temp_files(&entry.path())?;
+        } else if file_type.is_file() && entry.file_name().to_string_lossy().ends_with(".tmp") {
+            fs::remove_file(entry.path())?;
+            swept_count += 1;
+        }
+    }
+
+    Ok(swept_count)
+}
+
+/// Deletes every blob that no manifest references, or only counts them for a dry run. The partial files of
+/// interrupted downloads are what the next download resumes from, so they are only deleted when asked for, such as
+/// for a download that will not be run again.
+/// Returns how many files there were and how many bytes they took up in the store.
+pub fn collect_garbage(
+    storage_dir: &str,
+    dry_run: bool,
+    include_partials: bool,
+) -> io::Result<(u64, u64)> {
+    let _store_lock = lock_store(storage_dir, true)?;
+
+    let mut referenced_hashes: HashSet<String> = HashSet::new();
+    for model_manifest in manifest::load_all_manifests(storage_dir) {
+        referenced_hashes.extend(model_manifest.tensors.into_values().map(|t| t.hash));
+    }
+    for files_manifest in manifest::load_all_files_manifests(storage_dir) {
+        referenced_hashes.extend(files_manifest.files.into_values().map(|f| f.hash));
+    }
+
+    let mut unreferenced_count = 0;
+    let mut unreferenced_bytes = 0;
+    for (hash, codec) in list_blobs(storage_dir) {
+        if referenced_hashes.contains(&hash) {
+            continue;
+        }
+
+        let blob_path = get_blob_path(storage_dir, &hash, codec)?;
+        unreferenced_count += 1;
+        unreferenced_bytes += fs::metadata(&blob_path)?.len();
+        if !dry_run {
+            fs::remove_file(&blob_path)?;
+        }
+    }
+
+    // Both whole partial layers and the pieces of one, named `<hash>.partial` and `<hash>.<offset>.partial`
+    for (file_name, file_path) in list_blob_files(storage_dir) {
+        if !include_partials || !file_name.ends_with(".partial") {
+            continue;
+        }
+
+        unreferenced_count += 1;
+        unreferenced_bytes += fs::metadata(&file_path)?.len();
+        if !dry_run {
+            fs::remove_file(&file_path)?;
+        }
+    }
+
+    Ok((unreferenced_count, unreferenced_bytes))
+}
+
+#[derive(Default)]
+struct BlobStats {
+    count: u64,
+    logical_bytes: u64,
+    stored_bytes: u64,
+}
+
+impl BlobStats {
+    fn add(&mut self, logical_bytes: u64, stored_bytes: u64) {
+        self.count += 1;
+        self.logical_bytes += logical_bytes;
+        self.stored_bytes += stored_bytes;
+    }
+
+    fn print(&self, label: &str) {
+        let ratio = if self.logical_bytes == 0 {
+            1.0
+        } else {
+            self.stored_bytes as f64 / self.logical_bytes as f64
+        };
+        println!(
+            "{:<12} {:>8} blobs {:>16} logical bytes {:>16} stored bytes ({:.1}%)",
+            label,
+            self.count,
+            self.logical_bytes,
+            self.stored_bytes,
+            ratio * 100.0
+        );
+    }
+}
+
+/// Prints how much space the store takes up compared to the uncompressed layers, by codec and by dtype
+pub fn print_store_stats(storage_dir: &str) {
+    // Manifests are the only place that records the dtype of each layer
+    let mut hashes_to_dtypes: BTreeMap<String, String> = BTreeMap::new();
+    for model_manifest in manifest::load_all_manifests(storage_dir) {
+        let header: Value = serde_json::from_str(&model_manifest.header).unwrap();
+        for (tensor_name, tensor) in model_manifest.tensors {
+            let dtype = header
+                .get(&tensor_name)
+                .and_then(|t| t.get("dtype"))
+                .and_then(Value::as_str)
+                .unwrap_or("unknown");
+            hashes_to_dtypes.insert(tensor.hash, dtype.to_string());
+        }
+    }
+
+    let mut total = BlobStats::default();
+    let mut by_codec: BTreeMap<Codec, BlobStats> = BTreeMap::new();
+    let mut by_dtype: BTreeMap<String, BlobStats> = BTreeMap::new();
+
+    for (hash, codec) in list_blobs(storage_dir) {
+        // Only blobs named after their hash are listed
+        let blob_path = get_blob_path(storage_dir, &hash, codec).unwrap();
+        let stored_bytes = fs::metadata(&blob_path).unwrap().len();
+        let logical_bytes = match get_blob_logical_size(&blob_path, codec) {
+            Ok(logical_bytes) => logical_bytes,
+            Err(e) => {
+                println!("Unable to read {}: {}", blob_path.display(), e);
+                continue;
+            }
+        };
+
+        let dtype = hashes_to_dtypes
+            .get(&hash)
+            .map(String::as_str)
+            .unwrap_or("unknown");
+
+        total.add(logical_bytes, stored_bytes);
+        by_codec
+            .entry(codec)
+            .or_default()
+            .add(logical_bytes, stored_bytes);
+        by_dtype
+            .entry(dtype.to_string())
+            .or_default()
+            .add(logical_bytes, stored_bytes);
+    }
+
+    println!("By codec:");
+    for (codec, stats) in by_codec {
+        stats.print(&format!("{:?}", codec).to_lowercase());
+    }
+    println!("By dtype:");
+    for (dtype, stats) in by_dtype {
+        stats.print(&dtype);
+    }
+    total.print("Total");
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_blob_round_trip_with_each_codec() {
+        let storage_dir = std::env::temp_dir().join(format!(
+            "cake-test-store-round-trip-{:016x}",
+            rand::random::<u64>()
+        ));
+        let storage_dir = storage_dir.to_str().unwrap();
+        let bytes: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+        let hash = &hasher::sha256_hash(&bytes);
+
+        for codec in Codec::ALL {
+            let _ = fs::remove_dir_all(storage_dir);
+            fs::create_dir_all(get_blob_dir(storage_dir, hash).unwrap()).unwrap();
+            let partial_blob_path = get_partial_blob_path(storage_dir, hash).unwrap();
+            fs::write(&partial_blob_path, &bytes).unwrap();
+            assert_eq!(list_blobs(storage_dir), vec![]);
+            promote_blob_file(storage_dir, hash, &partial_blob_path, codec).unwrap();
+
+            let (blob_path, found_codec) = find_blob(storage_dir, hash).unwrap();
+            assert_eq!(found_codec, codec);
+            assert_eq!(
+                get_blob_logical_size(&blob_path, codec).unwrap(),
+                bytes.len() as u64
+            );
+
+            let mut read_bytes = Vec::new();
+            open_blob(storage_dir, hash)
+                .unwrap()
+                .read_to_end(&mut read_bytes)
+                .unwrap();
+            assert_eq!(read_bytes, bytes);
+
+            let mut read_bytes = Vec::new();
+            open_blob_at(storage_dir, hash, 1000)
+                .unwrap()
+                .read_to_end(&mut read_bytes)
+                .unwrap();
+            assert_eq!(read_bytes, bytes[1000..]);
+        }
+
+        assert_eq!(
+            list_blobs(storage_dir),
+            vec![(hash.to_string(), Codec::Lz4)]
+        );
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
+
+    #[test]
+    fn test_collect_garbage_keeps_referenced_blobs() {
+        let storage_dir =
+            std::env::temp_dir().join(format!("cake-test-store-gc-{:016x}", rand::random::<u64>()));
+        let storage_dir = storage_dir.to_str().unwrap();
+        let [layer_hash, config_hash, unused_hash] =
+            ["layer", "config", "unused"].map(|contents| hasher::sha256_hash(contents.as_bytes()));
+        for (hash, contents) in [
+            (&layer_hash, "layer"),
+            (&config_hash, "config"),
+            (&unused_hash, "unused"),
+        ] {
+            write_blob(storage_dir, hash, contents.as_bytes(), Codec::None).unwrap();
+        }
+
+        manifest::save_manifest(
+            storage_dir,
+            &manifest::Manifest {
+                model_id: "org/model".to_string(),
+                revision: "abc123".to_string(),
+                file_name: "model.safetensors".to_string(),
+                header: "{}".to_string(),
+                tensors: BTreeMap::from([(
+                    "a".to_string(),
+                    manifest::TensorEntry {
+                        hash: layer_hash.to_string(),
+                        data_offsets: [0, 5],
+                        size: 5,
+                    },
+                )]),
+                lfs_sha256: None,
+            },
+        );
+        manifest::save_files_manifest(
+            storage_dir,
+            &manifest::FilesManifest {
+                model_id: "org/model".to_string(),
+                revision: "abc123".to_string(),
+                files: BTreeMap::from([(
+                    "config.json".to_string(),
+                    manifest::FileEntry {
+                        hash: config_hash.to_string(),
+                        size: 6,
+                    },
+                )]),
+            },
+        );
+
+        // Left over from interrupted downloads, of a whole layer and of a piece of one
+        let partial_blob_path = get_partial_blob_path(storage_dir, &unused_hash).unwrap();
+        fs::write(&partial_blob_path, "unu").unwrap();
+        let partial_piece_path = get_partial_piece_path(storage_dir, &layer_hash, 1024).unwrap();
+        fs::write(&partial_piece_path, "la").unwrap();
+
+        assert_eq!(collect_garbage(storage_dir, true, true).unwrap(), (3, 11));
+        assert!(find_blob(storage_dir, &unused_hash).is_some());
+        assert!(partial_blob_path.exists());
+
+        // Partial files are kept for the next download to resume from unless they are asked for
+        assert_eq!(collect_garbage(storage_dir, false, false).unwrap(), (1, 6));
+        assert!(find_blob(storage_dir, &unused_hash).is_none());
+        assert!(partial_blob_path.exists());
+        assert!(partial_piece_path.exists());
+        assert_eq!(collect_garbage(storage_dir, false, true).unwrap(), (2, 5));
+
+        assert!(!partial_blob_path.exists());
+        assert!(!partial_piece_path.exists());
+        assert_eq!(list_blobs(storage_dir).len(), 2);
+        assert_eq!(list_blob_files(storage_dir).len(), 2);
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
+
+    #[test]
+    fn test_blob_paths_are_only_for_hashes() {
+        let hash = hasher::sha256_hash(b"layer");
+        assert!(get_blob_path("download", &hash, Codec::Lz4)
+            .unwrap()
+            .ends_with(format!("sha256/{}/{}.lz4", &hash[..2], &hash[2..])));
+
+        for not_a_hash in ["", "a", "../etc", &hash.to_uppercase(), &hash[1..]] {
+            assert!(get_blob_dir("download", not_a_hash).is_err());
+            assert!(get_partial_blob_path("download", not_a_hash).is_err());
+            assert_eq!(find_blob("download", not_a_hash), None);
+        }
+    }
+
+    #[test]
+    fn test_migrate_flat_store() {
+        let storage_dir = std::env::temp_dir().join(format!(
+            "cake-test-store-migrate-{:016x}",
+            rand::random::<u64>()
+        ));
+        let storage_dir = storage_dir.to_str().unwrap();
+        fs::create_dir_all(storage_dir).unwrap();
+
+        let hash = hasher::sha256_hash(b"layer");
+        let partial_hash = hasher::sha256_hash(b"partial layer");
+        fs::write(Path::new(storage_dir).join(format!("{}.lz4", hash)), b"lz4").unwrap();
+        fs::write(
+            Path::new(storage_dir).join(format!("{}.partial", partial_hash)),
+            b"partial",
+        )
+        .unwrap();
+        fs::write(Path::new(storage_dir).join("notes.txt"), b"notes").unwrap();
+
+        assert_eq!(migrate_flat_store(storage_dir).unwrap(), 2);
+        assert_eq!(
+            find_blob(storage_dir, &hash),
+            Some((
+                get_blob_path(storage_dir, &hash, Codec::Lz4).unwrap(),
+                Codec::Lz4
+            ))
+        );
+        assert!(get_partial_blob_path(storage_dir, &partial_hash)
+            .unwrap()
+            .is_file());
+        assert_eq!(list_blobs(storage_dir), vec![(hash, Codec::Lz4)]);
+        assert!(Path::new(storage_dir).join("notes.txt").is_file());
+
+        // Nothing is left to move the second time around
+        assert_eq!(migrate_flat_store(storage_dir).unwrap(), 0);
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
+
+    #[test]
+    fn test_lock_store_sweeps_temp_files() {
+        let storage_dir = std::env::temp_dir().join(format!(
+            "cake-test-store-sweep-{:016x}",
+            rand::random::<u64>()
+        ));
+        let storage_dir = storage_dir.to_str().unwrap();
+
+        let hash = hasher::sha256_hash(b"layer");
+        fs::create_dir_all(get_blob_dir(storage_dir, &hash).unwrap()).unwrap();
+        let temp_path = get_blob_dir(storage_dir, &hash)
+            .unwrap()
+            .join("cdef.lz4.tmp");
+        fs::write(&temp_path, b"half").unwrap();
+        fs::write(get_partial_blob_path(storage_dir, &hash).unwrap(), b"half").unwrap();
+        let quarantine_dir = Path::new(storage_dir).join("quarantine");
+        fs::create_dir_all(&quarantine_dir).unwrap();
+        fs::write(quarantine_dir.join("cdef.tmp"), b"half").unwrap();
+
+        let manifest_path = Path::new(storage_dir).join("manifests").join("model.json");
+        fs::create_dir_all(manifest_path.parent().unwrap()).unwrap();
+        write_file_atomically(&manifest_path, b"{}").unwrap();
+        assert_eq!(fs::read(&manifest_path).unwrap(), b"{}");
+
+        {
+            // Another process using the store may still be writing the file
+            let _other_lock = lock_store(storage_dir, false).unwrap();
+            fs::write(&temp_path, b"half").unwrap();
+            drop(lock_store(storage_dir, false).unwrap());
+            assert!(temp_path.is_file());
+        }
+
+        drop(lock_store(storage_dir, false).unwrap());
+        assert!(!temp_path.is_file());
+        assert!(get_partial_blob_path(storage_dir, &hash).unwrap().is_file());
+        assert!(quarantine_dir.join("cdef.tmp").is_file());
+        assert_eq!(
+            fs::read_dir(manifest_path.parent().unwrap())
+                .unwrap()
+                .count(),
+            1
+        );
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
+}